@@ -0,0 +1,27 @@
+//! HPET description table
+use core::ptr::read_unaligned;
+
+use x86_64::PhysAddr;
+
+use super::{find_table, GenericAddress, SdtHeader, ADDRESS_SPACE_MEMORY};
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct HpetTable {
+    header: SdtHeader,
+    event_timer_block_id: u32,
+    base_address: GenericAddress,
+    hpet_number: u8,
+    min_tick: u16,
+    page_protection: u8,
+}
+
+/// physical address of the first HPET's registers
+pub fn find() -> Option<PhysAddr> {
+    let table = unsafe { read_unaligned(find_table(b"HPET")?.as_ptr::<HpetTable>()) };
+    let base = table.base_address;
+    if base.address_space != ADDRESS_SPACE_MEMORY {
+        return None;
+    }
+    Some(PhysAddr::new(base.address))
+}
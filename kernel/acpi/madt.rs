@@ -0,0 +1,87 @@
+//! Multiple APIC Description Table
+use core::mem::size_of;
+use core::ptr::read_unaligned;
+
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::{find_table, SdtHeader};
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct MadtHeader {
+    header: SdtHeader,
+    local_apic_address: u32,
+    flags: u32,
+}
+
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_SOURCE_OVERRIDE: u8 = 2;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Entry {
+    LocalApic {
+        processor_id: u8,
+        apic_id: u8,
+        /// bit 0: enabled, bit 1: can be enabled
+        flags: u32,
+    },
+    IoApic {
+        id: u8,
+        address: PhysAddr,
+        /// first global system interrupt it handles
+        gsi_base: u32,
+    },
+    /// an ISA IRQ is not wired to the IOAPIC pin of the same number
+    SourceOverride {
+        irq: u8,
+        gsi: u32,
+        flags: u16,
+    },
+    Other(u8),
+}
+
+/// Iterate over the MADT's entries, `None` if there is no MADT.
+pub fn entries() -> Option<impl Iterator<Item = Entry>> {
+    let table = find_table(b"APIC")?;
+    let header = unsafe { read_unaligned(table.as_ptr::<MadtHeader>()) };
+    let end = table + header.header.length as u64;
+    let mut next = table + size_of::<MadtHeader>();
+    Some(core::iter::from_fn(move || {
+        if next + 2u64 > end {
+            return None;
+        }
+        let entry = next;
+        let (kind, len) = unsafe { (*entry.as_ptr::<u8>(), *(entry + 1u64).as_ptr::<u8>()) };
+        if len < 2 {
+            return None;
+        }
+        next += len as u64;
+        let field = |offset: u64| entry + offset;
+        let entry = unsafe {
+            match kind {
+                ENTRY_LOCAL_APIC => Entry::LocalApic {
+                    processor_id: read_at(field(2)),
+                    apic_id: read_at(field(3)),
+                    flags: read_at(field(4)),
+                },
+                ENTRY_IO_APIC => Entry::IoApic {
+                    id: read_at(field(2)),
+                    address: PhysAddr::new(read_at::<u32>(field(4)) as u64),
+                    gsi_base: read_at(field(8)),
+                },
+                ENTRY_SOURCE_OVERRIDE => Entry::SourceOverride {
+                    irq: read_at(field(3)),
+                    gsi: read_at(field(4)),
+                    flags: read_at(field(8)),
+                },
+                kind => Entry::Other(kind),
+            }
+        };
+        Some(entry)
+    }))
+}
+
+unsafe fn read_at<T: Copy>(addr: VirtAddr) -> T {
+    read_unaligned(addr.as_ptr::<T>())
+}
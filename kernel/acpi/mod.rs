@@ -0,0 +1,179 @@
+//! ACPI tables, only what's needed to find the devices
+//!
+//! The bootloader doesn't hand us the RSDP, so it's looked for in the BIOS areas.
+//! All tables are read through the physical memory mapping.
+pub mod hpet;
+pub mod madt;
+
+use core::mem::size_of;
+use core::ptr::read_unaligned;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::vmem::phys_to_virt;
+use crate::{info, warn};
+
+/// pointer to the EBDA segment, in the BIOS data area
+const EBDA_SEGMENT_PTR: u64 = 0x40e;
+const BIOS_AREA: (u64, u64) = (0xe_0000, 0x10_0000);
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // ACPI 2.0+
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+/// size of the ACPI 1.0 part of the RSDP
+const RSDP_V1_SIZE: usize = 20;
+
+/// header common to all the system description tables
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// Generic Address Structure
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+pub const ADDRESS_SPACE_MEMORY: u8 = 0;
+
+/// physical address of the RSDT or XSDT, 0 until `init_acpi` found it
+static ROOT_TABLE: AtomicU64 = AtomicU64::new(0);
+/// whether the root table is the XSDT, with 64 bits entries
+static EXTENDED: AtomicU64 = AtomicU64::new(0);
+
+fn checksum(addr: VirtAddr, len: usize) -> bool {
+    let bytes = unsafe { core::slice::from_raw_parts(addr.as_ptr::<u8>(), len) };
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+/// RSDPs are 16 bytes aligned
+fn scan_rsdp(start: u64, end: u64) -> Option<Rsdp> {
+    (start..end).step_by(16).find_map(|phys| {
+        let virt = phys_to_virt(PhysAddr::new(phys));
+        let rsdp = unsafe { read_unaligned(virt.as_ptr::<Rsdp>()) };
+        if &rsdp.signature == RSDP_SIGNATURE && checksum(virt, RSDP_V1_SIZE) {
+            Some(rsdp)
+        } else {
+            None
+        }
+    })
+}
+
+fn find_rsdp() -> Option<Rsdp> {
+    let ebda_segment =
+        unsafe { read_unaligned(phys_to_virt(PhysAddr::new(EBDA_SEGMENT_PTR)).as_ptr::<u16>()) };
+    let ebda = (ebda_segment as u64) << 4;
+    // it's in the first KiB of the EBDA, or in the BIOS read-only area
+    let in_ebda = if ebda != 0 {
+        scan_rsdp(ebda, ebda + 1024)
+    } else {
+        None
+    };
+    in_ebda.or_else(|| scan_rsdp(BIOS_AREA.0, BIOS_AREA.1))
+}
+
+pub fn init_acpi() {
+    let rsdp = match find_rsdp() {
+        Some(rsdp) => rsdp,
+        None => {
+            warn!("ACPI: no RSDP found");
+            return;
+        }
+    };
+    let revision = rsdp.revision;
+    let oem_id = rsdp.oem_id;
+    let extended = revision >= 2 && rsdp.xsdt_address != 0;
+    let root = if extended {
+        rsdp.xsdt_address
+    } else {
+        rsdp.rsdt_address as u64
+    };
+    let virt = phys_to_virt(PhysAddr::new(root));
+    let length = unsafe { read_unaligned(virt.as_ptr::<SdtHeader>()) }.length as usize;
+    if length < size_of::<SdtHeader>() || !checksum(virt, length) {
+        warn!("ACPI: invalid root table @ {:#x}", root);
+        return;
+    }
+    ROOT_TABLE.store(root, Ordering::Relaxed);
+    EXTENDED.store(extended as u64, Ordering::Relaxed);
+    info!(
+        "ACPI: revision {}, OEM {}, root table @ {:#x}",
+        revision,
+        core::str::from_utf8(&oem_id).unwrap_or("?"),
+        root
+    );
+}
+
+/// Find a table by its signature, returns its header's virtual address after checking its checksum.
+pub fn find_table(signature: &[u8; 4]) -> Option<VirtAddr> {
+    let root = match ROOT_TABLE.load(Ordering::Relaxed) {
+        0 => return None,
+        root => phys_to_virt(PhysAddr::new(root)),
+    };
+    let header = unsafe { read_unaligned(root.as_ptr::<SdtHeader>()) };
+    let entry_size = if EXTENDED.load(Ordering::Relaxed) != 0 {
+        8
+    } else {
+        4
+    };
+    let entries = (header.length as usize).checked_sub(size_of::<SdtHeader>())? / entry_size;
+    let first = root + size_of::<SdtHeader>();
+    (0..entries).find_map(|i| {
+        let entry = first + i * entry_size;
+        let phys = unsafe {
+            if entry_size == 8 {
+                read_unaligned(entry.as_ptr::<u64>())
+            } else {
+                read_unaligned(entry.as_ptr::<u32>()) as u64
+            }
+        };
+        let table = phys_to_virt(PhysAddr::new(phys));
+        let header = unsafe { read_unaligned(table.as_ptr::<SdtHeader>()) };
+        if &header.signature != signature {
+            return None;
+        }
+        if !checksum(table, header.length as usize) {
+            warn!(
+                "ACPI: bad checksum for {}",
+                core::str::from_utf8(signature).unwrap_or("?")
+            );
+            return None;
+        }
+        Some(table)
+    })
+}
+
+#[test_case]
+fn test_find_tables() {
+    // QEMU always has these
+    assert!(find_table(b"FACP").is_some());
+    assert!(find_table(b"APIC").is_some());
+    assert!(find_table(b"NOPE").is_none());
+}
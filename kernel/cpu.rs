@@ -0,0 +1,26 @@
+//! helpers about the executing CPU
+use core::arch::x86_64::{__cpuid, _rdtsc};
+
+use crate::percpu;
+
+/// maximum number of CPUs the kernel keeps state for
+pub const MAX_CPUS: usize = 8;
+
+/// Returns the index of the executing CPU, below `MAX_CPUS`: 0 for the bootstrap processor,
+/// then in the order the application processors started.
+#[inline]
+pub fn id() -> usize {
+    percpu::current().id()
+}
+
+/// Returns the initial local APIC id of the executing CPU, APIC ids are not guaranteed to be
+/// contiguous.
+pub fn apic_id() -> u32 {
+    unsafe { __cpuid(1) }.ebx >> 24
+}
+
+/// read the timestamp counter
+#[inline]
+pub fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
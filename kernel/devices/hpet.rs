@@ -0,0 +1,379 @@
+//! High Precision Event Timer
+//!
+//! The main counter is used as a clock, the comparators can raise one-shot or periodic
+//! interrupts through the IOAPIC, or replace the PIT and RTC interrupts in legacy mode.
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use crate::acpi;
+use crate::interrupts::ioapic::{self, Polarity, Trigger};
+use crate::interrupts::lapic;
+use crate::interrupts::trap::{self, TrapFrame};
+use crate::vmem;
+use crate::{info, warn};
+
+const REG_CAPABILITIES: u64 = 0x000;
+const REG_CONFIG: u64 = 0x010;
+const REG_INTERRUPT_STATUS: u64 = 0x020;
+const REG_COUNTER: u64 = 0x0f0;
+const fn reg_timer_config(n: usize) -> u64 {
+    0x100 + 0x20 * n as u64
+}
+const fn reg_timer_comparator(n: usize) -> u64 {
+    0x108 + 0x20 * n as u64
+}
+
+// capabilities
+const CAP_LEGACY_ROUTE: u64 = 1 << 15;
+/// the period of the main counter, in femtoseconds, is in the high half
+const CAP_PERIOD_SHIFT: u64 = 32;
+/// the spec doesn't allow periods over 100ns
+const MAX_PERIOD_FS: u64 = 100_000_000;
+// general configuration
+const CONFIG_ENABLE: u64 = 1 << 0;
+const CONFIG_LEGACY_ROUTE: u64 = 1 << 1;
+// timer configuration
+const TIMER_LEVEL: u64 = 1 << 1;
+const TIMER_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAP: u64 = 1 << 4;
+/// write the accumulator rather than the comparator, in periodic mode
+const TIMER_SET_ACCUMULATOR: u64 = 1 << 6;
+const TIMER_32BITS: u64 = 1 << 8;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_ROUTE_MASK: u64 = 0x1f << TIMER_ROUTE_SHIFT;
+/// IOAPIC inputs the comparator can be routed to, as a bitmap
+const TIMER_ROUTE_CAP_SHIFT: u64 = 32;
+
+/// comparators we support, each gets its own vector
+pub const MAX_COMPARATORS: usize = 8;
+/// vector of the first comparator when routed through the IOAPIC
+pub const VECTOR_BASE: u8 = 0x50;
+/// GSIs below this are the ISA IRQs, already driven by other devices
+const ISA_IRQS: u32 = 16;
+
+/// virtual address of the registers, 0 until `init_hpet`
+static BASE: AtomicU64 = AtomicU64::new(0);
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+static COMPARATORS: AtomicUsize = AtomicUsize::new(0);
+/// comparators 0 and 1 routed in legacy mode, as a bitmap
+static LEGACY: AtomicUsize = AtomicUsize::new(0);
+
+const ZERO: AtomicU64 = AtomicU64::new(0);
+static FIRED: [AtomicU64; MAX_COMPARATORS] = [ZERO; MAX_COMPARATORS];
+const NO_HANDLER: AtomicUsize = AtomicUsize::new(0);
+static HANDLERS: [AtomicUsize; MAX_COMPARATORS] = [NO_HANDLER; MAX_COMPARATORS];
+
+unsafe fn read(reg: u64) -> u64 {
+    core::ptr::read_volatile((BASE.load(Ordering::Relaxed) + reg) as *const u64)
+}
+unsafe fn write(reg: u64, value: u64) {
+    core::ptr::write_volatile((BASE.load(Ordering::Relaxed) + reg) as *mut u64, value)
+}
+
+pub fn init_hpet() {
+    let phys = match acpi::hpet::find() {
+        Some(phys) => phys,
+        None => {
+            warn!("no HPET");
+            return;
+        }
+    };
+    let virt = match vmem::map_mmio(phys, 0x400) {
+        Ok(virt) => virt,
+        Err(err) => {
+            warn!("failed to map the HPET: {:?}", err);
+            return;
+        }
+    };
+    BASE.store(virt.as_u64(), Ordering::Relaxed);
+    let caps = unsafe { read(REG_CAPABILITIES) };
+    let period = caps >> CAP_PERIOD_SHIFT;
+    if period == 0 || period > MAX_PERIOD_FS {
+        warn!("HPET: invalid period {}fs", period);
+        BASE.store(0, Ordering::Relaxed);
+        return;
+    }
+    let comparators = ((caps >> 8 & 0x1f) as usize + 1).min(MAX_COMPARATORS);
+    unsafe {
+        write(REG_CONFIG, 0);
+        write(REG_COUNTER, 0);
+        for n in 0..comparators {
+            let config = read(reg_timer_config(n));
+            write(
+                reg_timer_config(n),
+                config & !(TIMER_ENABLE | TIMER_PERIODIC),
+            );
+        }
+        write(REG_CONFIG, CONFIG_ENABLE);
+    }
+    for n in 0..comparators {
+        trap::register(VECTOR_BASE + n as u8, hpet_handler);
+    }
+    PERIOD_FS.store(period, Ordering::Relaxed);
+    COMPARATORS.store(comparators, Ordering::Relaxed);
+    info!(
+        "HPET @ {:p}: {}Hz, {} comparators",
+        phys,
+        1_000_000_000_000_000 / period,
+        comparators
+    );
+}
+
+pub fn is_enabled() -> bool {
+    PERIOD_FS.load(Ordering::Relaxed) != 0
+}
+
+/// frequency of the main counter in Hz
+pub fn frequency() -> Option<u64> {
+    match PERIOD_FS.load(Ordering::Relaxed) {
+        0 => None,
+        period => Some(1_000_000_000_000_000 / period),
+    }
+}
+
+/// main counter value, which starts at 0 in `init_hpet`, `None` if there is no HPET
+pub fn counter() -> Option<u64> {
+    if is_enabled() {
+        Some(unsafe { read(REG_COUNTER) })
+    } else {
+        None
+    }
+}
+
+/// nanoseconds since `init_hpet`, `None` if there is no HPET
+pub fn nanos() -> Option<u64> {
+    match PERIOD_FS.load(Ordering::Relaxed) {
+        0 => None,
+        period => {
+            let counter = unsafe { read(REG_COUNTER) };
+            Some((counter as u128 * period as u128 / 1_000_000) as u64)
+        }
+    }
+}
+
+fn to_ticks(duration: Duration) -> u64 {
+    let period = PERIOD_FS.load(Ordering::Relaxed) as u128;
+    ((duration.as_nanos() * 1_000_000 + period - 1) / period) as u64
+}
+
+fn hpet_handler(frame: &mut TrapFrame) {
+    let n = frame.vector as usize - VECTOR_BASE as usize;
+    // only meaningful in level mode, but harmless otherwise
+    unsafe { write(REG_INTERRUPT_STATUS, 1 << n) };
+    FIRED[n].fetch_add(1, Ordering::Relaxed);
+    if let Some(handler) = Comparator(n).handler() {
+        handler();
+    }
+    lapic::eoi();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    /// Comparator 0 replaces the PIT on IRQ0 and comparator 1 the RTC on IRQ8,
+    /// their interrupts go to these IRQs' handlers.
+    Legacy,
+    IoApic(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    NoSuchComparator,
+    NotPeriodic,
+    InvalidRoute,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Comparator(usize);
+
+impl Comparator {
+    pub fn get(n: usize) -> Result<Comparator, Error> {
+        if n < COMPARATORS.load(Ordering::Relaxed) {
+            Ok(Comparator(n))
+        } else {
+            Err(Error::NoSuchComparator)
+        }
+    }
+    fn config(&self) -> u64 {
+        unsafe { read(reg_timer_config(self.0)) }
+    }
+    pub fn can_be_periodic(&self) -> bool {
+        self.config() & TIMER_PERIODIC_CAP != 0
+    }
+    /// GSIs the comparator can be routed to, as a bitmap
+    pub fn routes(&self) -> u32 {
+        (self.config() >> TIMER_ROUTE_CAP_SHIFT) as u32
+    }
+    /// the highest GSI it can be routed to that isn't an ISA IRQ
+    pub fn free_gsi(&self) -> Option<u32> {
+        let routes = self.routes();
+        (ISA_IRQS..32)
+            .rev()
+            .find(|&gsi| routes & (1 << gsi) != 0 && ioapic::has_gsi(gsi))
+    }
+    /// interrupts raised since `init_hpet`, through the IOAPIC
+    pub fn fired(&self) -> u64 {
+        FIRED[self.0].load(Ordering::Relaxed)
+    }
+    /// Called in interrupt context when the comparator fires through the IOAPIC.
+    pub fn set_handler(&self, handler: Option<fn()>) {
+        HANDLERS[self.0].store(handler.map_or(0, |f| f as usize), Ordering::Release);
+    }
+    fn handler(&self) -> Option<fn()> {
+        match HANDLERS[self.0].load(Ordering::Acquire) {
+            0 => None,
+            // SAFETY: only `set_handler` stores non-zero values, which are `fn()`
+            addr => Some(unsafe { core::mem::transmute::<usize, fn()>(addr) }),
+        }
+    }
+
+    /// Raise an interrupt once, in `delay`.
+    pub fn oneshot(&self, delay: Duration, route: Route) -> Result<(), Error> {
+        self.program(to_ticks(delay).max(1), false, route)
+    }
+    /// Raise an interrupt every `period`.
+    pub fn periodic(&self, period: Duration, route: Route) -> Result<(), Error> {
+        if !self.can_be_periodic() {
+            return Err(Error::NotPeriodic);
+        }
+        self.program(to_ticks(period).max(1), true, route)
+    }
+    pub fn stop(&self) {
+        unsafe {
+            write(
+                reg_timer_config(self.0),
+                self.config() & !(TIMER_ENABLE | TIMER_PERIODIC),
+            )
+        };
+        if let Some(gsi) = self.routed_gsi().filter(|&gsi| ioapic::has_gsi(gsi)) {
+            ioapic::mask(gsi);
+        }
+        let bit = 1 << self.0;
+        if LEGACY.fetch_and(!bit, Ordering::Relaxed) == bit {
+            // the last one in legacy mode, give IRQ0 and IRQ8 back to the PIT and RTC
+            unsafe { write(REG_CONFIG, read(REG_CONFIG) & !CONFIG_LEGACY_ROUTE) };
+        }
+    }
+    fn routed_gsi(&self) -> Option<u32> {
+        let legacy = unsafe { read(REG_CONFIG) } & CONFIG_LEGACY_ROUTE != 0;
+        if legacy && self.0 < 2 {
+            return None;
+        }
+        Some(((self.config() & TIMER_ROUTE_MASK) >> TIMER_ROUTE_SHIFT) as u32)
+    }
+
+    fn program(&self, ticks: u64, periodic: bool, route: Route) -> Result<(), Error> {
+        let n = self.0;
+        self.stop();
+        let mut config =
+            self.config() & !(TIMER_LEVEL | TIMER_PERIODIC | TIMER_ROUTE_MASK | TIMER_32BITS);
+        let mut general = unsafe { read(REG_CONFIG) };
+        match route {
+            Route::Legacy => {
+                let caps = unsafe { read(REG_CAPABILITIES) };
+                if n >= 2 || caps & CAP_LEGACY_ROUTE == 0 {
+                    return Err(Error::InvalidRoute);
+                }
+                general |= CONFIG_LEGACY_ROUTE;
+                LEGACY.fetch_or(1 << n, Ordering::Relaxed);
+            }
+            Route::IoApic(gsi) => {
+                if gsi >= 32 || self.routes() & (1 << gsi) == 0 || !ioapic::has_gsi(gsi) {
+                    return Err(Error::InvalidRoute);
+                }
+                // legacy mode routes both comparators 0 and 1, while the other one uses it
+                if n < 2 && general & CONFIG_LEGACY_ROUTE != 0 {
+                    return Err(Error::InvalidRoute);
+                }
+                config |= (gsi as u64) << TIMER_ROUTE_SHIFT;
+                ioapic::route(
+                    gsi,
+                    VECTOR_BASE + n as u8,
+                    Trigger::Edge,
+                    Polarity::ActiveHigh,
+                );
+            }
+        }
+        unsafe {
+            write(REG_CONFIG, general);
+            if periodic {
+                // the first write sets the comparator, the second the period
+                write(
+                    reg_timer_config(n),
+                    config | TIMER_ENABLE | TIMER_PERIODIC | TIMER_SET_ACCUMULATOR,
+                );
+                write(reg_timer_comparator(n), read(REG_COUNTER) + ticks);
+                write(reg_timer_comparator(n), ticks);
+            } else {
+                write(reg_timer_comparator(n), read(REG_COUNTER) + ticks);
+                write(reg_timer_config(n), config | TIMER_ENABLE);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_counter() {
+    // the tests run on QEMU's q35, which has one
+    assert!(is_enabled(), "no HPET");
+    let before = counter().unwrap();
+    crate::devices::pit::busy_wait_us(1000);
+    let elapsed = counter().unwrap() - before;
+    let expected = frequency().unwrap() / 1000;
+    assert!(
+        elapsed >= expected && elapsed < expected * 2,
+        "{} ticks for 1ms",
+        elapsed
+    );
+}
+
+/// Returns a comparator that can be routed through the IOAPIC, and its GSI.
+#[cfg(test)]
+fn routable(periodic: bool) -> Option<(Comparator, u32)> {
+    (0..COMPARATORS.load(Ordering::Relaxed))
+        .map(Comparator)
+        .filter(|comparator| !periodic || comparator.can_be_periodic())
+        .find_map(|comparator| Some((comparator, comparator.free_gsi()?)))
+}
+
+#[test_case]
+fn test_oneshot() {
+    // some machines (QEMU's i440FX) only route the HPET to ISA IRQs, not q35
+    let (comparator, gsi) = routable(false).expect("no comparator routable through the IOAPIC");
+    let before = comparator.fired();
+    comparator
+        .oneshot(Duration::from_millis(1), Route::IoApic(gsi))
+        .unwrap();
+    crate::devices::pit::sleep_ms(10);
+    assert_eq!(comparator.fired(), before + 1);
+    comparator.stop();
+}
+
+#[test_case]
+fn test_periodic() {
+    let (comparator, gsi) =
+        routable(true).expect("no periodic comparator routable through the IOAPIC");
+    let before = comparator.fired();
+    comparator
+        .periodic(Duration::from_millis(1), Route::IoApic(gsi))
+        .unwrap();
+    crate::devices::pit::sleep_ms(20);
+    comparator.stop();
+    assert!(comparator.fired() - before >= 10);
+}
+
+#[test_case]
+fn test_legacy_route() {
+    let comparator = Comparator::get(1).unwrap();
+    let legacy = || unsafe { read(REG_CONFIG) } & CONFIG_LEGACY_ROUTE != 0;
+    // the PIT loses IRQ0 while it's on
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        comparator
+            .oneshot(Duration::from_secs(1), Route::Legacy)
+            .unwrap();
+        assert!(legacy());
+        comparator.stop();
+    });
+    assert!(!legacy());
+}
@@ -1,14 +1,27 @@
 use lazy_static::lazy_static;
 use pc_keyboard::{layouts::Us104Key, DecodedKey, ScancodeSet1};
 use spin::Mutex;
+use x86_64::instructions::interrupts;
 use x86_64::instructions::port::Port;
 
+use crate::interrupts::deferred::{self, Work};
+use crate::task::stream::{IrqQueue, Receiver};
 use crate::vgaprint;
 
 const KB_PORT: u16 = 0x60;
+const SCANCODES_SIZE: usize = 128;
 
 lazy_static! {
     pub static ref KEYBOARD_DEVICE: KeyboardDevice = KeyboardDevice::new(KB_PORT);
+    static ref SCANCODES: IrqQueue<u8, SCANCODES_SIZE> = IrqQueue::new();
+}
+
+/// Stream of the raw scancodes, `None` if it's already taken.
+/// While it's open, keys aren't echoed to the screen anymore.
+pub fn scancodes() -> Option<Receiver<u8, SCANCODES_SIZE>> {
+    // the IRQ handler would spin on a half initialized queue
+    interrupts::without_interrupts(|| lazy_static::initialize(&SCANCODES));
+    SCANCODES.receiver()
 }
 
 pub struct KeyboardDevice {
@@ -24,18 +37,26 @@ impl KeyboardDevice {
             port: Mutex::new(Port::new(port_id)),
         }
     }
-    pub fn handle_irq(&self) {
-        let mut handler = KEYBOARD_DEVICE.handler.lock();
-        let mut port = KEYBOARD_DEVICE.port.lock();
-
-        let scancode: u8 = unsafe { port.read() };
-        if let Ok(Some(k_ev)) = handler.add_byte(scancode) {
-            if let Some(key) = handler.process_keyevent(k_ev) {
-                match key {
-                    DecodedKey::RawKey(_) => {}
-                    DecodedKey::Unicode(ch) => vgaprint!("{}", ch),
-                }
-            }
+    /// only read the scancode, it goes to the `scancodes` stream or is decoded in deferred
+    /// context. Returns the scancode.
+    pub fn handle_irq(&self) -> u8 {
+        let scancode: u8 = unsafe { self.port.lock().read() };
+        if !SCANCODES.push(scancode) {
+            deferred::schedule(Work::new(Self::process_scancode, scancode as usize));
+        }
+        scancode
+    }
+    /// Feed a scancode to the decoder, returns the key once it's complete.
+    pub fn decode(&self, scancode: u8) -> Option<DecodedKey> {
+        let mut handler = self.handler.lock();
+        match handler.add_byte(scancode) {
+            Ok(Some(k_ev)) => handler.process_keyevent(k_ev),
+            _ => None,
+        }
+    }
+    fn process_scancode(scancode: usize) {
+        if let Some(DecodedKey::Unicode(ch)) = KEYBOARD_DEVICE.decode(scancode as u8) {
+            vgaprint!("{}", ch);
         }
     }
 }
@@ -1,4 +1,7 @@
+pub mod hpet;
 pub mod keyboard;
+pub mod pit;
+pub mod rtc;
 pub mod serial;
 pub mod vga;
 
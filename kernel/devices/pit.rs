@@ -0,0 +1,272 @@
+//! 8253/8254 programmable interval timer
+//!
+//! Channel 0 runs as a rate generator on IRQ0 and drives the tick counter.
+//! When idle, it's switched to one-shot mode for the next timer, or stopped, and the ticks
+//! that were skipped are caught up with the clock on wakeup.
+use core::convert::TryFrom;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use core::time::Duration;
+
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+use crate::locked::IrqLocked;
+use crate::time::{self, Instant};
+
+/// frequency of the PIT oscillator, in Hz
+pub const BASE_FREQUENCY: u32 = 1_193_182;
+/// tick rate the kernel runs at
+pub const TICK_HZ: u32 = 1000;
+
+const CHANNEL0_PORT: u16 = 0x40;
+const COMMAND_PORT: u16 = 0x43;
+
+// command byte: channel 0, lobyte/hibyte access, mode 2 (rate generator), binary
+const CMD_CHANNEL0_RATE: u8 = 0b0011_0100;
+// command byte: channel 0, lobyte/hibyte access, mode 0 (interrupt on terminal count), binary
+const CMD_CHANNEL0_ONESHOT: u8 = 0b0011_0000;
+// command byte: channel 0, latch the current count
+const CMD_CHANNEL0_LATCH: u8 = 0b0000_0000;
+
+struct Pit {
+    channel0: Port<u8>,
+    command: Port<u8>,
+}
+
+static PIT: IrqLocked<Pit> = IrqLocked::new(Pit {
+    channel0: Port::new(CHANNEL0_PORT),
+    command: Port::new(COMMAND_PORT),
+});
+
+/// reload value of channel 0, 0 until `init_pit`
+static DIVISOR: AtomicU32 = AtomicU32::new(0);
+/// actual tick rate, in mHz since the divisor rarely divides `BASE_FREQUENCY`
+static FREQUENCY_MHZ: AtomicU64 = AtomicU64::new(0);
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// tick at which `tick` panics, 0 for none. Used by the test runner.
+static DEADLINE: AtomicU64 = AtomicU64::new(0);
+
+/// `Instant` at which the tick stopped, in nanoseconds, `NOT_IDLE` while ticking
+static IDLE_SINCE: AtomicU64 = AtomicU64::new(NOT_IDLE);
+const NOT_IDLE: u64 = u64::MAX;
+/// part of a tick left over by the last catch up, in nanoseconds
+static IDLE_CARRY: AtomicU64 = AtomicU64::new(0);
+/// idle periods and the ticks they skipped
+static IDLE_PERIODS: AtomicU64 = AtomicU64::new(0);
+static SKIPPED_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Program channel 0 to fire IRQ0 `hz` times per second.
+pub fn init_pit(hz: u32) {
+    assert!(hz > 0, "the PIT can't run at 0Hz");
+    assert!(
+        hz <= BASE_FREQUENCY / 2,
+        "the PIT can't run above {}Hz",
+        BASE_FREQUENCY / 2
+    );
+    // the counter is 16 bits, a reload value of 0 means 65536. The rate generator needs
+    // at least 2.
+    let divisor = (BASE_FREQUENCY / hz).min(0x1_0000);
+    program(CMD_CHANNEL0_RATE, Some(divisor));
+    DIVISOR.store(divisor, Ordering::Relaxed);
+    FREQUENCY_MHZ.store(
+        BASE_FREQUENCY as u64 * 1000 / divisor as u64,
+        Ordering::Relaxed,
+    );
+    crate::info!(
+        "PIT running at {}Hz (divisor {})",
+        BASE_FREQUENCY / divisor,
+        divisor
+    );
+}
+
+/// Set channel 0's mode, and its count if any. Without a count, it waits for one.
+fn program(command: u8, count: Option<u32>) {
+    let mut pit = PIT.lock();
+    unsafe {
+        pit.command.write(command);
+        if let Some(count) = count {
+            pit.channel0.write(count as u8);
+            pit.channel0.write((count >> 8) as u8);
+        }
+    }
+}
+
+/// called by the IRQ0 handler
+pub fn tick() {
+    // the one-shot fired: the ticks it skipped, this one included, are caught up with the clock
+    let ticks = if exit_idle() {
+        ticks()
+    } else {
+        TICKS.fetch_add(1, Ordering::Relaxed) + 1
+    };
+    let deadline = DEADLINE.load(Ordering::Relaxed);
+    if deadline != 0 && ticks >= deadline {
+        DEADLINE.store(0, Ordering::Relaxed);
+        panic!("timed out after {} ticks", ticks);
+    }
+}
+
+/// ticks since `init_pit`
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+fn ms_to_ticks(ms: u64) -> u64 {
+    to_ticks(Duration::from_millis(ms))
+}
+
+/// number of ticks in `duration`, rounded up, `u64::MAX` if there are more
+pub fn to_ticks(duration: Duration) -> u64 {
+    let mhz = FREQUENCY_MHZ.load(Ordering::Relaxed) as u128;
+    // round up, so we never wait less than asked
+    let ticks = (duration.as_nanos() * mhz + 999_999_999_999) / 1_000_000_000_000;
+    u64::try_from(ticks).unwrap_or(u64::MAX)
+}
+
+pub fn uptime() -> Duration {
+    match FREQUENCY_MHZ.load(Ordering::Relaxed) {
+        0 => Duration::ZERO,
+        mhz => Duration::from_micros(ticks() * 1_000_000_000 / mhz),
+    }
+}
+
+/// Panic from the timer interrupt if still running in `ms` milliseconds, `None` cancels it.
+pub fn set_deadline(ms: Option<u64>) {
+    let deadline = match ms {
+        Some(ms) if DIVISOR.load(Ordering::Relaxed) != 0 => ticks() + ms_to_ticks(ms).max(1),
+        _ => 0,
+    };
+    DEADLINE.store(deadline, Ordering::Relaxed);
+}
+
+/// Stop the tick, or only fire once in `ticks` ticks.
+/// Interrupts must stay disabled until the CPU halts, and `exit_idle` be called on wakeup.
+///
+/// Only allowed when `time` has a clock that doesn't depend on the ticks, returns false otherwise.
+pub fn enter_idle(ticks: Option<u64>) -> bool {
+    let divisor = DIVISOR.load(Ordering::Relaxed);
+    if divisor == 0 || !time::is_precise() {
+        return false;
+    }
+    IDLE_SINCE.store(Instant::now().as_nanos(), Ordering::Relaxed);
+    // the longest the 16 bits counter can wait, we'll just idle again if it's too short
+    let count = ticks.map(|ticks| (ticks * divisor as u64).min(0xffff) as u32);
+    program(CMD_CHANNEL0_ONESHOT, count);
+    true
+}
+
+/// Restart the tick if it was stopped by `enter_idle`, and count the ticks that were skipped.
+/// Returns whether it was stopped.
+pub fn exit_idle() -> bool {
+    let since = IDLE_SINCE.swap(NOT_IDLE, Ordering::Relaxed);
+    if since == NOT_IDLE {
+        return false;
+    }
+    program(CMD_CHANNEL0_RATE, Some(DIVISOR.load(Ordering::Relaxed)));
+    let mhz = FREQUENCY_MHZ.load(Ordering::Relaxed) as u128;
+    let elapsed = (Instant::now().as_nanos() - since + IDLE_CARRY.load(Ordering::Relaxed)) as u128;
+    let skipped = elapsed * mhz / 1_000_000_000_000;
+    let carry = elapsed - skipped * 1_000_000_000_000 / mhz;
+    IDLE_CARRY.store(carry as u64, Ordering::Relaxed);
+    TICKS.fetch_add(skipped as u64, Ordering::Relaxed);
+    IDLE_PERIODS.fetch_add(1, Ordering::Relaxed);
+    SKIPPED_TICKS.fetch_add(skipped as u64, Ordering::Relaxed);
+    true
+}
+
+/// Returns the number of idle periods, and the ticks skipped during them.
+pub fn idle_stats() -> (u64, u64) {
+    (
+        IDLE_PERIODS.load(Ordering::Relaxed),
+        SKIPPED_TICKS.load(Ordering::Relaxed),
+    )
+}
+
+/// Halt until at least `ms` milliseconds passed.
+/// Falls back to `busy_wait_us` when interrupts are disabled, as ticks wouldn't advance.
+pub fn sleep_ms(ms: u64) {
+    if !interrupts::are_enabled() || DIVISOR.load(Ordering::Relaxed) == 0 {
+        return busy_wait_us(ms * 1000);
+    }
+    // the current tick is already partly elapsed
+    let target = ticks() + ms_to_ticks(ms) + 1;
+    loop {
+        interrupts::disable();
+        if ticks() >= target {
+            interrupts::enable();
+            return;
+        }
+        // no tick can be missed between the check and the hlt
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// current count of channel 0
+fn read_counter() -> u32 {
+    let mut pit = PIT.lock();
+    unsafe {
+        pit.command.write(CMD_CHANNEL0_LATCH);
+        let low = pit.channel0.read() as u32;
+        let high = pit.channel0.read() as u32;
+        high << 8 | low
+    }
+}
+
+/// Spin for at least `us` microseconds, polling the PIT counter. Works with interrupts disabled.
+pub fn busy_wait_us(us: u64) {
+    let divisor = match DIVISOR.load(Ordering::Relaxed) {
+        // still the BIOS setup: 65536
+        0 => 0x1_0000,
+        divisor => divisor,
+    };
+    let mut remaining = us * BASE_FREQUENCY as u64 / 1_000_000;
+    let mut last = read_counter();
+    while remaining > 0 {
+        core::hint::spin_loop();
+        let now = read_counter();
+        // the counter goes down, and is reloaded with the divisor when it reaches 0
+        let elapsed = if now <= last {
+            last - now
+        } else {
+            last + divisor - now
+        };
+        remaining = remaining.saturating_sub(elapsed as u64);
+        last = now;
+    }
+}
+
+#[test_case]
+fn test_sleep_ms() {
+    let before = ticks();
+    sleep_ms(20);
+    assert!(ticks() - before >= ms_to_ticks(20));
+}
+
+#[test_case]
+fn test_idle_catch_up() {
+    if !time::is_precise() {
+        return;
+    }
+    let before = ticks();
+    interrupts::disable();
+    assert!(enter_idle(Some(5)));
+    interrupts::enable_and_hlt();
+    interrupts::disable();
+    exit_idle();
+    interrupts::enable();
+    // woken by the one-shot, or by another interrupt earlier
+    let skipped = ticks() - before;
+    assert!(skipped <= 6, "{} ticks for a 5 ticks one-shot", skipped);
+    // the tick runs again
+    let before = ticks();
+    busy_wait_us(5000);
+    assert!(ticks() - before >= 3);
+}
+
+#[test_case]
+fn test_busy_wait_us() {
+    let before = ticks();
+    busy_wait_us(20_000);
+    // allow for a couple of ticks lost to the other tests' interrupts
+    assert!(ticks() - before >= ms_to_ticks(20) - 2);
+}
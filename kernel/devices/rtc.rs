@@ -0,0 +1,316 @@
+//! MC146818 real-time clock, in the CMOS
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::instructions::port::Port;
+
+use crate::devices::pit;
+use crate::interrupts::pic::{CASCADE_LINE, IRQ, PICS};
+use crate::locked::IrqLocked;
+
+const INDEX_PORT: u16 = 0x70;
+const DATA_PORT: u16 = 0x71;
+/// bit 7 of the index port masks the NMIs, keep them enabled
+const NMI_DISABLE: u8 = 1 << 7;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+/// not standard, but where every PC we care about keeps it
+const REG_CENTURY: u8 = 0x32;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+const REG_STATUS_C: u8 = 0x0c;
+
+// status A
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const RATE_MASK: u8 = 0x0f;
+// status B
+const HOURS_24: u8 = 1 << 1;
+const BINARY: u8 = 1 << 2;
+const PERIODIC_INTERRUPT: u8 = 1 << 6;
+/// in 12 hours mode
+const HOUR_PM: u8 = 1 << 7;
+
+struct Cmos {
+    index: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Cmos {
+    unsafe fn read(&mut self, reg: u8) -> u8 {
+        self.index.write(reg & !NMI_DISABLE);
+        self.data.read()
+    }
+    unsafe fn write(&mut self, reg: u8, value: u8) {
+        self.index.write(reg & !NMI_DISABLE);
+        self.data.write(value)
+    }
+}
+
+static CMOS: IrqLocked<Cmos> = IrqLocked::new(Cmos {
+    index: Port::new(INDEX_PORT),
+    data: Port::new(DATA_PORT),
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    /// 1-12
+    pub month: u8,
+    /// 1-31
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// seconds since 1970-01-01 00:00:00 UTC, assuming the RTC is in UTC. `None` before 1970.
+    pub fn unix_timestamp(&self) -> Option<u64> {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        if days < 0 {
+            return None;
+        }
+        Some(
+            days as u64 * 86400
+                + self.hour as u64 * 3600
+                + self.minute as u64 * 60
+                + self.second as u64,
+        )
+    }
+    pub fn from_unix_timestamp(timestamp: u64) -> DateTime {
+        let (year, month, day) = civil_from_days((timestamp / 86400) as i64);
+        let secs = timestamp % 86400;
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (secs / 3600) as u8,
+            minute: (secs / 60 % 60) as u8,
+            second: (secs % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+// see: http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+fn civil_from_days(z: i64) -> (i64, i64, i64) {
+    let z = z + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    (
+        if m <= 2 {
+            yoe + era * 400 + 1
+        } else {
+            yoe + era * 400
+        },
+        m,
+        d,
+    )
+}
+
+/// the registers as they are, in whatever format the RTC uses
+#[derive(PartialEq, Eq)]
+struct Raw([u8; 7]);
+
+unsafe fn read_raw(cmos: &mut Cmos) -> Raw {
+    while cmos.read(REG_STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    Raw([
+        cmos.read(REG_SECONDS),
+        cmos.read(REG_MINUTES),
+        cmos.read(REG_HOURS),
+        cmos.read(REG_DAY),
+        cmos.read(REG_MONTH),
+        cmos.read(REG_YEAR),
+        cmos.read(REG_CENTURY),
+    ])
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+/// Read the current date and time.
+pub fn read() -> DateTime {
+    let mut cmos = CMOS.lock();
+    let (raw, status_b) = unsafe {
+        // an update can still start right after UIP was checked, read until we get the same values twice
+        let mut raw = read_raw(&mut cmos);
+        loop {
+            let again = read_raw(&mut cmos);
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        (raw, cmos.read(REG_STATUS_B))
+    };
+    let [second, minute, hour, day, month, year, century] = raw.0;
+    let pm = hour & HOUR_PM != 0;
+    let hour = hour & !HOUR_PM;
+    let decode = |value| {
+        if status_b & BINARY != 0 {
+            value
+        } else {
+            from_bcd(value)
+        }
+    };
+    let mut hour = decode(hour);
+    if status_b & HOURS_24 == 0 {
+        // 12am is midnight, 12pm noon
+        hour = hour % 12 + if pm { 12 } else { 0 };
+    }
+    let century = match decode(century) {
+        // no century register
+        c if !(19..=99).contains(&c) => 20,
+        c => c,
+    };
+    DateTime {
+        year: century as u16 * 100 + decode(year) as u16,
+        month: decode(month),
+        day: decode(day),
+        hour,
+        minute: decode(minute),
+        second: decode(second),
+    }
+}
+
+/// unix time when the uptime was `BOOT_UPTIME_MS`, 0 until `init_rtc`
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+static BOOT_UPTIME_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Read the RTC once, the wall-clock time is then kept with the PIT.
+pub fn init_rtc() {
+    let now = read();
+    let timestamp = match now.unix_timestamp() {
+        Some(timestamp) => timestamp,
+        None => {
+            crate::warn!("RTC: {} is before 1970, ignoring it", now);
+            return;
+        }
+    };
+    BOOT_UPTIME_MS.store(pit::uptime().as_millis() as u64, Ordering::Relaxed);
+    BOOT_TIME.store(timestamp, Ordering::Relaxed);
+    crate::info!("RTC: {} UTC", now);
+}
+
+/// Current unix time in milliseconds, without touching the hardware. `None` before `init_rtc`.
+pub fn now_ms() -> Option<u64> {
+    match BOOT_TIME.load(Ordering::Relaxed) {
+        0 => None,
+        boot => {
+            let since_boot =
+                pit::uptime().as_millis() as u64 - BOOT_UPTIME_MS.load(Ordering::Relaxed);
+            Some(boot * 1000 + since_boot)
+        }
+    }
+}
+
+/// Periodic interrupts received on IRQ8.
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::Relaxed)
+}
+
+/// Raise IRQ8 at `32768 >> (rate - 1)` Hz, rate in 3..=15. `None` disables it.
+pub fn set_periodic(rate: Option<u8>) {
+    let mut cmos = CMOS.lock();
+    unsafe {
+        let status_b = cmos.read(REG_STATUS_B);
+        match rate {
+            Some(rate) => {
+                assert!((3..=15).contains(&rate), "invalid RTC rate: {}", rate);
+                let status_a = cmos.read(REG_STATUS_A);
+                cmos.write(REG_STATUS_A, status_a & !RATE_MASK | rate);
+                cmos.write(REG_STATUS_B, status_b | PERIODIC_INTERRUPT);
+                // acknowledge anything pending, or the RTC won't raise IRQ8 again
+                cmos.read(REG_STATUS_C);
+                let mut pics = PICS.lock();
+                pics.unmask(CASCADE_LINE);
+                pics.unmask(IRQ::Rtc.line());
+            }
+            None => {
+                PICS.lock().mask(IRQ::Rtc.line());
+                cmos.write(REG_STATUS_B, status_b & !PERIODIC_INTERRUPT);
+            }
+        }
+    }
+}
+
+/// called by the IRQ8 handler
+pub fn handle_irq() {
+    // until status C is read, no other interrupt is raised
+    unsafe { CMOS.lock().read(REG_STATUS_C) };
+    PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+#[test_case]
+fn test_unix_timestamp() {
+    let date = DateTime {
+        year: 2021,
+        month: 3,
+        day: 1,
+        hour: 12,
+        minute: 34,
+        second: 56,
+    };
+    assert_eq!(date.unix_timestamp(), Some(1614602096));
+    assert_eq!(DateTime::from_unix_timestamp(1614602096), date);
+    assert_eq!(DateTime::from_unix_timestamp(0).year, 1970);
+    let before_epoch = DateTime {
+        year: 1969,
+        month: 12,
+        day: 31,
+        hour: 23,
+        minute: 59,
+        second: 59,
+    };
+    assert_eq!(before_epoch.unix_timestamp(), None);
+}
+
+#[test_case]
+fn test_read() {
+    let now = read();
+    assert!(now.year >= 2021);
+    assert!((1..=12).contains(&now.month) && (1..=31).contains(&now.day));
+    assert!(now.hour < 24 && now.minute < 60 && now.second < 60);
+}
+
+#[test_case]
+fn test_periodic_interrupt() {
+    let before = periodic_ticks();
+    // 1024Hz
+    set_periodic(Some(6));
+    pit::sleep_ms(20);
+    set_periodic(None);
+    assert!(periodic_ticks() > before);
+}
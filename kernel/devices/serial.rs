@@ -2,37 +2,77 @@ use core::fmt::Write;
 
 use lazy_static::lazy_static;
 use pache::ansi_term::TermStyle;
-use spin::Mutex;
 use uart_16550::SerialPort;
 use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+use crate::locked::IrqLocked;
+use crate::task::stream::{IrqQueue, Receiver};
 
 pub const SERIAL_PORT_ID: u16 = 0x3f8;
+const INPUT_SIZE: usize = 256;
 
 lazy_static! {
-    pub static ref SERIAL1: Mutex<(SerialPort, TermStyle)> = {
+    pub static ref SERIAL1: IrqLocked<(SerialPort, TermStyle)> = {
         let mut serial_port = unsafe { SerialPort::new(SERIAL_PORT_ID) };
         serial_port.init();
-        Mutex::new((serial_port, TermStyle::default()))
+        IrqLocked::new((serial_port, TermStyle::default()))
     };
+    static ref INPUT: IrqQueue<u8, INPUT_SIZE> = IrqQueue::new();
+}
+
+/// Stream of the bytes received on COM1, `None` if it's already taken.
+pub fn input() -> Option<Receiver<u8, INPUT_SIZE>> {
+    // the IRQ handler would spin on a half initialized queue
+    interrupts::without_interrupts(|| lazy_static::initialize(&INPUT));
+    INPUT.receiver()
+}
+
+/// Drain the receive FIFO into the `input` stream, returns whether a break into the debugger
+/// was requested.
+pub fn handle_irq() -> bool {
+    let _guard = SERIAL1.lock();
+    let mut line_status: Port<u8> = Port::new(SERIAL_PORT_ID + 5);
+    let mut data: Port<u8> = Port::new(SERIAL_PORT_ID);
+    let mut brk = false;
+    // bit 0: data ready
+    while unsafe { line_status.read() } & 1 != 0 {
+        let byte = unsafe { data.read() };
+        if byte == crate::kdb::BREAK_CHAR {
+            brk = true;
+        } else {
+            INPUT.push(byte);
+        }
+    }
+    brk
 }
 
 #[doc(hidden)]
 pub fn _serial_print(args: ::core::fmt::Arguments) {
-    interrupts::without_interrupts(|| {
-        SERIAL1.lock().0.write_fmt(args).unwrap();
-    });
+    SERIAL1.lock().0.write_fmt(args).unwrap();
 }
 
 #[doc(hidden)]
 #[cfg(not(test))]
 pub fn _serial_print_with_style(style: &TermStyle, args: ::core::fmt::Arguments) {
-    interrupts::without_interrupts(|| {
-        let mut guard = SERIAL1.lock();
-        let old_style = guard.1;
-        _serial_print_style(&mut guard.0, style);
-        guard.0.write_fmt(args).unwrap();
-        _serial_print_style(&mut guard.0, &old_style);
-    });
+    let mut guard = SERIAL1.lock();
+    let old_style = guard.1;
+    _serial_print_style(&mut guard.0, style);
+    if let Some(now) = crate::devices::rtc::now_ms() {
+        let secs = now / 1000 % 86400;
+        guard
+            .0
+            .write_fmt(format_args!(
+                "[{:02}:{:02}:{:02}.{:03}] ",
+                secs / 3600,
+                secs / 60 % 60,
+                secs % 60,
+                now % 1000
+            ))
+            .unwrap();
+    }
+    guard.0.write_fmt(args).unwrap();
+    _serial_print_style(&mut guard.0, &old_style);
 }
 #[doc(hidden)]
 #[cfg(test)]
@@ -40,20 +80,16 @@ pub fn _serial_print_with_style(_: &TermStyle, _: ::core::fmt::Arguments) {}
 
 #[doc(hidden)]
 pub fn _serial_serial_set_style(style: &TermStyle) {
-    interrupts::without_interrupts(|| {
-        let mut guard = SERIAL1.lock();
-        _serial_print_style(&mut guard.0, style);
-        guard.1 = *style;
-    });
+    let mut guard = SERIAL1.lock();
+    _serial_print_style(&mut guard.0, style);
+    guard.1 = *style;
 }
 
 #[doc(hidden)]
 pub fn _serial_reset_style() {
-    interrupts::without_interrupts(|| {
-        let mut guard = SERIAL1.lock();
-        _serial_print_reset_style(&mut guard.0);
-        guard.1 = TermStyle::RESET;
-    });
+    let mut guard = SERIAL1.lock();
+    _serial_print_reset_style(&mut guard.0);
+    guard.1 = TermStyle::RESET;
 }
 
 #[doc(hidden)]
@@ -2,9 +2,9 @@
 use core::fmt;
 use core::mem;
 use lazy_static::lazy_static;
-use spin::mutex::Mutex;
 use volatile::Volatile;
-use x86_64::instructions::interrupts;
+
+use crate::locked::IrqLocked;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
@@ -92,10 +92,10 @@ pub struct Writer {
     pub buffer: &'static mut Buffer,
 }
 lazy_static! {
-    pub static ref VGA_WRITER: Mutex<Writer> = {
+    pub static ref VGA_WRITER: IrqLocked<Writer> = {
         let addr = 0xb8000;
         crate::info!("loading VGA buffer @ 0x{:x}", addr);
-        Mutex::new(Writer {
+        IrqLocked::new(Writer {
             col: 0,
             row: 0,
             colour_code: ColourCode::new(Colour::Yellow, Colour::Black),
@@ -197,7 +197,5 @@ macro_rules! vgaeprintln {
 #[doc(hidden)]
 pub fn _vgaprint(args: fmt::Arguments) {
     use core::fmt::Write;
-    interrupts::without_interrupts(|| {
-        VGA_WRITER.lock().write_fmt(args).unwrap();
-    });
+    VGA_WRITER.lock().write_fmt(args).unwrap();
 }
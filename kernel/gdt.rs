@@ -1,3 +1,9 @@
+use alloc::boxed::Box;
+use alloc::vec;
+use core::cell::UnsafeCell;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
 use crate::GlobalResource;
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
@@ -7,37 +13,115 @@ use x86_64::VirtAddr;
 use crate::info;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
 pub type Gdt = (GlobalDescriptorTable, Selectors);
 
+/// the TSS is updated on every switch to a thread that can run in user mode
+struct Tss(UnsafeCell<TaskStateSegment>);
+unsafe impl Sync for Tss {}
+
+/// size of the double fault and NMI stacks
+const IST_STACK_SIZE: usize = 4096 * 5;
+
+crate::percpu! {
+    /// the TSS loaded on the CPU, for `set_kernel_stack`
+    static CPU_TSS: AtomicPtr<TaskStateSegment> = AtomicPtr::new(ptr::null_mut());
+}
+
 lazy_static! {
-    static ref GDT: Gdt = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss = gdt.add_entry(Descriptor::tss_segment(TaskStateSegment::the()));
-        (gdt, Selectors { code, tss })
-    };
-    static ref TSS: TaskStateSegment = {
+    static ref GDT: Gdt = new_gdt(TaskStateSegment::the());
+    static ref TSS: Tss = {
         let mut tss = TaskStateSegment::new();
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+            static mut STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
             let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
+            let stack_end = stack_start + IST_STACK_SIZE;
             stack_end
         };
-        tss
+        // NMIs can come in anywhere, even in the middle of a stack switch
+        tss.interrupt_stack_table[NMI_IST_INDEX as usize] = {
+            static mut STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
+            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+            stack_start + IST_STACK_SIZE
+        };
+        Tss(UnsafeCell::new(tss))
     };
 }
 
+fn new_gdt(tss: &'static TaskStateSegment) -> Gdt {
+    let mut gdt = GlobalDescriptorTable::new();
+    // SYSCALL and SYSRET expect this order: kernel code then data, user data then code
+    let kernel_code = gdt.add_entry(Descriptor::kernel_code_segment());
+    let kernel_data = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code = gdt.add_entry(Descriptor::user_code_segment());
+    let tss = gdt.add_entry(Descriptor::tss_segment(tss));
+    (
+        gdt,
+        Selectors {
+            kernel_code,
+            kernel_data,
+            user_data,
+            user_code,
+            tss,
+        },
+    )
+}
+
+/// The GDT of an application processor, with the TSS and IST stacks it points to.
+pub struct ApGdt {
+    gdt: Gdt,
+    tss: Box<Tss>,
+    _stacks: [Box<[u64]>; 2],
+}
+
+impl ApGdt {
+    /// Load it on the executing CPU, see `load`.
+    pub fn load(&'static self) {
+        load(&self.gdt, &self.tss);
+    }
+}
+
+/// Build the GDT of an application processor, each CPU needs its own TSS: loading one marks
+/// it busy. Its selectors are the same as the bootstrap processor's.
+pub fn new_ap() -> Box<ApGdt> {
+    let stacks = [
+        vec![0; IST_STACK_SIZE / 8].into_boxed_slice(),
+        vec![0; IST_STACK_SIZE / 8].into_boxed_slice(),
+    ];
+    let top = |stack: &[u64]| VirtAddr::from_ptr(stack.as_ptr()) + IST_STACK_SIZE;
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = top(&stacks[0]);
+    tss.interrupt_stack_table[NMI_IST_INDEX as usize] = top(&stacks[1]);
+    let tss = Box::new(Tss(UnsafeCell::new(tss)));
+    // SAFETY: the TSS doesn't move, and is dropped with the GDT pointing to it
+    let gdt = new_gdt(unsafe { &*tss.0.get() });
+    Box::new(ApGdt {
+        gdt,
+        tss,
+        _stacks: stacks,
+    })
+}
+
+/// Load `gdt` and its TSS, and reload the segment registers.
+fn load(gdt: &'static Gdt, tss: &'static Tss) {
+    use x86_64::instructions::segmentation::{load_ds, load_es, load_ss, set_cs};
+    use x86_64::instructions::tables::load_tss;
+    gdt.0.load();
+    unsafe {
+        set_cs(gdt.1.kernel_code);
+        load_ss(gdt.1.kernel_data);
+        load_ds(gdt.1.kernel_data);
+        load_es(gdt.1.kernel_data);
+        load_tss(gdt.1.tss);
+    }
+    CPU_TSS.get().store(tss.0.get(), Ordering::Relaxed);
+}
+
 impl GlobalResource for (GlobalDescriptorTable, Selectors) {
     fn init() {
-        use x86_64::instructions::{segmentation::set_cs, tables::load_tss};
         info!("initializing GDT");
-        GDT.0.load();
-        unsafe {
-            set_cs(GDT.1.code);
-            load_tss(GDT.1.tss);
-        }
+        load(&GDT, &TSS);
     }
 
     fn the() -> &'static Self {
@@ -48,11 +132,36 @@ impl GlobalResource for TaskStateSegment {
     fn init() {}
 
     fn the() -> &'static Self {
-        &TSS
+        unsafe { &*TSS.0.get() }
     }
 }
 
+/// Set the stack the executing CPU switches to when an interrupt comes in user mode.
+pub fn set_kernel_stack(top: VirtAddr) {
+    let tss = CPU_TSS.get().load(Ordering::Relaxed);
+    assert!(!tss.is_null(), "no TSS loaded on this CPU");
+    // the TSS is packed, and only read by the CPU on privilege changes
+    unsafe {
+        let rsp0 = ptr::addr_of_mut!((*tss).privilege_stack_table) as *mut VirtAddr;
+        rsp0.write_unaligned(top);
+    }
+}
+
+pub fn selectors() -> &'static Selectors {
+    &GDT.1
+}
+
+#[derive(Debug, Clone, Copy)]
 pub struct Selectors {
-    code: SegmentSelector,
-    tss: SegmentSelector,
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    /// with RPL 3, like `user_code`
+    pub user_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    pub tss: SegmentSelector,
+}
+
+#[test_case]
+fn test_bsp_tss() {
+    assert_eq!(CPU_TSS.get().load(Ordering::Relaxed), TSS.0.get());
 }
@@ -142,6 +142,16 @@ impl BootstrapFramesAlloc {
 
         frame
     }
+    /// small pages `pop_region` can still give
+    pub fn small_frames_left(&self) -> u64 {
+        let in_ranges: u64 = self
+            .srange
+            .iter()
+            .filter(|r| r.end > self.snext)
+            .map(|r| (r.end - r.start.max(self.snext)) / PAGE_SIZE)
+            .sum();
+        in_ranges + self.back.saturating_sub(self.lnext) / PAGE_SIZE
+    }
     /// pop a large page region (the page is not mapped)
     pub fn pop_large(&mut self) -> Option<PhysFrame<Size2MiB>> {
         assert!(<Size2MiB as PageSize>::SIZE == LPAGE_SIZE); // TODO move this to tests
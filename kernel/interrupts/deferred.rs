@@ -0,0 +1,136 @@
+//! deferred work ("bottom halves").
+//!
+//! IRQ handlers should only acknowledge their device and push a small work item here, the
+//! work is then run with interrupts enabled: at the end of the interrupt or by the idle loop.
+//!
+//! Work never runs on top of code holding a lock: not at the end of an interrupt which came in
+//! with preemption disabled, as `Locked` holders have it, or with interrupts disabled, as
+//! `IrqLocked` holders have them. It's left pending until preemption is enabled again, see
+//! `preempt::enable`, or the next interrupt.
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use lazy_static::lazy_static;
+use pache::ring::Ring;
+use x86_64::instructions::interrupts;
+use x86_64::registers::rflags::RFlags;
+
+use crate::interrupts::trap::TrapFrame;
+use crate::thread::preempt;
+
+const QUEUE_SIZE: usize = 256;
+
+lazy_static! {
+    static ref QUEUE: Ring<Work, QUEUE_SIZE> = Ring::new();
+}
+/// set while the queue is being drained, so nested interrupts don't drain it too
+static RUNNING: AtomicBool = AtomicBool::new(false);
+/// work that was dropped because the queue was full
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy)]
+pub struct Work {
+    func: fn(usize),
+    arg: usize,
+}
+impl Work {
+    pub const fn new(func: fn(usize), arg: usize) -> Work {
+        Work { func, arg }
+    }
+}
+
+/// must be called before interrupts are enabled, so the queue isn't lazily
+/// initialized from an IRQ handler.
+pub fn init_deferred() {
+    lazy_static::initialize(&QUEUE);
+}
+
+/// queue `work`, it is dropped if the queue is full. Safe to call from interrupt context.
+///
+/// Returns whether it was queued.
+pub fn schedule(work: Work) -> bool {
+    let queued = QUEUE.push(work).is_ok();
+    if !queued {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+    queued
+}
+
+/// number of work items dropped because the queue was full
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Whether work is waiting to run.
+pub fn has_pending() -> bool {
+    !QUEUE.is_empty()
+}
+
+/// Run all the pending work with interrupts enabled.
+///
+/// Does nothing if the queue is already being drained, i.e. we interrupted `run_pending`, or if
+/// preemption is disabled.
+pub fn run_pending() {
+    if !preempt::is_enabled() {
+        return;
+    }
+    // switching thread would stop draining until it's switched back to.
+    // Dropped after interrupts are restored: a deferred switch only happens if they were enabled.
+    let _preempt = preempt::guard();
+    let enabled = interrupts::are_enabled();
+    loop {
+        if RUNNING.swap(true, Ordering::Acquire) {
+            break;
+        }
+        interrupts::enable();
+        while let Some(work) = QUEUE.pop() {
+            (work.func)(work.arg);
+        }
+        RUNNING.store(false, Ordering::Release);
+        // work might have been pushed after the last pop, but skipped draining.
+        if QUEUE.is_empty() {
+            break;
+        }
+    }
+    if !enabled {
+        interrupts::disable();
+    }
+}
+
+/// Called at the end of IRQs, runs the pending work unless the interrupted code had interrupts
+/// disabled.
+pub fn on_irq_exit(frame: &TrapFrame) {
+    if RFlags::from_bits_truncate(frame.rflags).contains(RFlags::INTERRUPT_FLAG) {
+        run_pending();
+    }
+}
+
+#[test_case]
+fn test_run_pending() {
+    static RAN: AtomicU64 = AtomicU64::new(0);
+    fn work(arg: usize) {
+        RAN.fetch_add(arg as u64, Ordering::Relaxed);
+    }
+    let before = dropped();
+    for _ in 0..3 {
+        schedule(Work::new(work, 2));
+    }
+    run_pending();
+    assert_eq!(dropped(), before);
+    assert_eq!(RAN.load(Ordering::Relaxed), 6);
+}
+
+#[test_case]
+fn test_preempt_disabled() {
+    static RAN: AtomicBool = AtomicBool::new(false);
+    fn work(_: usize) {
+        RAN.store(true, Ordering::Relaxed);
+    }
+    {
+        let _guard = preempt::guard();
+        assert!(schedule(Work::new(work, 0)));
+        run_pending();
+        assert!(!RAN.load(Ordering::Relaxed));
+    }
+    // run when the guard was dropped
+    assert!(RAN.load(Ordering::Relaxed));
+}
@@ -1,60 +1,134 @@
+use core::mem;
+
 use crate::gdt;
 use crate::info;
+use crate::interrupts::nmi;
 use crate::interrupts::pic::IRQ;
+use crate::interrupts::trap::{self, TrapFrame, TrapHandler, NUM_VECTORS};
+use crate::kdb::{self, probe};
+use crate::syscall;
 use lazy_static::lazy_static;
-use x86_64::structures::idt::{
-    HandlerFunc, InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode,
-};
+use x86_64::structures::idt::{Entry, HandlerFunc, InterruptDescriptorTable, PageFaultErrorCode};
+use x86_64::PrivilegeLevel;
+
+pub const DEBUG_VECTOR: u8 = 1;
+pub const NMI_VECTOR: u8 = 2;
+pub const BREAKPOINT_VECTOR: u8 = 3;
+pub const DOUBLE_FAULT_VECTOR: u8 = 8;
+pub const GENERAL_PROTECTION_VECTOR: u8 = 13;
+pub const PAGE_FAULT_VECTOR: u8 = 14;
 
-const IRQ_HANDLERS: [(IRQ, HandlerFunc); 2] = [
+const EXCEPTION_HANDLERS: [(u8, TrapHandler); 6] = [
+    (DEBUG_VECTOR, debug_handler),
+    (NMI_VECTOR, nmi::nmi_handler),
+    (BREAKPOINT_VECTOR, breakpoint_handler),
+    (DOUBLE_FAULT_VECTOR, double_fault_handler),
+    (GENERAL_PROTECTION_VECTOR, general_protection_handler),
+    (PAGE_FAULT_VECTOR, page_fault_handler),
+];
+const IRQ_HANDLERS: [(IRQ, TrapHandler); 4] = [
     (IRQ::Timer, timer_handler),
     (IRQ::Keyboard, keyboard_handler),
+    (IRQ::Com1, com1_handler),
+    (IRQ::Rtc, rtc_handler),
 ];
 
-pub fn init_idt() {
-    lazy_static! {
-        pub static ref IDT: InterruptDescriptorTable = {
-            let mut idt = InterruptDescriptorTable::new();
-            idt.breakpoint.set_handler_fn(breakpoint_handler);
-            unsafe {
-                idt.double_fault
-                    .set_handler_fn(double_fault_handler)
-                    .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-            }
-            idt.page_fault.set_handler_fn(page_fault_handler);
-            for (irq, handler) in IRQ_HANDLERS {
-                idt[irq.as_usize()].set_handler_fn(handler);
-            }
-            idt
+lazy_static! {
+    static ref IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        // all entries have the same layout, whatever the handler type they're declared with.
+        let entries = unsafe {
+            &mut *(&mut idt as *mut InterruptDescriptorTable
+                as *mut [Entry<HandlerFunc>; NUM_VECTORS])
         };
+        for (vector, entry) in entries.iter_mut().enumerate() {
+            let stub = trap::stub_addr(vector as u8).as_u64();
+            let options = entry.set_handler_fn(unsafe { mem::transmute::<u64, HandlerFunc>(stub) });
+            match vector as u8 {
+                DOUBLE_FAULT_VECTOR => unsafe {
+                    options.set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+                },
+                NMI_VECTOR => unsafe {
+                    options.set_stack_index(gdt::NMI_IST_INDEX);
+                },
+                syscall::SYSCALL_VECTOR => {
+                    options.set_privilege_level(PrivilegeLevel::Ring3);
+                }
+                _ => {}
+            }
+        }
+        idt
+    };
+}
+
+pub fn init_idt() {
+    for (vector, handler) in EXCEPTION_HANDLERS {
+        trap::register(vector, handler);
+    }
+    for (irq, handler) in IRQ_HANDLERS {
+        trap::register(irq.as_u8(), handler);
     }
     info!("loading IDT");
     IDT.load();
 }
 
-extern "x86-interrupt" fn breakpoint_handler(_: InterruptStackFrame) {}
+/// Load the IDT on an application processor, `init_idt` must have run on the bootstrap one.
+pub fn load_idt() {
+    IDT.load();
+}
+
+fn debug_handler(frame: &mut TrapFrame) {
+    kdb::debug_trap(frame);
+}
+fn breakpoint_handler(frame: &mut TrapFrame) {
+    kdb::enter(frame, kdb::Reason::Breakpoint);
+}
 
-extern "x86-interrupt" fn double_fault_handler(sf: InterruptStackFrame, _error_code: u64) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT! dumping stackframe\n{:#?}\n", sf);
+fn double_fault_handler(frame: &mut TrapFrame) {
+    panic!(
+        "EXCEPTION: DOUBLE FAULT! dumping stackframe\n{:#?}\n",
+        frame
+    );
 }
-extern "x86-interrupt" fn page_fault_handler(sf: InterruptStackFrame, ecode: PageFaultErrorCode) {
+fn general_protection_handler(frame: &mut TrapFrame) {
+    if probe::fixup(frame) {
+        return;
+    }
+    panic!(
+        "EXCEPTION: GENERAL PROTECTION FAULT `{:#x}`\n{:#?}\n",
+        frame.error_code, frame
+    );
+}
+fn page_fault_handler(frame: &mut TrapFrame) {
     use x86_64::registers::control::Cr2;
+    if probe::fixup(frame) {
+        return;
+    }
     panic!(
         "EXCEPTION: PAGEFAULT @ 0x{:x} `{:?}`\n{:#?}\n",
         Cr2::read(),
-        ecode,
-        sf
+        PageFaultErrorCode::from_bits_truncate(frame.error_code),
+        frame
     );
 }
-extern "x86-interrupt" fn timer_handler(_: InterruptStackFrame) {
-    unsafe {
-        super::PICS.lock().notify_eoi(IRQ::Timer.as_u8());
+
+// IRQs are acknowledged by the dispatcher, see `trap::trap_dispatch`.
+fn timer_handler(_: &mut TrapFrame) {
+    crate::devices::pit::tick();
+    crate::time::timer::on_tick(crate::devices::pit::ticks());
+    crate::thread::scheduler::tick();
+}
+fn rtc_handler(_: &mut TrapFrame) {
+    crate::devices::rtc::handle_irq();
+}
+fn keyboard_handler(frame: &mut TrapFrame) {
+    if crate::devices::KEYBOARD_DEVICE.handle_irq() == kdb::HOTKEY_SCANCODE {
+        kdb::enter(frame, kdb::Reason::Hotkey);
     }
 }
-extern "x86-interrupt" fn keyboard_handler(_: InterruptStackFrame) {
-    crate::devices::KEYBOARD_DEVICE.handle_irq();
-    unsafe {
-        super::PICS.lock().notify_eoi(IRQ::Keyboard.as_u8());
+fn com1_handler(frame: &mut TrapFrame) {
+    if crate::devices::serial::handle_irq() {
+        kdb::enter(frame, kdb::Reason::Hotkey);
     }
 }
 
@@ -0,0 +1,142 @@
+//! IOAPIC, only for the inputs the 8259 doesn't have
+//!
+//! ISA IRQs keep going through the PIC, the IOAPIC pins they're on stay masked.
+//! Interrupts routed here are delivered by the local APIC, their handlers must call `lapic::eoi`.
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use crate::acpi::madt::{self, Entry};
+use crate::interrupts::lapic;
+use crate::locked::IrqLocked;
+use crate::vmem;
+use crate::{info, warn};
+
+const REG_SELECT: u64 = 0x00;
+const REG_WINDOW: u64 = 0x10;
+
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION: u32 = 0x10;
+
+const REDIRECTION_MASKED: u64 = 1 << 16;
+const REDIRECTION_LEVEL: u64 = 1 << 15;
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+
+/// virtual address of the registers, 0 until `init_ioapic`
+static BASE: AtomicU64 = AtomicU64::new(0);
+static GSI_BASE: AtomicU32 = AtomicU32::new(0);
+static PINS: AtomicU32 = AtomicU32::new(0);
+/// the select/window pair must not be interleaved
+static LOCK: IrqLocked<()> = IrqLocked::new(());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// Map the first IOAPIC of the MADT and mask all its pins.
+pub fn init_ioapic() {
+    if !lapic::is_enabled() {
+        warn!("no local APIC to deliver the IOAPIC interrupts to");
+        return;
+    }
+    let found = madt::entries().and_then(|mut entries| {
+        entries.find_map(|entry| match entry {
+            Entry::IoApic {
+                address, gsi_base, ..
+            } => Some((address, gsi_base)),
+            _ => None,
+        })
+    });
+    let (phys, gsi_base) = match found {
+        Some(found) => found,
+        None => {
+            warn!("no IOAPIC in the MADT");
+            return;
+        }
+    };
+    let virt = match vmem::map_mmio(phys, 0x20) {
+        Ok(virt) => virt,
+        Err(err) => {
+            warn!("failed to map the IOAPIC: {:?}", err);
+            return;
+        }
+    };
+    BASE.store(virt.as_u64(), Ordering::Relaxed);
+    GSI_BASE.store(gsi_base, Ordering::Relaxed);
+    let pins = unsafe { read(IOAPIC_VERSION) >> 16 & 0xff } + 1;
+    PINS.store(pins, Ordering::Relaxed);
+    for pin in 0..pins {
+        mask(gsi_base + pin);
+    }
+    info!(
+        "IOAPIC @ {:p}: GSIs {}..{}",
+        phys,
+        gsi_base,
+        gsi_base + pins
+    );
+}
+
+pub fn is_enabled() -> bool {
+    BASE.load(Ordering::Relaxed) != 0
+}
+
+unsafe fn read(reg: u32) -> u32 {
+    let base = BASE.load(Ordering::Relaxed);
+    let _guard = LOCK.lock();
+    core::ptr::write_volatile((base + REG_SELECT) as *mut u32, reg);
+    core::ptr::read_volatile((base + REG_WINDOW) as *const u32)
+}
+unsafe fn write(reg: u32, value: u32) {
+    let base = BASE.load(Ordering::Relaxed);
+    let _guard = LOCK.lock();
+    core::ptr::write_volatile((base + REG_SELECT) as *mut u32, reg);
+    core::ptr::write_volatile((base + REG_WINDOW) as *mut u32, value)
+}
+
+fn pin(gsi: u32) -> u32 {
+    assert!(is_enabled(), "IOAPIC not initialized");
+    let pin = gsi.wrapping_sub(GSI_BASE.load(Ordering::Relaxed));
+    assert!(
+        pin < PINS.load(Ordering::Relaxed),
+        "GSI {} isn't on the IOAPIC",
+        gsi
+    );
+    pin
+}
+
+fn write_redirection(gsi: u32, entry: u64) {
+    let reg = IOAPIC_REDIRECTION + 2 * pin(gsi);
+    unsafe {
+        // masked while it's half written
+        write(reg, REDIRECTION_MASKED as u32);
+        write(reg + 1, (entry >> 32) as u32);
+        write(reg, entry as u32);
+    }
+}
+
+/// Deliver `gsi` to `vector` on the executing CPU, in fixed mode.
+pub fn route(gsi: u32, vector: u8, trigger: Trigger, polarity: Polarity) {
+    let mut entry = vector as u64 | (lapic::id() as u64) << 56;
+    if trigger == Trigger::Level {
+        entry |= REDIRECTION_LEVEL;
+    }
+    if polarity == Polarity::ActiveLow {
+        entry |= REDIRECTION_ACTIVE_LOW;
+    }
+    write_redirection(gsi, entry);
+}
+
+pub fn mask(gsi: u32) {
+    write_redirection(gsi, REDIRECTION_MASKED);
+}
+
+/// whether `gsi` is handled by the IOAPIC
+pub fn has_gsi(gsi: u32) -> bool {
+    let pin = gsi.wrapping_sub(GSI_BASE.load(Ordering::Relaxed));
+    is_enabled() && pin < PINS.load(Ordering::Relaxed)
+}
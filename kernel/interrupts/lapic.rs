@@ -0,0 +1,122 @@
+//! local APIC (xAPIC mode)
+//!
+//! IRQs still come from the 8259 PIC through LINT0, the local APIC is only used for what
+//! the PIC can't do: NMIs from the performance counters, IPIs...
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::registers::model_specific::Msr;
+use x86_64::PhysAddr;
+
+use crate::interrupts::trap::{self, TrapFrame};
+use crate::vmem;
+use crate::{info, warn};
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ADDR_MASK: u64 = 0xf_ffff_f000;
+
+// registers, as offsets from the base
+pub const REG_ID: u32 = 0x20;
+pub const REG_VERSION: u32 = 0x30;
+pub const REG_EOI: u32 = 0xb0;
+pub const REG_SPURIOUS: u32 = 0xf0;
+pub const REG_ICR_LOW: u32 = 0x300;
+pub const REG_ICR_HIGH: u32 = 0x310;
+pub const REG_LVT_TIMER: u32 = 0x320;
+pub const REG_LVT_PERF: u32 = 0x340;
+pub const REG_LVT_LINT0: u32 = 0x350;
+pub const REG_LVT_LINT1: u32 = 0x360;
+pub const REG_LVT_ERROR: u32 = 0x370;
+pub const REG_TIMER_INITIAL: u32 = 0x380;
+pub const REG_TIMER_CURRENT: u32 = 0x390;
+pub const REG_TIMER_DIVIDE: u32 = 0x3e0;
+
+pub const LVT_MASKED: u32 = 1 << 16;
+pub const DELIVERY_NMI: u32 = 0b100 << 8;
+pub const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+/// divide the timer's clock by 16
+pub const TIMER_DIVIDE_16: u32 = 0b0011;
+
+const SPURIOUS_ENABLE: u32 = 1 << 8;
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+// interrupt command register
+pub const ICR_INIT: u32 = 0b101 << 8;
+pub const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+pub const ICR_ASSERT: u32 = 1 << 14;
+
+/// virtual address of the registers, 0 until `init_lapic`
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+pub fn init_lapic() {
+    let phys = PhysAddr::new(unsafe { Msr::new(IA32_APIC_BASE).read() } & APIC_BASE_ADDR_MASK);
+    let virt = match vmem::map_mmio(phys, 0x400) {
+        Ok(virt) => virt,
+        Err(err) => {
+            warn!("failed to map the local APIC: {:?}", err);
+            return;
+        }
+    };
+    BASE.store(virt.as_u64(), Ordering::Relaxed);
+    // spurious interrupts must not be acknowledged
+    trap::register(SPURIOUS_VECTOR, |_: &mut TrapFrame| {});
+    enable();
+    info!(
+        "local APIC {} @ {:p} (version {:#x})",
+        id(),
+        phys,
+        unsafe { read(REG_VERSION) } & 0xff
+    );
+}
+
+/// Enable the local APIC of the executing CPU, `init_lapic` must have succeeded on the
+/// bootstrap processor: the registers are at the same address on every CPU.
+pub fn enable() {
+    unsafe {
+        let spurious = read(REG_SPURIOUS);
+        write(
+            REG_SPURIOUS,
+            spurious & !0xff | SPURIOUS_ENABLE | SPURIOUS_VECTOR as u32,
+        );
+    }
+}
+
+pub fn is_enabled() -> bool {
+    BASE.load(Ordering::Relaxed) != 0
+}
+
+/// SAFETY: `init_lapic` must have succeeded
+pub unsafe fn read(reg: u32) -> u32 {
+    let base = BASE.load(Ordering::Relaxed);
+    debug_assert!(base != 0);
+    core::ptr::read_volatile((base + reg as u64) as *const u32)
+}
+/// SAFETY: `init_lapic` must have succeeded, and writing `reg` must not break anything
+pub unsafe fn write(reg: u32, value: u32) {
+    let base = BASE.load(Ordering::Relaxed);
+    debug_assert!(base != 0);
+    core::ptr::write_volatile((base + reg as u64) as *mut u32, value)
+}
+
+/// APIC id of the executing CPU
+pub fn id() -> u32 {
+    unsafe { read(REG_ID) >> 24 }
+}
+
+/// Send an inter-processor interrupt to the CPU with `apic_id`, `command` is the low half of
+/// the ICR. Returns once the local APIC accepted it.
+///
+/// SAFETY: `init_lapic` must have succeeded, and the IPI must not break anything
+pub unsafe fn send_ipi(apic_id: u32, command: u32) {
+    write(REG_ICR_HIGH, apic_id << 24);
+    // writing the low half sends it
+    write(REG_ICR_LOW, command);
+    while read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// end of interrupt, for interrupts delivered by the local APIC in fixed mode
+pub fn eoi() {
+    unsafe { write(REG_EOI, 0) }
+}
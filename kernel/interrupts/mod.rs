@@ -1,5 +1,14 @@
+pub mod deferred;
 pub mod idt;
+pub mod ioapic;
+pub mod lapic;
+pub mod nmi;
 pub mod pic;
+pub mod stats;
+pub mod trap;
 
+pub use deferred::init_deferred;
 pub use idt::init_idt;
+pub use ioapic::init_ioapic;
+pub use lapic::init_lapic;
 pub use pic::{init_pic, PICS};
@@ -0,0 +1,175 @@
+//! NMIs and the hard-lockup watchdog.
+//!
+//! The watchdog programs the first performance counter to count unhalted cycles and raise an
+//! NMI when it overflows. Without performance counters, as under QEMU's TCG, the local APIC
+//! timer raises periodic NMIs instead: QEMU honors the delivery mode of its LVT entry, which
+//! is reserved on real hardware.
+//!
+//! On every NMI it checks that the timer ticked since the previous one, if it didn't for
+//! `WATCHDOG_THRESHOLD` NMIs in a row the CPU is stuck with interrupts disabled, so we print
+//! where it is. A CPU interrupted with interrupts enabled isn't stuck, its tick may just be
+//! stopped while idle.
+use core::arch::x86_64::__cpuid;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+use x86_64::registers::model_specific::Msr;
+use x86_64::registers::rflags::RFlags;
+
+use crate::devices::pit;
+use crate::interrupts::lapic::{
+    self, DELIVERY_NMI, LVT_MASKED, LVT_TIMER_PERIODIC, REG_LVT_PERF, REG_LVT_TIMER,
+    REG_TIMER_CURRENT, REG_TIMER_DIVIDE, REG_TIMER_INITIAL, TIMER_DIVIDE_16,
+};
+use crate::interrupts::trap::TrapFrame;
+use crate::kdb::{self, Console};
+use crate::{info, warn};
+
+const IA32_PMC0: u32 = 0xc1;
+const IA32_PERFEVTSEL0: u32 = 0x186;
+const IA32_PERF_GLOBAL_STATUS: u32 = 0x38e;
+const IA32_PERF_GLOBAL_CTRL: u32 = 0x38f;
+const IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+
+/// architectural event: UnHalted Core Cycles
+const EVENT_UNHALTED_CYCLES: u64 = 0x3c;
+const EVTSEL_USR: u64 = 1 << 16;
+const EVTSEL_OS: u64 = 1 << 17;
+const EVTSEL_INT: u64 = 1 << 20;
+const EVTSEL_EN: u64 = 1 << 22;
+
+/// cycles between NMIs: writes to the counters are sign extended from bit 31,
+/// so this is the longest period we can get, roughly a second on current CPUs.
+const WATCHDOG_PERIOD: u64 = (1 << 31) - 1;
+/// NMIs without any timer tick before we consider the CPU locked up
+pub const WATCHDOG_THRESHOLD: u64 = 10;
+/// period of the local APIC timer NMIs
+const TIMER_PERIOD_MS: u64 = 1000;
+
+/// what raises the watchdog's NMIs
+const SOURCE_NONE: u8 = 0;
+const SOURCE_PMU: u8 = 1;
+const SOURCE_LAPIC_TIMER: u8 = 2;
+
+static SOURCE: AtomicU8 = AtomicU8::new(SOURCE_NONE);
+/// version of the architectural performance monitoring
+static PMU_VERSION: AtomicU64 = AtomicU64::new(0);
+/// timer ticks seen by the last NMI
+static LAST_TICKS: AtomicU64 = AtomicU64::new(0);
+/// NMIs since the timer last ticked
+static STALLED: AtomicU64 = AtomicU64::new(0);
+
+pub fn init_watchdog() {
+    if !lapic::is_enabled() {
+        warn!("no local APIC, the watchdog is disabled");
+        return;
+    }
+    let eax = unsafe { __cpuid(0xa) }.eax;
+    let (version, counters) = (eax & 0xff, (eax >> 8) & 0xff);
+    if version == 0 || counters == 0 {
+        init_timer_watchdog();
+        return;
+    }
+    PMU_VERSION.store(version as u64, Ordering::Relaxed);
+    unsafe {
+        Msr::new(IA32_PERFEVTSEL0).write(0);
+        arm();
+        if version >= 2 {
+            let mut global_ctrl = Msr::new(IA32_PERF_GLOBAL_CTRL);
+            let ctrl = global_ctrl.read();
+            global_ctrl.write(ctrl | 1);
+        }
+        Msr::new(IA32_PERFEVTSEL0)
+            .write(EVENT_UNHALTED_CYCLES | EVTSEL_USR | EVTSEL_OS | EVTSEL_INT | EVTSEL_EN);
+    }
+    SOURCE.store(SOURCE_PMU, Ordering::Relaxed);
+    info!(
+        "NMI watchdog enabled: {} NMIs without a tick, every {} cycles",
+        WATCHDOG_THRESHOLD, WATCHDOG_PERIOD
+    );
+}
+
+/// Drive the watchdog from the local APIC timer, in periodic mode, when there are no
+/// performance counters.
+fn init_timer_watchdog() {
+    const CALIBRATION_MS: u64 = 10;
+    let per_ms = unsafe {
+        lapic::write(REG_LVT_TIMER, LVT_MASKED);
+        lapic::write(REG_TIMER_DIVIDE, TIMER_DIVIDE_16);
+        lapic::write(REG_TIMER_INITIAL, u32::MAX);
+        pit::busy_wait_us(CALIBRATION_MS * 1000);
+        let elapsed = u32::MAX - lapic::read(REG_TIMER_CURRENT);
+        lapic::write(REG_TIMER_INITIAL, 0);
+        elapsed as u64 / CALIBRATION_MS
+    };
+    if per_ms == 0 {
+        warn!("the local APIC timer doesn't run, the watchdog is disabled");
+        return;
+    }
+    let initial = (per_ms * TIMER_PERIOD_MS).min(u32::MAX as u64) as u32;
+    SOURCE.store(SOURCE_LAPIC_TIMER, Ordering::Relaxed);
+    unsafe {
+        lapic::write(REG_LVT_TIMER, DELIVERY_NMI | LVT_TIMER_PERIODIC);
+        lapic::write(REG_TIMER_INITIAL, initial);
+    }
+    info!(
+        "NMI watchdog enabled: {} NMIs without a tick, from the local APIC timer every {}ms",
+        WATCHDOG_THRESHOLD,
+        initial as u64 / per_ms
+    );
+}
+
+/// reload the counter and unmask the LVT entry (masked on delivery)
+unsafe fn arm() {
+    Msr::new(IA32_PMC0).write((WATCHDOG_PERIOD.wrapping_neg()) & 0xffff_ffff);
+    lapic::write(REG_LVT_PERF, DELIVERY_NMI);
+}
+
+/// whether the counter overflowed, i.e. the NMI is the watchdog's
+unsafe fn overflowed() -> bool {
+    if PMU_VERSION.load(Ordering::Relaxed) >= 2 {
+        let overflowed = Msr::new(IA32_PERF_GLOBAL_STATUS).read() & 1 != 0;
+        if overflowed {
+            Msr::new(IA32_PERF_GLOBAL_OVF_CTRL).write(1);
+        }
+        overflowed
+    } else {
+        // counting up from -PERIOD, so it's small again after an overflow
+        Msr::new(IA32_PMC0).read() & (1 << 31) == 0
+    }
+}
+
+pub fn nmi_handler(frame: &mut TrapFrame) {
+    // SERIAL1 might be what we're stuck on
+    let mut console = Console::new();
+    let source = SOURCE.load(Ordering::Relaxed);
+    let is_watchdog = match source {
+        SOURCE_PMU => unsafe { overflowed() },
+        // nothing tells its NMIs apart, the other ones are rare enough
+        SOURCE_LAPIC_TIMER => true,
+        _ => false,
+    };
+    if !is_watchdog {
+        let _ = writeln!(console, "NMI received @ {:#x}", frame.rip);
+        kdb::backtrace(&mut console, frame.rbp);
+        return;
+    }
+    let ticks = pit::ticks();
+    let interruptible = RFlags::from_bits_truncate(frame.rflags).contains(RFlags::INTERRUPT_FLAG);
+    if ticks != LAST_TICKS.swap(ticks, Ordering::Relaxed) || interruptible {
+        STALLED.store(0, Ordering::Relaxed);
+    } else if STALLED.fetch_add(1, Ordering::Relaxed) + 1 == WATCHDOG_THRESHOLD {
+        // only report once per lockup
+        let _ = writeln!(
+            console,
+            "WATCHDOG: hard lockup on CPU {}, no timer tick for {} NMIs",
+            crate::cpu::id(),
+            WATCHDOG_THRESHOLD
+        );
+        let _ = writeln!(console, "{:#x?}", frame);
+        kdb::backtrace(&mut console, frame.rbp);
+    }
+    if source == SOURCE_PMU {
+        unsafe { arm() };
+    }
+}
@@ -1,22 +1,36 @@
+use core::sync::atomic::{AtomicU64, Ordering};
 use core::u8;
 
-use spin::Mutex;
+use crate::locked::IrqLocked;
 
-pub static PICS: Mutex<CascadePic> =
-    Mutex::new(unsafe { CascadePic::new(MASTER_PIC_OFFSET, SLAVE_PIC_OFFSET) });
+pub static PICS: IrqLocked<CascadePic> =
+    IrqLocked::new(unsafe { CascadePic::new(MASTER_PIC_OFFSET, SLAVE_PIC_OFFSET) });
 
 pub fn init_pic() {
     info!("initializing Cascading PIC");
-    unsafe { PICS.lock().initialize() };
+    let mut pics = PICS.lock();
+    unsafe {
+        pics.initialize();
+        // masked by the BIOS
+        pics.unmask(IRQ::Com1.line());
+    }
 }
 pub const MASTER_PIC_OFFSET: u8 = 32;
 pub const SLAVE_PIC_OFFSET: u8 = MASTER_PIC_OFFSET + 8;
+/// line of the master the slave is wired to
+pub const CASCADE_LINE: u8 = 2;
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum IRQ {
     Timer = MASTER_PIC_OFFSET,
     Keyboard,
+    Com1 = MASTER_PIC_OFFSET + 4,
+    /// lowest priority line of the master, also raised for its spurious interrupts
+    Lpt1 = MASTER_PIC_OFFSET + 7,
+    Rtc = SLAVE_PIC_OFFSET,
+    /// lowest priority line of the slave, also raised for its spurious interrupts
+    SecondaryAta = SLAVE_PIC_OFFSET + 7,
 }
 
 impl IRQ {
@@ -28,6 +42,22 @@ impl IRQ {
     pub fn as_usize(self) -> usize {
         self as usize
     }
+    /// the line (0-15) on the cascaded PICs
+    #[inline]
+    pub fn line(self) -> u8 {
+        self.as_u8() - MASTER_PIC_OFFSET
+    }
+}
+
+/// spurious interrupts received on the master (IRQ7) and the slave (IRQ15)
+static SPURIOUS_IRQS: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+
+/// Returns the number of spurious interrupts of the master and the slave.
+pub fn spurious_count() -> [u64; 2] {
+    [
+        SPURIOUS_IRQS[0].load(Ordering::Relaxed),
+        SPURIOUS_IRQS[1].load(Ordering::Relaxed),
+    ]
 }
 
 use x86_64::instructions::port::Port;
@@ -36,7 +66,13 @@ use crate::info;
 
 const CMD_INIT: u8 = 0x11;
 const CMD_EOI: u8 = 0x20;
+/// OCW3: next read of the command port returns the Interrupt Request Register
+const CMD_READ_IRR: u8 = 0x0a;
+/// OCW3: next read of the command port returns the In-Service Register
+const CMD_READ_ISR: u8 = 0x0b;
 const MODE_86: u8 = 0x01;
+/// spurious interrupts are always reported on the lowest priority line
+const SPURIOUS_LINE: u8 = 7;
 pub struct CascadePic {
     master: Pic,
     slave: Pic,
@@ -99,11 +135,66 @@ impl CascadePic {
     pub fn handles_interrupt(&self, interrupt_id: u8) -> bool {
         self.master.handles_interrupt(interrupt_id) || self.slave.handles_interrupt(interrupt_id)
     }
+    /// Returns the In-Service Registers, slave in the high byte.
+    pub unsafe fn read_isr(&mut self) -> u16 {
+        (self.slave.read_isr() as u16) << 8 | self.master.read_isr() as u16
+    }
+    /// Returns the Interrupt Request Registers, slave in the high byte.
+    pub unsafe fn read_irr(&mut self) -> u16 {
+        (self.slave.read_irr() as u16) << 8 | self.master.read_irr() as u16
+    }
+    /// IRQ7 and IRQ15 are spurious if the line isn't actually in service.
+    pub unsafe fn is_spurious(&mut self, interrupt_id: u8) -> bool {
+        if self.master.handles_interrupt(interrupt_id) {
+            self.master.is_spurious(interrupt_id)
+        } else if self.slave.handles_interrupt(interrupt_id) {
+            self.slave.is_spurious(interrupt_id)
+        } else {
+            false
+        }
+    }
+    /// send EOI for `interrupt_id`, taking care of spurious interrupts:
+    /// - a spurious IRQ7 must not be acknowledged at all.
+    /// - a spurious IRQ15 is only acknowledged on the master, which did see it from the slave.
     pub unsafe fn notify_eoi(&mut self, interrupt_id: u8) {
         if self.master.handles_interrupt(interrupt_id) {
-            self.master.eoi();
+            if self.master.is_spurious(interrupt_id) {
+                SPURIOUS_IRQS[0].fetch_add(1, Ordering::Relaxed);
+            } else {
+                self.master.eoi();
+            }
         } else if self.slave.handles_interrupt(interrupt_id) {
-            self.slave.eoi();
+            if self.slave.is_spurious(interrupt_id) {
+                SPURIOUS_IRQS[1].fetch_add(1, Ordering::Relaxed);
+            } else {
+                self.slave.eoi();
+            }
+            // the slave is chained on the master
+            self.master.eoi();
+        }
+    }
+    /// disable a single line (0-15)
+    pub unsafe fn mask(&mut self, line: u8) {
+        let (pic, bit) = self.line(line);
+        let mask = pic.read_mask();
+        pic.write_mask(mask | 1 << bit);
+    }
+    /// enable a single line (0-15)
+    pub unsafe fn unmask(&mut self, line: u8) {
+        let (pic, bit) = self.line(line);
+        let mask = pic.read_mask();
+        pic.write_mask(mask & !(1 << bit));
+    }
+    pub unsafe fn is_masked(&mut self, line: u8) -> bool {
+        let (pic, bit) = self.line(line);
+        pic.read_mask() & (1 << bit) != 0
+    }
+    fn line(&mut self, line: u8) -> (&mut Pic, u8) {
+        assert!(line < 16, "invalid PIC line: {}", line);
+        if line < 8 {
+            (&mut self.master, line)
+        } else {
+            (&mut self.slave, line - 8)
         }
     }
     pub unsafe fn disable(&mut self) {
@@ -129,7 +220,33 @@ impl Pic {
     unsafe fn write_mask(&mut self, mask: u8) {
         self.data.write(mask)
     }
+    unsafe fn read_irr(&mut self) -> u8 {
+        self.command.write(CMD_READ_IRR);
+        self.command.read()
+    }
+    unsafe fn read_isr(&mut self) -> u8 {
+        self.command.write(CMD_READ_ISR);
+        self.command.read()
+    }
+    unsafe fn is_spurious(&mut self, interrupt_id: u8) -> bool {
+        interrupt_id == self.offset + SPURIOUS_LINE && self.read_isr() & (1 << SPURIOUS_LINE) == 0
+    }
     fn handles_interrupt(&self, interrupt_id: u8) -> bool {
         self.offset <= interrupt_id && interrupt_id < self.offset + 8
     }
 }
+
+#[test_case]
+fn test_mask_unmask() {
+    let mut pics = PICS.lock();
+    unsafe {
+        let saved = pics.read_masks();
+        for line in [3, 12] {
+            pics.mask(line);
+            assert!(pics.is_masked(line));
+            pics.unmask(line);
+            assert!(!pics.is_masked(line));
+        }
+        pics.write_masks(saved[0], saved[1]);
+    }
+}
@@ -0,0 +1,188 @@
+//! per-CPU, per-vector interrupt counters and handler durations (in TSC cycles)
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::cpu::{self, MAX_CPUS};
+use crate::devices::hpet;
+use crate::interrupts::pic::{self, IRQ};
+use crate::println;
+
+pub const NUM_VECTORS: usize = 256;
+
+struct VectorStats {
+    count: AtomicU64,
+    /// sum of all durations, for the average
+    total: AtomicU64,
+    min: AtomicU64,
+    max: AtomicU64,
+}
+impl VectorStats {
+    const NEW: VectorStats = VectorStats {
+        count: AtomicU64::new(0),
+        total: AtomicU64::new(0),
+        min: AtomicU64::new(u64::MAX),
+        max: AtomicU64::new(0),
+    };
+}
+
+const CPU_STATS: [VectorStats; NUM_VECTORS] = [VectorStats::NEW; NUM_VECTORS];
+static STATS: [[VectorStats; NUM_VECTORS]; MAX_CPUS] = [CPU_STATS; MAX_CPUS];
+
+/// Measures a handler from its creation until it's dropped.
+pub struct Measure {
+    vector: u8,
+    start: u64,
+}
+impl Drop for Measure {
+    fn drop(&mut self) {
+        record(self.vector, cpu::rdtsc().wrapping_sub(self.start));
+    }
+}
+
+/// start measuring a handler for `vector` on the executing CPU
+#[inline]
+pub fn measure(vector: u8) -> Measure {
+    Measure {
+        vector,
+        start: cpu::rdtsc(),
+    }
+}
+
+pub fn record(vector: u8, cycles: u64) {
+    let stats = &STATS[cpu::id()][vector as usize];
+    stats.count.fetch_add(1, Ordering::Relaxed);
+    stats.total.fetch_add(cycles, Ordering::Relaxed);
+    stats.min.fetch_min(cycles, Ordering::Relaxed);
+    stats.max.fetch_max(cycles, Ordering::Relaxed);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Snapshot {
+    pub count: u64,
+    pub min: u64,
+    pub max: u64,
+    pub avg: u64,
+}
+impl Snapshot {
+    fn merge(self, other: Snapshot) -> Snapshot {
+        if self.count == 0 {
+            return other;
+        } else if other.count == 0 {
+            return self;
+        }
+        let count = self.count + other.count;
+        Snapshot {
+            count,
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+            avg: (self.avg * self.count + other.avg * other.count) / count,
+        }
+    }
+}
+
+/// Returns the statistics of `vector` on `cpu`.
+pub fn snapshot(cpu: usize, vector: u8) -> Snapshot {
+    let stats = &STATS[cpu][vector as usize];
+    let count = stats.count.load(Ordering::Relaxed);
+    if count == 0 {
+        return Snapshot::default();
+    }
+    Snapshot {
+        count,
+        min: stats.min.load(Ordering::Relaxed),
+        max: stats.max.load(Ordering::Relaxed),
+        avg: stats.total.load(Ordering::Relaxed) / count,
+    }
+}
+
+/// print a table of all vectors that fired at least once, like `/proc/interrupts`
+pub fn dump() {
+    let cpus = (0..MAX_CPUS)
+        .filter(|&cpu| (0..NUM_VECTORS).any(|v| snapshot(cpu, v as u8).count > 0))
+        .fold(0u64, |set, cpu| set | 1 << cpu);
+
+    print_header(cpus);
+    for vector in 0..NUM_VECTORS {
+        let vector = vector as u8;
+        let mut total = Snapshot::default();
+        for cpu in 0..MAX_CPUS {
+            total = total.merge(snapshot(cpu, vector));
+        }
+        if total.count == 0 {
+            continue;
+        }
+        crate::print!("{:>4}:", vector);
+        for cpu in (0..MAX_CPUS).filter(|cpu| cpus & 1 << cpu != 0) {
+            crate::print!(" {:>10}", snapshot(cpu, vector).count);
+        }
+        println!(
+            " {:>10} {:>10} {:>10}  {}",
+            total.min,
+            total.max,
+            total.avg,
+            vector_name(vector)
+        );
+    }
+    let [master, slave] = pic::spurious_count();
+    println!(" SPU: {:>10}  Spurious interrupts", master + slave);
+    let (periods, skipped) = crate::devices::pit::idle_stats();
+    println!(
+        " IDL: {:>10}  Tickless idle periods, {} ticks skipped",
+        periods, skipped
+    );
+}
+
+fn print_header(cpus: u64) {
+    crate::print!("     ");
+    for cpu in (0..MAX_CPUS).filter(|cpu| cpus & 1 << cpu != 0) {
+        crate::print!(" {:>10}", format_args!("CPU{}", cpu));
+    }
+    println!(" {:>10} {:>10} {:>10}", "min(cyc)", "max(cyc)", "avg(cyc)");
+}
+
+pub fn vector_name(vector: u8) -> &'static str {
+    const EXCEPTIONS: [&str; 21] = [
+        "Divide error",
+        "Debug",
+        "Non-maskable interrupt",
+        "Breakpoint",
+        "Overflow",
+        "Bound range exceeded",
+        "Invalid opcode",
+        "Device not available",
+        "Double fault",
+        "Coprocessor segment overrun",
+        "Invalid TSS",
+        "Segment not present",
+        "Stack-segment fault",
+        "General protection fault",
+        "Page fault",
+        "Reserved",
+        "x87 floating-point exception",
+        "Alignment check",
+        "Machine check",
+        "SIMD floating-point exception",
+        "Virtualization exception",
+    ];
+    match vector {
+        v if (v as usize) < EXCEPTIONS.len() => EXCEPTIONS[v as usize],
+        v if v == IRQ::Timer.as_u8() => "IRQ0 Timer",
+        v if v == IRQ::Keyboard.as_u8() => "IRQ1 Keyboard",
+        v if v == IRQ::Com1.as_u8() => "IRQ4 COM1",
+        v if v == IRQ::Lpt1.as_u8() => "IRQ7 LPT1",
+        v if v == IRQ::Rtc.as_u8() => "IRQ8 RTC",
+        v if v == IRQ::SecondaryAta.as_u8() => "IRQ15 Secondary ATA",
+        v if v == crate::interrupts::lapic::SPURIOUS_VECTOR => "LAPIC spurious",
+        v if v == crate::syscall::SYSCALL_VECTOR => "int 0x80 syscall",
+        v if (hpet::VECTOR_BASE..hpet::VECTOR_BASE + hpet::MAX_COMPARATORS as u8).contains(&v) => {
+            "HPET"
+        }
+        _ => "",
+    }
+}
+
+#[test_case]
+fn test_count_breakpoint() {
+    let before = snapshot(cpu::id(), 3).count;
+    x86_64::instructions::interrupts::int3();
+    assert_eq!(snapshot(cpu::id(), 3).count, before + 1);
+}
@@ -0,0 +1,253 @@
+//! interrupt entry trampolines.
+//!
+//! Every vector has a small stub pushing its number (and a dummy error code when the CPU
+//! doesn't push one), then `trap_common` saves all the general purpose registers and calls
+//! `trap_dispatch` with the resulting `TrapFrame`. On return the registers are restored from the
+//! frame, the vector and error code popped, then `iretq`: any change made to the frame by a
+//! handler is visible to the interrupted code. Before returning to user mode, the pending
+//! signals of the thread's process are taken. GS is swapped on the way in and out of user mode.
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use x86_64::VirtAddr;
+
+use crate::devices::pit;
+use crate::interrupts::pic::{IRQ, MASTER_PIC_OFFSET, SLAVE_PIC_OFFSET};
+use crate::interrupts::{deferred, stats, PICS};
+use crate::process::signal;
+use crate::thread;
+use crate::user;
+
+pub const NUM_VECTORS: usize = 256;
+/// size of every stub, so the stub of a vector is at `trap_stubs + vector * STUB_SIZE`
+const STUB_SIZE: u64 = 16;
+
+/// The registers saved on the stack by the trampolines, lowest address first.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    /// pushed by the CPU for some exceptions, 0 otherwise.
+    pub error_code: u64,
+    // pushed by the CPU
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl fmt::Debug for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        macro_rules! hex_fields {
+            ($dbg:expr, $($field:ident)*) => {
+                $dbg$(.field(stringify!($field), &format_args!("{:#x}", self.$field)))*
+            };
+        }
+        hex_fields!(
+            f.debug_struct("TrapFrame"),
+            vector error_code rip cs rflags rsp ss
+            rax rbx rcx rdx rsi rdi rbp r8 r9 r10 r11 r12 r13 r14 r15
+        )
+        .finish()
+    }
+}
+
+pub type TrapHandler = fn(&mut TrapFrame);
+
+/// handlers indexed by vector, 0 if there's none.
+static HANDLERS: [AtomicUsize; NUM_VECTORS] = {
+    const NONE: AtomicUsize = AtomicUsize::new(0);
+    [NONE; NUM_VECTORS]
+};
+
+/// set (or replace) the handler of `vector`.
+pub fn register(vector: u8, handler: TrapHandler) {
+    HANDLERS[vector as usize].store(handler as usize, Ordering::Release);
+}
+pub fn unregister(vector: u8) {
+    HANDLERS[vector as usize].store(0, Ordering::Release);
+}
+fn handler(vector: u8) -> Option<TrapHandler> {
+    match HANDLERS[vector as usize].load(Ordering::Acquire) {
+        0 => None,
+        // SAFETY: only `register` stores non-zero values, which are `TrapHandler`s
+        addr => Some(unsafe { core::mem::transmute::<usize, TrapHandler>(addr) }),
+    }
+}
+
+/// address of the entry stub of `vector`
+pub fn stub_addr(vector: u8) -> VirtAddr {
+    extern "C" {
+        static trap_stubs: u8;
+    }
+    let start = unsafe { &trap_stubs as *const u8 as u64 };
+    VirtAddr::new(start + vector as u64 * STUB_SIZE)
+}
+
+#[no_mangle]
+extern "C" fn trap_dispatch(frame: &mut TrapFrame) {
+    let vector = frame.vector as u8;
+    let is_irq = (MASTER_PIC_OFFSET..SLAVE_PIC_OFFSET + 8).contains(&vector);
+    if vector >= MASTER_PIC_OFFSET && vector != IRQ::Timer.as_u8() {
+        // the idle loop might have stopped the tick, catch up before anything uses it.
+        // The timer handler does it itself, as it must not count its own tick.
+        pit::exit_idle();
+    }
+    // user faults raise a signal instead
+    if !user::check_fault(frame) {
+        let _measure = stats::measure(vector);
+        match handler(vector) {
+            Some(handler) => handler(frame),
+            None if is_irq => {}
+            None => panic!(
+                "EXCEPTION: unhandled {} (#{})\n{:#?}\n",
+                stats::vector_name(vector),
+                vector,
+                frame
+            ),
+        }
+        if is_irq {
+            unsafe { PICS.lock().notify_eoi(vector) };
+        }
+    }
+    if is_irq {
+        deferred::on_irq_exit(frame);
+        thread::preempt::on_irq_exit(frame);
+    }
+    signal::deliver(frame);
+}
+
+/// expands to the entry stub of every vector passed, in order.
+macro_rules! trap_stubs {
+    ($($vector:literal)*) => {
+        concat!($(
+            ".p2align 4\n",
+            // exceptions pushing an error code: #DF, #TS, #NP, #SS, #GP, #PF, #AC, #CP, #VC, #SX
+            ".if ", $vector, " != 8 && (", $vector, " < 10 || ", $vector, " > 14) && ",
+            $vector, " != 17 && ", $vector, " != 21 && ", $vector, " != 29 && ", $vector, " != 30\n",
+            "    push 0\n",
+            ".endif\n",
+            "    push ", $vector, "\n",
+            "    jmp trap_common\n",
+        )*)
+    };
+}
+
+global_asm!(concat!(
+    ".pushsection .text\n",
+    ".p2align 4\n",
+    ".global trap_stubs\n",
+    "trap_stubs:\n",
+    trap_stubs!(
+    0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15
+    16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31
+    32 33 34 35 36 37 38 39 40 41 42 43 44 45 46 47
+    48 49 50 51 52 53 54 55 56 57 58 59 60 61 62 63
+    64 65 66 67 68 69 70 71 72 73 74 75 76 77 78 79
+    80 81 82 83 84 85 86 87 88 89 90 91 92 93 94 95
+    96 97 98 99 100 101 102 103 104 105 106 107 108 109 110 111
+    112 113 114 115 116 117 118 119 120 121 122 123 124 125 126 127
+    128 129 130 131 132 133 134 135 136 137 138 139 140 141 142 143
+    144 145 146 147 148 149 150 151 152 153 154 155 156 157 158 159
+    160 161 162 163 164 165 166 167 168 169 170 171 172 173 174 175
+    176 177 178 179 180 181 182 183 184 185 186 187 188 189 190 191
+    192 193 194 195 196 197 198 199 200 201 202 203 204 205 206 207
+    208 209 210 211 212 213 214 215 216 217 218 219 220 221 222 223
+    224 225 226 227 228 229 230 231 232 233 234 235 236 237 238 239
+    240 241 242 243 244 245 246 247 248 249 250 251 252 253 254 255
+    ),
+    "
+trap_common:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    // GS is the CPU's block in the kernel, see `percpu`: swap it in when coming from user mode,
+    // or from the few kernel instructions around a `swapgs`, which only NMIs can interrupt.
+    // RBX remembers it, `trap_dispatch` preserves it.
+    xor ebx, ebx
+    test byte ptr [rsp + 144], 3
+    jnz 1f
+    mov ecx, 0xc0000101
+    rdmsr
+    or eax, edx
+    jnz 2f
+1:
+    swapgs
+    mov ebx, 1
+2:
+    mov rdi, rsp
+    cld
+    call trap_dispatch
+    // `trap_return` swaps back before user mode, not before the kernel
+    test ebx, ebx
+    jz trap_return
+    test byte ptr [rsp + 144], 3
+    jnz trap_return
+    swapgs
+.global trap_return
+trap_return:
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    add rsp, 16
+    test byte ptr [rsp + 8], 3
+    jz 3f
+    swapgs
+3:
+    iretq
+",
+    ".popsection\n",
+));
+
+#[test_case]
+fn test_modify_frame() {
+    const TEST_VECTOR: u8 = 0xf0;
+    fn handler(frame: &mut TrapFrame) {
+        frame.rax += frame.vector;
+    }
+    register(TEST_VECTOR, handler);
+    let rax: u64;
+    unsafe { asm!("int 0xf0", inout("rax") 1u64 => rax) };
+    unregister(TEST_VECTOR);
+    assert_eq!(rax, 1 + TEST_VECTOR as u64);
+}
@@ -0,0 +1,339 @@
+//! interactive kernel debugger on COM1.
+//!
+//! Entered on `int3`, or with `Ctrl-]` on the serial line or F12 on the keyboard.
+//! It polls the serial port directly, without taking `SERIAL1`'s lock, since the code
+//! we stopped might be holding it.
+pub mod probe;
+
+use core::convert::TryFrom;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use uart_16550::SerialPort;
+use x86_64::registers::control::Cr3;
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::devices::serial::SERIAL_PORT_ID;
+use crate::interrupts::trap::TrapFrame;
+use crate::vmem;
+
+/// serial byte breaking into the debugger: Ctrl-]
+pub const BREAK_CHAR: u8 = 0x1d;
+/// keyboard scancode (set 1) breaking into the debugger: F12 pressed
+pub const HOTKEY_SCANCODE: u8 = 0x58;
+
+/// tests can't answer the prompt, so `int3` must not stop them.
+static ENABLED: AtomicBool = AtomicBool::new(!cfg!(test));
+/// set when we resumed with the trap flag, so the next #DB is ours
+static STEPPING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    Breakpoint,
+    Step,
+    Hotkey,
+}
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Run the debugger until the user continues or steps, then return to the trapped code.
+pub fn enter(frame: &mut TrapFrame, reason: Reason) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut console = Console::new();
+    let _ = writeln!(console, "\nkdb: {:?} @ {:#x}", reason, frame.rip);
+    let mut buf = [0u8; 80];
+    loop {
+        let _ = write!(console, "kdb> ");
+        let line = console.read_line(&mut buf);
+        match command(&mut console, frame, line) {
+            Ok(Some(Resume::Continue)) => {
+                frame.rflags &= !RFlags::TRAP_FLAG.bits();
+                return;
+            }
+            Ok(Some(Resume::Step)) => {
+                frame.rflags |= RFlags::TRAP_FLAG.bits();
+                STEPPING.store(true, Ordering::Relaxed);
+                return;
+            }
+            Ok(None) => {}
+            Err(err) => {
+                let _ = writeln!(console, "error: {}", err);
+            }
+        }
+    }
+}
+
+/// #DB handler: re-enters the debugger after a single step.
+pub fn debug_trap(frame: &mut TrapFrame) {
+    if STEPPING.swap(false, Ordering::Relaxed) {
+        frame.rflags &= !RFlags::TRAP_FLAG.bits();
+        enter(frame, Reason::Step);
+    }
+}
+
+enum Resume {
+    Continue,
+    Step,
+}
+
+const HELP: &str = "\
+help                 this message
+regs                 show the trap frame
+set <reg> <value>    change a register of the trap frame
+m <addr> [len]       hex dump memory
+w <addr> <byte>...   write bytes to memory
+i [addr] [len]       hex dump instructions, at rip by default
+bt                   backtrace, following rbp
+pt <addr>            walk the page tables for a virtual address
+s                    single step
+c                    continue";
+
+fn command(
+    console: &mut Console,
+    frame: &mut TrapFrame,
+    line: &str,
+) -> Result<Option<Resume>, &'static str> {
+    let mut args = line.split_whitespace();
+    let cmd = match args.next() {
+        Some(cmd) => cmd,
+        None => return Ok(None),
+    };
+    match cmd {
+        "help" | "h" | "?" => {
+            let _ = writeln!(console, "{}", HELP);
+        }
+        "regs" | "r" => {
+            let _ = writeln!(console, "{:#x?}", frame);
+        }
+        "set" => {
+            let reg = args.next().ok_or("missing register")?;
+            let value = parse(args.next())?;
+            *register(frame, reg).ok_or("unknown register")? = value;
+        }
+        "m" => {
+            let addr = virt(parse(args.next())?)?;
+            let len = args.next().map_or(Ok(64), |len| parse(Some(len)))?;
+            hex_dump(console, addr, len);
+        }
+        "w" => {
+            let mut addr = virt(parse(args.next())?)?;
+            for byte in args {
+                let byte = parse(Some(byte))?;
+                let byte = u8::try_from(byte).map_err(|_| "not a byte")?;
+                unsafe { probe::write_u8(addr, byte) }.map_err(|_| "write faulted")?;
+                addr += 1u64;
+            }
+        }
+        "i" => {
+            let addr = args
+                .next()
+                .map_or(Ok(frame.rip), |addr| parse(Some(addr)))?;
+            let len = args.next().map_or(Ok(16), |len| parse(Some(len)))?;
+            hex_dump(console, virt(addr)?, len);
+        }
+        "bt" => backtrace(console, frame.rbp),
+        "pt" => walk_page_tables(console, virt(parse(args.next())?)?),
+        "s" | "step" => return Ok(Some(Resume::Step)),
+        "c" | "continue" => return Ok(Some(Resume::Continue)),
+        _ => return Err("unknown command, try `help`"),
+    }
+    Ok(None)
+}
+
+fn register<'a>(frame: &'a mut TrapFrame, name: &str) -> Option<&'a mut u64> {
+    Some(match name {
+        "rax" => &mut frame.rax,
+        "rbx" => &mut frame.rbx,
+        "rcx" => &mut frame.rcx,
+        "rdx" => &mut frame.rdx,
+        "rsi" => &mut frame.rsi,
+        "rdi" => &mut frame.rdi,
+        "rbp" => &mut frame.rbp,
+        "r8" => &mut frame.r8,
+        "r9" => &mut frame.r9,
+        "r10" => &mut frame.r10,
+        "r11" => &mut frame.r11,
+        "r12" => &mut frame.r12,
+        "r13" => &mut frame.r13,
+        "r14" => &mut frame.r14,
+        "r15" => &mut frame.r15,
+        "rip" => &mut frame.rip,
+        "rflags" => &mut frame.rflags,
+        "rsp" => &mut frame.rsp,
+        _ => return None,
+    })
+}
+
+/// parse a hexadecimal number, with or without `0x`
+fn parse(arg: Option<&str>) -> Result<u64, &'static str> {
+    let arg = arg.ok_or("missing argument")?;
+    let digits = arg.strip_prefix("0x").unwrap_or(arg);
+    u64::from_str_radix(digits, 16).map_err(|_| "not an hexadecimal number")
+}
+
+fn virt(addr: u64) -> Result<VirtAddr, &'static str> {
+    VirtAddr::try_new(addr).map_err(|_| "non canonical address")
+}
+
+/// the byte at `addr`, `None` if it isn't canonical or mapped
+fn read_byte(addr: u64) -> Option<u8> {
+    probe::read_u8(VirtAddr::try_new(addr).ok()?).ok()
+}
+
+fn hex_dump(console: &mut Console, addr: VirtAddr, len: u64) {
+    const WIDTH: u64 = 16;
+    let start = addr.as_u64();
+    // stops at the top of the address space
+    let end = start.saturating_add(len);
+    let mut line = start;
+    while line < end {
+        let _ = write!(console, "{:016x}: ", line);
+        let mut ascii = [b' '; WIDTH as usize];
+        for i in 0..WIDTH.min(end - line) {
+            match read_byte(line + i) {
+                Some(byte) => {
+                    let _ = write!(console, "{:02x} ", byte);
+                    ascii[i as usize] = if byte.is_ascii_graphic() { byte } else { b'.' };
+                }
+                None => {
+                    let _ = write!(console, "?? ");
+                }
+            }
+        }
+        let _ = writeln!(
+            console,
+            " |{}|",
+            core::str::from_utf8(&ascii).unwrap_or_default()
+        );
+        line = match line.checked_add(WIDTH) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+}
+
+/// print the return addresses of the frames chained from `rbp`
+pub fn backtrace(out: &mut impl Write, mut rbp: u64) {
+    const MAX_DEPTH: usize = 32;
+    let _ = writeln!(out, "backtrace:");
+    for depth in 0..MAX_DEPTH {
+        let frame = match VirtAddr::try_new(rbp) {
+            Ok(frame) if rbp != 0 => frame,
+            _ => return,
+        };
+        // [rbp] is the caller's rbp, [rbp + 8] our return address
+        let (next, ret) = match (probe::read_u64(frame), probe::read_u64(frame + 8u64)) {
+            (Ok(next), Ok(ret)) => (next, ret),
+            _ => {
+                let _ = writeln!(out, "  #{:<2} <unreadable frame @ {:#x}>", depth, rbp);
+                return;
+            }
+        };
+        let _ = writeln!(out, "  #{:<2} {:#x}", depth, ret);
+        rbp = next;
+    }
+}
+
+fn walk_page_tables(console: &mut Console, addr: VirtAddr) {
+    const ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+    let mut table = Cr3::read().0.start_address();
+    let indices = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+    for (level, index) in (1..=4u8).rev().zip(indices.iter()) {
+        let index = u16::from(*index);
+        let entry_addr = vmem::phys_to_virt(table + index as u64 * 8);
+        let entry = match probe::read_u64(entry_addr) {
+            Ok(entry) => entry,
+            Err(_) => {
+                let _ = writeln!(console, "P{} @ {:#x}: unreadable", level, entry_addr);
+                return;
+            }
+        };
+        let flags = PageTableFlags::from_bits_truncate(entry);
+        let _ = writeln!(
+            console,
+            "P{}[{:3}] = {:#018x} {:?}",
+            level, index, entry, flags
+        );
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return;
+        }
+        let frame = PhysAddr::new(entry & ADDR_MASK);
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            let page_size = 1u64 << (12 + 9 * (level as u64 - 1));
+            let _ = writeln!(
+                console,
+                "=> {:#x}",
+                frame + (addr.as_u64() & (page_size - 1))
+            );
+            return;
+        }
+        table = frame;
+    }
+}
+
+/// the serial port, used without any lock
+pub struct Console(SerialPort);
+
+impl Console {
+    pub fn new() -> Console {
+        // already initialized by `SERIAL1`
+        Console(unsafe { SerialPort::new(SERIAL_PORT_ID) })
+    }
+    fn read_line<'a>(&mut self, buf: &'a mut [u8]) -> &'a str {
+        let mut len = 0;
+        loop {
+            match self.0.receive() {
+                b'\r' | b'\n' => break,
+                // backspace and DEL
+                0x08 | 0x7f if len > 0 => {
+                    len -= 1;
+                    self.0.send(0x08);
+                }
+                byte if (0x20..0x7f).contains(&byte) && len < buf.len() => {
+                    buf[len] = byte;
+                    len += 1;
+                    self.0.send(byte);
+                }
+                _ => {}
+            }
+        }
+        self.0.send(b'\n');
+        // only printable ascii was kept
+        core::str::from_utf8(&buf[..len]).unwrap_or_default()
+    }
+}
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write_str(s)
+    }
+}
+
+#[test_case]
+fn test_hex_dump_top_of_memory() {
+    // runs into the end of the address space without overflowing
+    hex_dump(
+        &mut Console::new(),
+        VirtAddr::new(0xffff_ffff_ffff_ffc0),
+        0x100,
+    );
+}
+
+#[test_case]
+fn test_read_byte_non_canonical() {
+    static BYTE: u8 = 0x42;
+    assert_eq!(read_byte(&BYTE as *const u8 as u64), Some(0x42));
+    // not read from 0xffff_8000_0000_0000
+    assert_eq!(read_byte(0x0000_8000_0000_0000), None);
+}
@@ -0,0 +1,98 @@
+//! memory accesses that recover from faults instead of panicking.
+//!
+//! The accesses are done by a single instruction whose address is known: if it faults,
+//! `fixup` makes the handler resume at `probe_fault`, which returns an error.
+use x86_64::VirtAddr;
+
+use crate::interrupts::trap::TrapFrame;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault;
+
+global_asm!(
+    "
+.pushsection .text
+.global probe_read_u8
+.global probe_read_u8_insn
+.global probe_write_u8
+.global probe_write_u8_insn
+.global probe_fault
+probe_read_u8:
+    xor eax, eax
+probe_read_u8_insn:
+    mov al, byte ptr [rdi]
+    ret
+probe_write_u8:
+    xor eax, eax
+probe_write_u8_insn:
+    mov byte ptr [rdi], sil
+    ret
+probe_fault:
+    mov rax, -1
+    ret
+.popsection
+"
+);
+
+extern "C" {
+    /// Returns the byte, or `u64::MAX` if the read faulted
+    fn probe_read_u8(addr: u64) -> u64;
+    /// Returns 0, or `u64::MAX` if the write faulted
+    fn probe_write_u8(addr: u64, value: u8) -> u64;
+    static probe_read_u8_insn: u8;
+    static probe_write_u8_insn: u8;
+    static probe_fault: u8;
+}
+
+pub fn read_u8(addr: VirtAddr) -> Result<u8, Fault> {
+    match unsafe { probe_read_u8(addr.as_u64()) } {
+        u64::MAX => Err(Fault),
+        byte => Ok(byte as u8),
+    }
+}
+
+pub fn read_u64(addr: VirtAddr) -> Result<u64, Fault> {
+    let mut bytes = [0; 8];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = read_u8(addr + i)?;
+    }
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// SAFETY: the write mustn't break any invariant of the memory written to
+pub unsafe fn write_u8(addr: VirtAddr, value: u8) -> Result<(), Fault> {
+    match probe_write_u8(addr.as_u64(), value) {
+        0 => Ok(()),
+        _ => Err(Fault),
+    }
+}
+
+/// If the fault happened while probing, make the trap return to the error path.
+///
+/// To be called by the page fault and general protection fault handlers.
+pub fn fixup(frame: &mut TrapFrame) -> bool {
+    let (read, write, fault) = unsafe {
+        (
+            &probe_read_u8_insn as *const u8 as u64,
+            &probe_write_u8_insn as *const u8 as u64,
+            &probe_fault as *const u8 as u64,
+        )
+    };
+    if frame.rip == read || frame.rip == write {
+        frame.rip = fault;
+        true
+    } else {
+        false
+    }
+}
+
+#[test_case]
+fn test_probe_unmapped() {
+    // the first page is never mapped
+    assert_eq!(read_u8(VirtAddr::new(0x10)), Err(Fault));
+    // non canonical: #GP instead of #PF
+    let non_canonical = unsafe { VirtAddr::new_unsafe(0x0000_8000_0000_0000) };
+    assert_eq!(read_u8(non_canonical), Err(Fault));
+    let value: u8 = 42;
+    assert_eq!(read_u8(VirtAddr::from_ptr(&value)), Ok(42));
+}
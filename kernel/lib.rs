@@ -2,9 +2,9 @@
 #![cfg_attr(test, no_main)]
 #![feature(
     custom_test_frameworks,
-    abi_x86_interrupt,
     format_args_nl,
     asm,
+    global_asm,
     allocator_api,
     nonnull_slice_from_raw_parts
 )]
@@ -13,12 +13,24 @@
 #![cfg_attr(test, feature(default_alloc_error_handler))]
 
 extern crate alloc;
+pub mod acpi;
+pub mod cpu;
 pub mod debug;
 pub mod devices;
 pub mod gdt;
 pub mod heap;
 pub mod interrupts;
+pub mod kdb;
 pub mod locked;
+pub mod percpu;
+pub mod process;
+pub mod smp;
+pub mod sync;
+pub mod syscall;
+pub mod task;
+pub mod thread;
+pub mod time;
+pub mod user;
 pub mod vmem;
 
 use alloc::boxed::Box;
@@ -46,9 +58,13 @@ pub fn debug_regions(memory_map: &'static MemoryMap) {
 
 pub fn init(boot_info: &'static BootInfo) {
     use heap::BootstrapFramesAlloc;
+    percpu::init_percpu();
     gdt::Gdt::init();
     interrupts::init_idt();
     interrupts::init_pic();
+    interrupts::init_deferred();
+    devices::pit::init_pit(devices::pit::TICK_HZ);
+    devices::rtc::init_rtc();
     info!("enabling IRQ");
     x86_64::instructions::interrupts::enable();
     info!("CPU init done.");
@@ -60,7 +76,19 @@ pub fn init(boot_info: &'static BootInfo) {
         None => panic!("Could not find enough memory for initial heap"),
     };
     heap::init(&mut mapper, &mut bootstrap).expect("failed to init kernel heap");
+    vmem::install(mapper, bootstrap);
     info!("memory enabled");
+    thread::init_threads();
+    process::init_processes();
+    syscall::init_syscalls();
+
+    interrupts::init_lapic();
+    interrupts::nmi::init_watchdog();
+    acpi::init_acpi();
+    interrupts::init_ioapic();
+    devices::hpet::init_hpet();
+    time::init_time();
+    smp::init_smp(&boot_info.memory_map);
 
     dbg!(alloc::alloc::Layout::new::<u8>());
     dbg!(alloc::alloc::Layout::new::<u16>());
@@ -79,17 +107,53 @@ pub fn main() -> ! {
     let world = Box::new("World");
     vgaprintln!("Hello, {}!", world);
 
-    info!("entering halt...");
-    halt()
+    let mut executor = task::Executor::new();
+    executor.spawn(echo_keyboard());
+    executor.spawn(echo_serial());
+    info!("running {} tasks...", executor.len());
+    executor.run();
+    panic!("the echo tasks read endless streams, they never complete")
+}
+
+async fn echo_keyboard() {
+    use pc_keyboard::DecodedKey;
+    use task::StreamExt;
+    let mut scancodes = devices::keyboard::scancodes().expect("keyboard stream already taken");
+    while let Some(scancode) = scancodes.next().await {
+        if let Some(DecodedKey::Unicode(ch)) = devices::KEYBOARD_DEVICE.decode(scancode) {
+            vgaprint!("{}", ch);
+        }
+    }
+}
+
+async fn echo_serial() {
+    use task::StreamExt;
+    let mut input = devices::serial::input().expect("serial stream already taken");
+    while let Some(byte) = input.next().await {
+        vgaprint!("{}", byte as char);
+    }
+}
+
+/// Run the other threads and the deferred work, sleep when there's nothing to do.
+pub fn idle_loop() -> ! {
+    loop {
+        interrupts::deferred::run_pending();
+        thread::yield_now();
+        time::idle();
+    }
 }
 
 pub fn halt() -> ! {
     loop {
-        x86_64::instructions::hlt();
+        interrupts::deferred::run_pending();
+        time::idle();
     }
 }
 
 /** TESTING */
+/// a test still running after this long fails
+pub const TEST_TIMEOUT_MS: u64 = 10_000;
+
 pub trait Testable {
     fn test_name(&self) -> &'static str;
     fn run(&self, align_to: usize) -> ();
@@ -101,7 +165,9 @@ impl<T: Fn()> Testable for T {
     fn run(&self, align_to: usize) {
         let name = self.test_name();
         print!("{}... {: >2$}", name, "", align_to - name.len());
+        devices::pit::set_deadline(Some(TEST_TIMEOUT_MS));
         self();
+        devices::pit::set_deadline(None);
         println!("[ok]");
     }
 }
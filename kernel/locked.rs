@@ -1,5 +1,14 @@
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+
 use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts;
+
+use crate::thread::preempt::{self, PreemptGuard};
 
+/// A spinlock that disables preemption while it's held,
+/// so a thread doesn't spin on a lock held by a preempted one.
+/// Deferred work, which could take it too, is held back as well.
 #[repr(transparent)]
 pub struct Locked<T>(Mutex<T>);
 
@@ -7,7 +16,96 @@ impl<T> Locked<T> {
     pub const fn new(inner: T) -> Self {
         Locked(Mutex::new(inner))
     }
-    pub fn lock(&self) -> MutexGuard<'_, T> {
-        self.0.lock()
+    pub fn lock(&self) -> LockedGuard<'_, T> {
+        let preempt = preempt::guard();
+        LockedGuard {
+            guard: self.0.lock(),
+            _preempt: preempt,
+        }
+    }
+}
+
+pub struct LockedGuard<'a, T> {
+    // fields are dropped in order: the lock is released before preemption is enabled
+    guard: MutexGuard<'a, T>,
+    _preempt: PreemptGuard,
+}
+
+impl<T> Deref for LockedGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+impl<T> DerefMut for LockedGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+/// A spinlock that disables interrupts while it's held,
+/// so it can be shared with IRQ handlers without deadlocking.
+#[repr(transparent)]
+pub struct IrqLocked<T>(Mutex<T>);
+
+impl<T> IrqLocked<T> {
+    pub const fn new(inner: T) -> Self {
+        IrqLocked(Mutex::new(inner))
+    }
+    /// disables interrupts, the previous RFLAGS.IF is restored when the guard is dropped.
+    pub fn lock(&self) -> IrqLockedGuard<'_, T> {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        IrqLockedGuard {
+            guard: ManuallyDrop::new(self.0.lock()),
+            enabled,
+        }
+    }
+}
+
+pub struct IrqLockedGuard<'a, T> {
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    /// whether interrupts were enabled before locking
+    enabled: bool,
+}
+
+impl<T> Deref for IrqLockedGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+impl<T> DerefMut for IrqLockedGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+impl<T> Drop for IrqLockedGuard<'_, T> {
+    fn drop(&mut self) {
+        // release the lock before an interrupt can come in
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+#[test_case]
+fn test_irq_locked_restores_if() {
+    let a = IrqLocked::new(0);
+    let b = IrqLocked::new(0);
+    assert!(interrupts::are_enabled());
+    {
+        let mut outer = a.lock();
+        assert!(!interrupts::are_enabled());
+        {
+            let mut inner = b.lock();
+            *inner += 1;
+        }
+        // still inside the outer lock
+        assert!(!interrupts::are_enabled());
+        *outer += 1;
     }
+    assert!(interrupts::are_enabled());
+    assert_eq!((*a.lock(), *b.lock()), (1, 1));
 }
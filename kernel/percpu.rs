@@ -0,0 +1,190 @@
+//! per-CPU data
+//!
+//! Every CPU has its own `PerCpu` block, which GS points to while it runs the kernel. In user
+//! mode GS is the user's, the block waits in `KERNEL_GS_BASE` for the `swapgs` of the next entry
+//! in the kernel: see `trap_common` and the SYSCALL stub. User code can't move its GS base
+//! away from 0, which tells the two apart.
+//!
+//! Nothing is shared between CPUs through a block, and threads don't move between CPUs: the
+//! block of the executing CPU is the one for as long as the thread runs.
+//!
+//! The block only holds what assembly reaches through GS. Other per-CPU variables are declared
+//! with `percpu!`, which keeps a value per CPU in an array indexed by the block's id.
+use alloc::boxed::Box;
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::VirtAddr;
+
+use crate::cpu::{self, MAX_CPUS};
+use crate::thread::preempt;
+
+/// The SYSCALL stub hardcodes the offsets of `kernel_rsp` and `user_rsp`.
+#[repr(C)]
+pub struct PerCpu {
+    /// the block's own address, GS relative addressing can't give it
+    this: *const PerCpu,
+    /// top of the running thread's kernel stack, for the SYSCALL stub
+    kernel_rsp: Cell<u64>,
+    /// the user stack pointer, while the SYSCALL stub switches stacks
+    user_rsp: Cell<u64>,
+    id: usize,
+    apic_id: u32,
+}
+
+/// the bootstrap processor's, which needs one before the heap is up
+static mut BSP: PerCpu = PerCpu::new(0);
+/// whether the bootstrap processor installed its block, the APs install theirs first thing
+static READY: AtomicBool = AtomicBool::new(false);
+
+impl PerCpu {
+    const fn new(id: usize) -> PerCpu {
+        PerCpu {
+            this: core::ptr::null(),
+            kernel_rsp: Cell::new(0),
+            user_rsp: Cell::new(0),
+            id,
+            apic_id: 0,
+        }
+    }
+
+    /// index of the CPU: 0 for the bootstrap processor, then in the order they started
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// initial local APIC id of the CPU
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id
+    }
+
+    /// Set the stack the SYSCALL stub switches to, only read with interrupts disabled.
+    pub(crate) fn set_kernel_rsp(&self, top: u64) {
+        self.kernel_rsp.set(top);
+    }
+}
+
+/// Allocate the block of the application processor `id`, which installs it with `install`.
+pub fn new_ap(id: usize, apic_id: u32) -> Box<PerCpu> {
+    assert!(id > 0 && id < MAX_CPUS);
+    let mut block = Box::new(PerCpu::new(id));
+    block.this = &*block;
+    block.apic_id = apic_id;
+    block
+}
+
+/// Point GS to `block` on the executing CPU, first thing on every CPU.
+pub fn install(block: &'static PerCpu) {
+    // the user's GS, until the first entry in user mode swaps them
+    KernelGsBase::write(VirtAddr::zero());
+    GsBase::write(VirtAddr::from_ptr(block));
+}
+
+/// Install the block of the bootstrap processor.
+pub fn init_percpu() {
+    // SAFETY: only the bootstrap processor gets here, once
+    let block = unsafe {
+        BSP.this = &BSP;
+        BSP.apic_id = cpu::apic_id();
+        &BSP
+    };
+    install(block);
+    READY.store(true, Ordering::Release);
+}
+
+/// the executing CPU's block, after `init_percpu`
+#[inline]
+pub fn current() -> &'static PerCpu {
+    assert!(
+        READY.load(Ordering::Acquire),
+        "per-CPU data used before init_percpu"
+    );
+    let this: *const PerCpu;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) this, options(nostack, preserves_flags, readonly));
+        &*this
+    }
+}
+
+/// index of the executing CPU in the `percpu!` arrays. Only the bootstrap processor runs
+/// before `init_percpu`.
+#[inline]
+fn index() -> usize {
+    if READY.load(Ordering::Acquire) {
+        current().id()
+    } else {
+        0
+    }
+}
+
+/// A variable with a value per CPU, declared with `percpu!`.
+pub struct PerCpuVar<T> {
+    values: [T; MAX_CPUS],
+}
+
+// SAFETY: a CPU only reaches its own value, `get` hands out the others' only if `T: Sync`
+unsafe impl<T: Send> Sync for PerCpuVar<T> {}
+
+impl<T> PerCpuVar<T> {
+    #[doc(hidden)]
+    pub const fn new(values: [T; MAX_CPUS]) -> PerCpuVar<T> {
+        PerCpuVar { values }
+    }
+
+    /// Run `f` with the executing CPU's value, preemption is disabled meanwhile.
+    pub fn with<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        let _guard = preempt::guard();
+        f(&self.values[index()])
+    }
+}
+
+impl<T: Sync> PerCpuVar<T> {
+    /// the executing CPU's value, without disabling preemption: for values the preemption code
+    /// itself uses, or which are fine to keep using from another CPU.
+    #[inline]
+    pub fn get(&self) -> &T {
+        &self.values[index()]
+    }
+}
+
+/// Declare per-CPU variables: `percpu!(static NAME: Type = init;)`, where `init` is a constant
+/// expression every CPU's value starts with. They are reached with `NAME.with(|value| ..)`.
+#[macro_export]
+macro_rules! percpu {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)*) => {
+        $(
+            $(#[$attr])*
+            $vis static $name: $crate::percpu::PerCpuVar<$ty> = {
+                const INIT: $ty = $init;
+                $crate::percpu::PerCpuVar::new([INIT; $crate::cpu::MAX_CPUS])
+            };
+        )*
+    };
+}
+
+#[test_case]
+fn test_current() {
+    let block = current();
+    assert_eq!(block.this, block as *const PerCpu);
+    assert_eq!(block.id(), 0);
+    assert_eq!(block.apic_id(), cpu::apic_id());
+}
+
+#[test_case]
+fn test_syscall_offsets() {
+    let block = current();
+    let base = block as *const PerCpu as usize;
+    assert_eq!(&block.kernel_rsp as *const Cell<u64> as usize - base, 8);
+    assert_eq!(&block.user_rsp as *const Cell<u64> as usize - base, 16);
+}
+
+#[test_case]
+fn test_percpu_var() {
+    crate::percpu! {
+        static COUNTER: Cell<u64> = Cell::new(0);
+    }
+    COUNTER.with(|counter| counter.set(counter.get() + 1));
+    assert_eq!(COUNTER.with(Cell::get), 1);
+    assert_eq!(COUNTER.values[1].get(), 0);
+}
@@ -0,0 +1,299 @@
+//! per-process page tables
+//!
+//! Every address space starts with the kernel's level 4 entries, so the kernel is mapped
+//! everywhere. User mappings live in `USER_START..USER_END`, which the kernel doesn't use:
+//! their page tables and frames belong to the address space and are freed with it.
+use alloc::vec::Vec;
+
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{
+    mapper::MapToError, FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+    PhysFrame, Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::vmem::{self, paging::PAGE_SIZE};
+
+/// first level 4 entry of the user half, 0x1000_0000_0000
+pub const USER_START: u64 = 32 << 39;
+/// the end of the lower canonical half
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    OutOfMemory,
+    AlreadyMapped,
+    /// the range isn't in `USER_START..USER_END`
+    NotUserAddress,
+    NotMapped,
+}
+
+impl From<MapToError<Size4KiB>> for MapError {
+    fn from(err: MapToError<Size4KiB>) -> MapError {
+        match err {
+            MapToError::FrameAllocationFailed => MapError::OutOfMemory,
+            MapToError::PageAlreadyMapped(_) | MapToError::ParentEntryHugePage => {
+                MapError::AlreadyMapped
+            }
+        }
+    }
+}
+
+pub struct AddressSpace {
+    l4: PhysFrame,
+    /// frames of the user mappings, and of their page tables
+    frames: Vec<PhysFrame>,
+}
+
+fn table(frame: PhysFrame) -> *mut PageTable {
+    vmem::phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+/// level 4 entries of the user half
+fn is_user_entry(index: usize) -> bool {
+    (index as u64) << 39 >= USER_START && ((index as u64) << 39) < USER_END
+}
+
+impl AddressSpace {
+    pub fn new() -> Result<AddressSpace, MapError> {
+        let l4 = vmem::alloc_frame().ok_or(MapError::OutOfMemory)?;
+        let (new, kernel) = unsafe { (&mut *table(l4), &*table(vmem::kernel_page_table())) };
+        new.zero();
+        for (i, entry) in kernel.iter().enumerate() {
+            if is_user_entry(i) {
+                debug_assert!(entry.is_unused(), "the kernel maps user addresses");
+            } else {
+                new[i] = entry.clone();
+            }
+        }
+        Ok(AddressSpace {
+            l4,
+            frames: Vec::new(),
+        })
+    }
+
+    /// the level 4 table, to load in CR3
+    pub fn page_table(&self) -> PhysFrame {
+        self.l4
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.l4
+    }
+
+    /// Load the address space on this CPU. Threads switch to theirs on their own, this is for
+    /// the kernel to access user memory.
+    pub fn activate(&self) {
+        let (_, flags) = Cr3::read();
+        unsafe { Cr3::write(self.l4, flags) };
+    }
+
+    fn mapper(&self) -> OffsetPageTable<'static> {
+        // the tables are only accessed through `&mut self`, or read through `&self`
+        unsafe { OffsetPageTable::new(&mut *table(self.l4), vmem::phys_to_virt(PhysAddr::new(0))) }
+    }
+
+    /// Map `len` bytes of zeroed memory from `start`, accessible from user mode with `flags`.
+    /// The range is extended to whole pages.
+    pub fn map(
+        &mut self,
+        start: VirtAddr,
+        len: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), MapError> {
+        let end = start
+            .as_u64()
+            .checked_add(len)
+            .ok_or(MapError::NotUserAddress)?;
+        if start.as_u64() < USER_START || end > USER_END || len == 0 {
+            return Err(MapError::NotUserAddress);
+        }
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let parent_flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        let mut mapper = self.mapper();
+        let active = self.is_active();
+        let pages = Page::<Size4KiB>::range_inclusive(
+            Page::containing_address(start),
+            Page::containing_address(VirtAddr::new(end - 1)),
+        );
+        for page in pages {
+            let frame = vmem::alloc_frame().ok_or(MapError::OutOfMemory)?;
+            unsafe {
+                core::ptr::write_bytes(table(frame) as *mut u8, 0, PAGE_SIZE as usize);
+            }
+            self.frames.push(frame);
+            let mut tables = TableFrames(&mut self.frames);
+            let flush = unsafe {
+                mapper.map_to_with_table_flags(page, frame, flags, parent_flags, &mut tables)?
+            };
+            if active {
+                flush.flush();
+            } else {
+                flush.ignore();
+            }
+        }
+        Ok(())
+    }
+
+    /// Unmap `len` bytes from `start` and free their frames, the page tables are kept. The
+    /// range is extended to whole pages, which must all be mapped.
+    pub fn unmap(&mut self, start: VirtAddr, len: u64) -> Result<(), MapError> {
+        let end = start
+            .as_u64()
+            .checked_add(len)
+            .ok_or(MapError::NotUserAddress)?;
+        if start.as_u64() < USER_START || end > USER_END || len == 0 {
+            return Err(MapError::NotUserAddress);
+        }
+        let mut mapper = self.mapper();
+        let active = self.is_active();
+        let pages = Page::<Size4KiB>::range_inclusive(
+            Page::containing_address(start),
+            Page::containing_address(VirtAddr::new(end - 1)),
+        );
+        let mut unmapped = Vec::new();
+        let mut result = Ok(());
+        for page in pages {
+            let (frame, flush) = match mapper.unmap(page) {
+                Ok(unmapped) => unmapped,
+                Err(_) => {
+                    result = Err(MapError::NotMapped);
+                    break;
+                }
+            };
+            if active {
+                flush.flush();
+            } else {
+                flush.ignore();
+            }
+            unmapped.push(frame);
+        }
+        unmapped.sort_unstable();
+        self.frames
+            .retain(|frame| unmapped.binary_search(frame).is_err());
+        for frame in unmapped {
+            vmem::free_frame(frame);
+        }
+        result
+    }
+
+    /// Where `addr` is mapped, if it is.
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        self.mapper().translate_addr(addr)
+    }
+
+    /// Copy `bytes` to `addr`, whatever the permissions of the pages, and whether the address
+    /// space is active or not. The pages must be mapped.
+    pub fn write(&self, addr: VirtAddr, bytes: &[u8]) -> Result<(), MapError> {
+        let mut done = 0;
+        while done < bytes.len() {
+            let at = addr + done;
+            let in_page = ((PAGE_SIZE - at.as_u64() % PAGE_SIZE) as usize).min(bytes.len() - done);
+            if !(USER_START..USER_END).contains(&at.as_u64()) {
+                return Err(MapError::NotUserAddress);
+            }
+            let phys = self.translate(at).ok_or(MapError::NotMapped)?;
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    bytes[done..].as_ptr(),
+                    vmem::phys_to_virt(phys).as_mut_ptr(),
+                    in_page,
+                );
+            }
+            done += in_page;
+        }
+        Ok(())
+    }
+
+    /// frames owned by the address space, page tables included
+    pub fn frame_count(&self) -> usize {
+        self.frames.len() + 1
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if self.is_active() {
+            let (_, flags) = Cr3::read();
+            unsafe { Cr3::write(vmem::kernel_page_table(), flags) };
+        }
+        for frame in self.frames.drain(..) {
+            vmem::free_frame(frame);
+        }
+        vmem::free_frame(self.l4);
+    }
+}
+
+/// allocates the page tables of the user mappings, so they're freed with the address space
+struct TableFrames<'a>(&'a mut Vec<PhysFrame>);
+
+unsafe impl FrameAllocator<Size4KiB> for TableFrames<'_> {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = vmem::alloc_frame()?;
+        self.0.push(frame);
+        Some(frame)
+    }
+}
+
+#[test_case]
+fn test_map_and_translate() {
+    let addr = VirtAddr::new(USER_START + 0x1000);
+    let mut space = AddressSpace::new().unwrap();
+    assert_eq!(space.translate(addr), None);
+    space
+        .map(addr, 2 * PAGE_SIZE, PageTableFlags::WRITABLE)
+        .unwrap();
+    assert!(space.translate(addr).is_some());
+    assert!(space.translate(addr + 2 * PAGE_SIZE).is_none());
+    assert_eq!(
+        space.map(addr, 1, PageTableFlags::empty()),
+        Err(MapError::AlreadyMapped)
+    );
+    assert_eq!(
+        space.map(VirtAddr::new(0x1000), 1, PageTableFlags::empty()),
+        Err(MapError::NotUserAddress)
+    );
+    // the kernel is mapped too
+    let kernel = VirtAddr::new(vmem::kernel_page_table as fn() -> PhysFrame as u64);
+    assert!(space.translate(kernel).is_some());
+}
+
+#[test_case]
+fn test_unmap() {
+    let addr = VirtAddr::new(USER_START + 0x1000);
+    let mut space = AddressSpace::new().unwrap();
+    space
+        .map(addr, 3 * PAGE_SIZE, PageTableFlags::WRITABLE)
+        .unwrap();
+    let frames = space.frame_count();
+    space.unmap(addr + PAGE_SIZE, 2 * PAGE_SIZE).unwrap();
+    assert!(space.translate(addr).is_some());
+    assert!(space.translate(addr + PAGE_SIZE).is_none());
+    assert_eq!(space.frame_count(), frames - 2);
+    assert_eq!(space.unmap(addr, 2 * PAGE_SIZE), Err(MapError::NotMapped));
+    // the pages before the hole were unmapped still
+    assert!(space.translate(addr).is_none());
+    assert_eq!(space.frame_count(), frames - 3);
+}
+
+#[test_case]
+fn test_isolated() {
+    let addr = VirtAddr::new(USER_START);
+    let mut a = AddressSpace::new().unwrap();
+    let mut b = AddressSpace::new().unwrap();
+    a.map(addr, 8, PageTableFlags::WRITABLE).unwrap();
+    b.map(addr, 8, PageTableFlags::WRITABLE).unwrap();
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let ptr = addr.as_mut_ptr::<u64>();
+        a.activate();
+        unsafe { ptr.write_volatile(1) };
+        b.activate();
+        assert_eq!(unsafe { ptr.read_volatile() }, 0);
+        a.activate();
+        assert_eq!(unsafe { ptr.read_volatile() }, 1);
+        // dropping the active address space switches back to the kernel's
+        drop(a);
+        assert_eq!(Cr3::read().0, vmem::kernel_page_table());
+    });
+}
@@ -0,0 +1,481 @@
+//! loader for static ELF64 executables
+//!
+//! The `PT_LOAD` segments are mapped in a fresh address space with their permissions, the
+//! stack is set up like the System V ABI describes: `argc`, then the `argv` and `envp`
+//! pointers, then the auxiliary vector, with the strings above them.
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::iter;
+
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+use super::address_space::{AddressSpace, MapError, USER_END, USER_START};
+use crate::vmem::{self, paging::PAGE_SIZE};
+
+/// top of the user stack, the last page of the user half is left for the signal trampoline
+pub const STACK_TOP: u64 = USER_END - PAGE_SIZE;
+pub const STACK_SIZE: u64 = 64 * 1024;
+/// the heap can't grow past it, a page is left unmapped under the stack
+pub const HEAP_END: u64 = STACK_TOP - STACK_SIZE - PAGE_SIZE;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 0x3e;
+const PT_LOAD: u32 = 1;
+const PT_PHDR: u32 = 6;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+// auxiliary vector entries
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// shorter than its headers say
+    Truncated,
+    BadMagic,
+    /// not a little endian ELF64 executable for x86_64
+    Unsupported,
+    /// a segment is malformed, or outside the user half
+    BadSegment,
+    /// the entry point isn't in an executable segment
+    BadEntry,
+    OutOfMemory,
+}
+
+impl From<MapError> for ElfError {
+    fn from(err: MapError) -> ElfError {
+        match err {
+            MapError::OutOfMemory => ElfError::OutOfMemory,
+            _ => ElfError::BadSegment,
+        }
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+}
+
+/// A validated executable.
+pub struct Elf<'a> {
+    data: &'a [u8],
+    entry: u64,
+    phoff: u64,
+    segments: Vec<Segment>,
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Elf<'a>, ElfError> {
+        if data.len() < HEADER_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if data[..4] != *b"\x7fELF" {
+            return Err(ElfError::BadMagic);
+        }
+        // 64 bits, little endian, version 1
+        if data[4..7] != [2, 1, 1]
+            || read_u16(data, 16) != ET_EXEC
+            || read_u16(data, 18) != EM_X86_64
+            || read_u16(data, 54) as usize != PROGRAM_HEADER_SIZE
+        {
+            return Err(ElfError::Unsupported);
+        }
+        let entry = read_u64(data, 24);
+        let phoff = read_u64(data, 32);
+        let phnum = read_u16(data, 56) as u64;
+        let end = phoff
+            .checked_add(phnum * PROGRAM_HEADER_SIZE as u64)
+            .ok_or(ElfError::Truncated)?;
+        if end > data.len() as u64 {
+            return Err(ElfError::Truncated);
+        }
+        let segments = (0..phnum as usize)
+            .map(|i| {
+                let at = phoff as usize + i * PROGRAM_HEADER_SIZE;
+                Segment {
+                    kind: read_u32(data, at),
+                    flags: read_u32(data, at + 4),
+                    offset: read_u64(data, at + 8),
+                    vaddr: read_u64(data, at + 16),
+                    file_size: read_u64(data, at + 32),
+                    mem_size: read_u64(data, at + 40),
+                }
+            })
+            .collect();
+        let elf = Elf {
+            data,
+            entry,
+            phoff,
+            segments,
+        };
+        for segment in elf.loadable() {
+            elf.check(segment)?;
+        }
+        let executable = elf.loadable().any(|segment| {
+            segment.flags & PF_X != 0
+                && (segment.vaddr..segment.vaddr + segment.mem_size).contains(&entry)
+        });
+        if !executable {
+            return Err(ElfError::BadEntry);
+        }
+        Ok(elf)
+    }
+
+    fn check(&self, segment: &Segment) -> Result<(), ElfError> {
+        let file_end = segment
+            .offset
+            .checked_add(segment.file_size)
+            .ok_or(ElfError::Truncated)?;
+        if file_end > self.data.len() as u64 {
+            return Err(ElfError::Truncated);
+        }
+        let mem_end = segment.vaddr.checked_add(segment.mem_size);
+        let in_user = segment.vaddr >= USER_START && mem_end.map_or(false, |end| end <= HEAP_END);
+        if !in_user || segment.file_size > segment.mem_size || segment.mem_size == 0 {
+            return Err(ElfError::BadSegment);
+        }
+        // mapped page by page from the file
+        if segment.vaddr % PAGE_SIZE != segment.offset % PAGE_SIZE {
+            return Err(ElfError::BadSegment);
+        }
+        Ok(())
+    }
+
+    pub fn entry(&self) -> VirtAddr {
+        VirtAddr::new(self.entry)
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    fn loadable(&self) -> impl Iterator<Item = &Segment> {
+        self.segments
+            .iter()
+            .filter(|segment| segment.kind == PT_LOAD)
+    }
+
+    /// where the program headers are once loaded, if they are
+    fn phdr(&self) -> Option<u64> {
+        if let Some(phdr) = self.segments.iter().find(|segment| segment.kind == PT_PHDR) {
+            return Some(phdr.vaddr);
+        }
+        self.loadable()
+            .find(|segment| {
+                (segment.offset..segment.offset + segment.file_size).contains(&self.phoff)
+            })
+            .map(|segment| segment.vaddr + (self.phoff - segment.offset))
+    }
+}
+
+/// Where to start a loaded program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Image {
+    pub entry: VirtAddr,
+    pub stack: VirtAddr,
+    /// the initial program break, the page after the last segment
+    pub brk: VirtAddr,
+}
+
+/// Map `elf` and its stack in `space`, which should be fresh.
+pub fn load(
+    space: &mut AddressSpace,
+    elf: &Elf<'_>,
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<Image, ElfError> {
+    let runs = page_runs(elf);
+    // give up before mapping anything: the sizes come from the file. The page tables take a
+    // frame every 512 pages, and up to 3 more for the upper levels of every run.
+    let pages =
+        runs.iter().map(|&(start, end, _)| end - start).sum::<u64>() + STACK_SIZE / PAGE_SIZE;
+    if pages + pages / 512 + 3 * (runs.len() as u64 + 1) > vmem::available_frames() {
+        return Err(ElfError::OutOfMemory);
+    }
+    for (start, end, flags) in runs {
+        space.map(
+            VirtAddr::new(start * PAGE_SIZE),
+            (end - start) * PAGE_SIZE,
+            flags,
+        )?;
+    }
+    // the rest of the memory size, .bss, is already zeroed
+    for segment in elf.loadable() {
+        let data =
+            &elf.data[segment.offset as usize..(segment.offset + segment.file_size) as usize];
+        space.write(VirtAddr::new(segment.vaddr), data)?;
+    }
+    let stack_bottom = VirtAddr::new(STACK_TOP - STACK_SIZE);
+    space.map(
+        stack_bottom,
+        STACK_SIZE,
+        PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+    )?;
+    let stack = setup_stack(space, elf, argv, envp)?;
+    let end = elf
+        .loadable()
+        .map(|segment| segment.vaddr + segment.mem_size)
+        .max()
+        .unwrap_or(USER_START);
+    Ok(Image {
+        entry: elf.entry(),
+        stack,
+        brk: VirtAddr::new(end).align_up(PAGE_SIZE),
+    })
+}
+
+/// The pages of the loadable segments, as runs of page numbers `start..end` with their flags.
+/// Segments may share pages, at their boundaries, which get the permissions of all of them.
+fn page_runs(elf: &Elf<'_>) -> Vec<(u64, u64, PageTableFlags)> {
+    let ranges: Vec<(u64, u64, u32)> = elf
+        .loadable()
+        .map(|segment| {
+            let end = (segment.vaddr + segment.mem_size - 1) / PAGE_SIZE + 1;
+            (segment.vaddr / PAGE_SIZE, end, segment.flags)
+        })
+        .collect();
+    let mut bounds: Vec<u64> = ranges
+        .iter()
+        .flat_map(|&(start, end, _)| iter::once(start).chain(iter::once(end)))
+        .collect();
+    bounds.sort_unstable();
+    bounds.dedup();
+    let mut runs: Vec<(u64, u64, PageTableFlags)> = Vec::new();
+    for pair in bounds.windows(2) {
+        let (start, end) = (pair[0], pair[1]);
+        let segment_flags = ranges
+            .iter()
+            .filter(|&&(first, last, _)| first <= start && end <= last)
+            .fold(None, |flags, &(_, _, segment)| {
+                Some(flags.unwrap_or(0) | segment)
+            });
+        let segment_flags = match segment_flags {
+            Some(flags) => flags,
+            // between segments
+            None => continue,
+        };
+        let mut flags = PageTableFlags::empty();
+        if segment_flags & PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if segment_flags & PF_X == 0 {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        match runs.last_mut() {
+            Some(last) if last.1 == start && last.2 == flags => last.1 = end,
+            _ => runs.push((start, end, flags)),
+        }
+    }
+    runs
+}
+
+/// Write the arguments, environment and auxiliary vector at the top of the stack, returns the
+/// initial stack pointer.
+fn setup_stack(
+    space: &AddressSpace,
+    elf: &Elf<'_>,
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<VirtAddr, ElfError> {
+    let mut top = STACK_TOP;
+    let mut push_string = |s: &str| -> Result<u64, ElfError> {
+        top -= s.len() as u64 + 1;
+        space.write(VirtAddr::new(top), s.as_bytes())?;
+        space.write(VirtAddr::new(top + s.len() as u64), &[0])?;
+        Ok(top)
+    };
+    let argv = argv
+        .iter()
+        .map(|arg| push_string(arg))
+        .collect::<Result<Vec<_>, _>>()?;
+    let envp = envp
+        .iter()
+        .map(|var| push_string(var))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut words = Vec::new();
+    words.push(argv.len() as u64);
+    words.extend(&argv);
+    words.push(0);
+    words.extend(&envp);
+    words.push(0);
+    if let Some(phdr) = elf.phdr() {
+        words.extend(&[AT_PHDR, phdr]);
+    }
+    words.extend(&[
+        AT_PHENT,
+        PROGRAM_HEADER_SIZE as u64,
+        AT_PHNUM,
+        elf.segments.len() as u64,
+        AT_PAGESZ,
+        PAGE_SIZE,
+        AT_ENTRY,
+        elf.entry,
+        AT_NULL,
+        0,
+    ]);
+    // `argc` must be 16 bytes aligned
+    let rsp = (top - words.len() as u64 * 8) & !0xf;
+    if rsp < STACK_TOP - STACK_SIZE {
+        return Err(ElfError::OutOfMemory);
+    }
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    space.write(VirtAddr::new(rsp), &bytes)?;
+    Ok(VirtAddr::new(rsp))
+}
+
+/// A minimal executable: `code` mapped read and execute at `base`, followed by `bss` zeroed
+/// bytes, writable.
+#[cfg(test)]
+pub(crate) fn build(code: &[u8], bss: u64) -> Vec<u8> {
+    const BASE: u64 = USER_START + 0x40_0000;
+    let code_offset = (HEADER_SIZE + 2 * PROGRAM_HEADER_SIZE) as u64;
+    let code_end = code_offset + code.len() as u64;
+    let mut elf = Vec::new();
+    elf.extend(b"\x7fELF");
+    elf.extend(&[2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    elf.extend(&ET_EXEC.to_le_bytes());
+    elf.extend(&EM_X86_64.to_le_bytes());
+    elf.extend(&1u32.to_le_bytes());
+    elf.extend(&(BASE + code_offset).to_le_bytes());
+    elf.extend(&(HEADER_SIZE as u64).to_le_bytes());
+    elf.extend(&0u64.to_le_bytes());
+    elf.extend(&0u32.to_le_bytes());
+    for half in &[HEADER_SIZE, PROGRAM_HEADER_SIZE, 2, 0, 0, 0] {
+        elf.extend(&(*half as u16).to_le_bytes());
+    }
+    let mut program_header = |flags: u32, offset: u64, vaddr: u64, file_size: u64, mem_size| {
+        elf.extend(&PT_LOAD.to_le_bytes());
+        elf.extend(&flags.to_le_bytes());
+        for word in &[offset, vaddr, vaddr, file_size, mem_size, PAGE_SIZE] {
+            elf.extend(&word.to_le_bytes());
+        }
+    };
+    // the headers are loaded with the code
+    program_header(PF_X | 4, 0, BASE, code_end, code_end);
+    // .bss, on the next page
+    let bss_start = BASE + PAGE_SIZE;
+    program_header(
+        PF_W | 4,
+        code_end,
+        bss_start + code_end % PAGE_SIZE,
+        0,
+        bss.max(1),
+    );
+    elf.extend(code);
+    elf
+}
+
+#[test_case]
+fn test_parse_errors() {
+    assert_eq!(Elf::parse(b"\x7fELF").err(), Some(ElfError::Truncated));
+    let mut elf = build(&[0x0f, 0x05], 0);
+    assert!(Elf::parse(&elf).is_ok());
+    elf[18] = 0x28;
+    assert_eq!(Elf::parse(&elf).err(), Some(ElfError::Unsupported));
+    elf[0] = 0;
+    assert_eq!(Elf::parse(&elf).err(), Some(ElfError::BadMagic));
+    let elf = build(&[0x0f, 0x05], 0);
+    assert_eq!(
+        Elf::parse(&elf[..elf.len() - 1]).err(),
+        Some(ElfError::Truncated)
+    );
+}
+
+#[test_case]
+fn test_load() {
+    let elf = build(&[0x0f, 0x05], 16);
+    let elf = Elf::parse(&elf).unwrap();
+    let mut space = AddressSpace::new().unwrap();
+    let image = load(&mut space, &elf, &["test", "arg"], &["HOME=/"]).unwrap();
+    assert_eq!(image.entry, elf.entry());
+    assert_eq!(image.stack.as_u64() % 16, 0);
+    assert!(space.translate(image.entry).is_some());
+    assert!(space.translate(image.stack).is_some());
+    // the stack isn't mapped past its size
+    assert!(space.translate(VirtAddr::new(STACK_TOP)).is_none());
+}
+
+#[test_case]
+fn test_run_with_args() {
+    use super::{spawn_elf, waitpid};
+    // mov rdi, [rsp]; xor eax, eax; syscall: exit(argc)
+    let elf = build(&[0x48, 0x8b, 0x3c, 0x24, 0x31, 0xc0, 0x0f, 0x05], 0);
+    let pid = spawn_elf("args", &elf, &["args", "a", "b"], &[]).unwrap();
+    assert_eq!(waitpid(pid), Ok(3));
+}
+
+#[test_case]
+fn test_segment_permissions() {
+    use super::signal::{exit_status, Signal};
+    use super::{spawn_elf, waitpid};
+    // mov rax, bss; mov qword [rax], 7; mov rdi, [rax]; xor eax, eax; syscall
+    let code = [
+        0x48, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0x48, 0xc7, 0x00, 0x07, 0x00, 0x00, 0x00, 0x48, 0x8b,
+        0x38, 0x31, 0xc0, 0x0f, 0x05,
+    ];
+    let mut binary = build(&code, 8);
+    let bss = Elf::parse(&binary).unwrap().segments()[1].vaddr;
+    let imm = HEADER_SIZE + 2 * PROGRAM_HEADER_SIZE + 2;
+    binary[imm..imm + 8].copy_from_slice(&bss.to_le_bytes());
+    let pid = spawn_elf("bss", &binary, &[], &[]).unwrap();
+    assert_eq!(waitpid(pid), Ok(7));
+    // lea rax, [rip]; mov byte [rax], 0: the code isn't writable
+    let binary = build(&[0x48, 0x8d, 0x05, 0, 0, 0, 0, 0xc6, 0x00, 0x00], 0);
+    let pid = spawn_elf("code", &binary, &[], &[]).unwrap();
+    assert_eq!(waitpid(pid), Ok(exit_status(Signal::SIGSEGV)));
+}
+
+#[test_case]
+fn test_huge_segment() {
+    // .bss up to most of the user half
+    let binary = build(&[0x0f, 0x05], HEAP_END / 2);
+    let elf = Elf::parse(&binary).unwrap();
+    let mut space = AddressSpace::new().unwrap();
+    let before = vmem::available_frames();
+    assert_eq!(
+        load(&mut space, &elf, &[], &[]).err(),
+        Some(ElfError::OutOfMemory)
+    );
+    assert_eq!(space.frame_count(), 1);
+    assert_eq!(vmem::available_frames(), before);
+}
+
+#[test_case]
+fn test_shared_page() {
+    let mut binary = build(&[0x0f, 0x05], 16);
+    // move .bss back on the code's last page
+    let vaddr = HEADER_SIZE + PROGRAM_HEADER_SIZE + 16;
+    let bss = u64::from_le_bytes(binary[vaddr..vaddr + 8].try_into().unwrap()) - PAGE_SIZE;
+    binary[vaddr..vaddr + 8].copy_from_slice(&bss.to_le_bytes());
+    binary[vaddr + 8..vaddr + 16].copy_from_slice(&bss.to_le_bytes());
+    let elf = Elf::parse(&binary).unwrap();
+    let page = elf.segments()[0].vaddr / PAGE_SIZE;
+    assert_eq!(
+        page_runs(&elf),
+        [(page, page + 1, PageTableFlags::WRITABLE)]
+    );
+}
@@ -0,0 +1,502 @@
+//! processes: an address space and the threads running in it
+//!
+//! Processes form a tree. A process whose last thread exited stays a zombie, with its exit
+//! status, until its parent reaps it with `wait`. The children of an exited process are
+//! adopted by init, the process of the boot thread.
+pub mod address_space;
+pub mod elf;
+#[cfg(all(test, feature = "userland-tests"))]
+mod programs;
+pub mod signal;
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use lazy_static::lazy_static;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+use crate::info;
+use crate::locked::Locked;
+use crate::sync::WaitQueue;
+use crate::thread::{self, JoinHandle, ThreadId};
+use crate::user;
+use crate::vmem::{self, paging::PAGE_SIZE};
+pub use address_space::AddressSpace;
+use elf::{Elf, ElfError};
+use signal::{Signal, Signals};
+
+pub type Pid = u64;
+
+/// the process of the boot thread, which adopts the orphans. It doesn't wait for them, they're
+/// reaped as soon as they exit.
+pub const INIT_PID: Pid = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    /// stopped by a signal, until `SIGCONT`
+    Stopped,
+    /// exited with this status, waiting to be reaped
+    Zombie(i32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// no such process, or it isn't a child of the caller
+    NoSuchProcess,
+    NoChildren,
+    OutOfMemory,
+    InvalidExecutable(ElfError),
+    /// init can't be signaled, `SIGKILL` and `SIGSTOP` can't be caught or ignored
+    NotPermitted,
+}
+
+pub struct Process {
+    parent: Pid,
+    name: String,
+    /// `None` for init, which runs in the kernel's, and for zombies
+    space: Option<AddressSpace>,
+    threads: Vec<ThreadId>,
+    children: Vec<Pid>,
+    /// set by the first `exit`
+    exit_status: Option<i32>,
+    state: State,
+    signals: Signals,
+    /// `None` if it wasn't loaded from an executable
+    brk: Option<Break>,
+    /// adopted by init, which doesn't reap it
+    orphan: bool,
+}
+
+/// the program break: the end of the heap, which follows the executable
+#[derive(Debug, Clone, Copy)]
+struct Break {
+    start: u64,
+    end: u64,
+    /// pages are mapped up to there, they stay mapped when the break moves down
+    mapped: u64,
+}
+
+#[derive(Default)]
+struct Table {
+    processes: BTreeMap<Pid, Process>,
+    /// the process of each thread, kernel threads aren't in any
+    owners: BTreeMap<ThreadId, Pid>,
+    next_pid: Pid,
+}
+
+lazy_static! {
+    static ref TABLE: Locked<Table> = Locked::new(Table::default());
+}
+/// woken up whenever a process becomes a zombie
+static EXITED: WaitQueue = WaitQueue::new();
+
+/// Make the boot thread the init process.
+pub fn init_processes() {
+    let mut table = TABLE.lock();
+    table.processes.insert(
+        INIT_PID,
+        Process {
+            parent: INIT_PID,
+            name: String::from("init"),
+            space: None,
+            threads: alloc::vec![thread::current_id()],
+            children: Vec::new(),
+            exit_status: None,
+            state: State::Running,
+            signals: Signals::default(),
+            brk: None,
+            orphan: false,
+        },
+    );
+    table.owners.insert(thread::current_id(), INIT_PID);
+    table.next_pid = INIT_PID + 1;
+    info!("processes enabled");
+}
+
+impl Table {
+    fn get_mut(&mut self, pid: Pid) -> &mut Process {
+        self.processes.get_mut(&pid).expect("no such process")
+    }
+
+    /// Forget the thread, returns whether its process became a zombie.
+    fn thread_exited(&mut self, id: ThreadId, status: i32) -> bool {
+        let pid = match self.owners.remove(&id) {
+            Some(pid) => pid,
+            None => return false,
+        };
+        assert_ne!(pid, INIT_PID, "init exited");
+        let process = self.get_mut(pid);
+        process.threads.retain(|&thread| thread != id);
+        let status = *process.exit_status.get_or_insert(status);
+        if !process.threads.is_empty() {
+            return false;
+        }
+        process.state = State::Zombie(status);
+        // switches to the kernel's page tables if it's still loaded
+        process.space = None;
+        let children = core::mem::take(&mut process.children);
+        for &child in &children {
+            let child = self.get_mut(child);
+            child.parent = INIT_PID;
+            child.orphan = true;
+        }
+        self.get_mut(INIT_PID).children.extend(&children);
+        for child in children {
+            if let State::Zombie(_) = self.processes[&child].state {
+                self.reap_orphan(child);
+            }
+        }
+        let process = &self.processes[&pid];
+        if process.orphan {
+            self.reap_orphan(pid);
+        } else if process.parent != INIT_PID {
+            let parent = process.parent;
+            self.get_mut(parent).post(Signal::SIGCHLD);
+        }
+        true
+    }
+
+    /// Forget a zombie adopted by init, nothing waits for it.
+    fn reap_orphan(&mut self, pid: Pid) {
+        self.processes.remove(&pid);
+        self.get_mut(INIT_PID)
+            .children
+            .retain(|&child| child != pid);
+    }
+
+    /// Remove a zombie child of `parent`, `pid` or any if `None`.
+    /// Returns `None` if there are children, but none of them exited yet.
+    fn reap(&mut self, parent: Pid, pid: Option<Pid>) -> Option<Result<(Pid, i32), Error>> {
+        let children = &self.processes[&parent].children;
+        if children.is_empty() {
+            return Some(Err(Error::NoChildren));
+        }
+        if let Some(pid) = pid {
+            if !children.contains(&pid) {
+                return Some(Err(Error::NoSuchProcess));
+            }
+        }
+        let (zombie, status) = children
+            .iter()
+            .filter(|&&child| pid.map_or(true, |pid| pid == child))
+            .find_map(|child| match self.processes[child].state {
+                State::Zombie(status) => Some((*child, status)),
+                State::Running | State::Stopped => None,
+            })?;
+        self.processes.remove(&zombie);
+        self.get_mut(parent)
+            .children
+            .retain(|&child| child != zombie);
+        Some(Ok((zombie, status)))
+    }
+
+    fn add_thread(&mut self, pid: Pid, f: impl FnOnce() + Send + 'static) -> JoinHandle {
+        let page_table = self.processes[&pid]
+            .space
+            .as_ref()
+            .expect("a process without address space")
+            .page_table();
+        let handle = thread::spawn_in(page_table, move || {
+            f();
+            exit(0)
+        });
+        // the lock disables preemption, it can't run before it's registered
+        self.get_mut(pid).threads.push(handle.id());
+        self.owners.insert(handle.id(), pid);
+        handle
+    }
+}
+
+/// Create a child of the current process, with a new address space and a thread running `f`.
+/// It exits with status 0 when `f` returns.
+pub fn spawn(name: &str, f: impl FnOnce() + Send + 'static) -> Result<Pid, Error> {
+    let space = AddressSpace::new().map_err(|_| Error::OutOfMemory)?;
+    Ok(spawn_in(name, space, None, f))
+}
+
+/// Create a child of the current process running the ELF executable `binary` in user mode.
+pub fn spawn_elf(name: &str, binary: &[u8], argv: &[&str], envp: &[&str]) -> Result<Pid, Error> {
+    let elf = Elf::parse(binary).map_err(Error::InvalidExecutable)?;
+    let mut space = AddressSpace::new().map_err(|_| Error::OutOfMemory)?;
+    let image = elf::load(&mut space, &elf, argv, envp).map_err(|err| match err {
+        ElfError::OutOfMemory => Error::OutOfMemory,
+        err => Error::InvalidExecutable(err),
+    })?;
+    let brk = Break {
+        start: image.brk.as_u64(),
+        end: image.brk.as_u64(),
+        mapped: image.brk.as_u64(),
+    };
+    Ok(spawn_in(name, space, Some(brk), move || {
+        user::enter_user(image.entry, image.stack)
+    }))
+}
+
+fn spawn_in(
+    name: &str,
+    space: AddressSpace,
+    brk: Option<Break>,
+    f: impl FnOnce() + Send + 'static,
+) -> Pid {
+    let mut table = TABLE.lock();
+    let parent = table
+        .owners
+        .get(&thread::current_id())
+        .copied()
+        .unwrap_or(INIT_PID);
+    let pid = table.next_pid;
+    table.next_pid += 1;
+    table.processes.insert(
+        pid,
+        Process {
+            parent,
+            name: String::from(name),
+            space: Some(space),
+            threads: Vec::new(),
+            children: Vec::new(),
+            exit_status: None,
+            state: State::Running,
+            signals: Signals::default(),
+            brk,
+            orphan: false,
+        },
+    );
+    table.get_mut(parent).children.push(pid);
+    table.add_thread(pid, f);
+    pid
+}
+
+/// Start another thread in the current process.
+pub fn spawn_thread(f: impl FnOnce() + Send + 'static) -> Result<JoinHandle, Error> {
+    let mut table = TABLE.lock();
+    let pid = table
+        .owners
+        .get(&thread::current_id())
+        .copied()
+        .ok_or(Error::NoSuchProcess)?;
+    if pid == INIT_PID {
+        // init's threads are the kernel's
+        return Err(Error::NoSuchProcess);
+    }
+    Ok(table.add_thread(pid, f))
+}
+
+/// End the current thread. The process exits with the status of the first `exit` once its
+/// last thread did.
+pub fn exit(status: i32) -> ! {
+    // not preempted while running on an address space that's being freed
+    interrupts::disable();
+    let zombie = TABLE.lock().thread_exited(thread::current_id(), status);
+    if zombie {
+        EXITED.wake_all();
+    }
+    thread::exit()
+}
+
+/// Block until a child exits, reaps it and returns its pid and exit status.
+pub fn wait() -> Result<(Pid, i32), Error> {
+    wait_for(None)
+}
+
+/// Block until the child `pid` exits, reaps it and returns its exit status.
+pub fn waitpid(pid: Pid) -> Result<i32, Error> {
+    wait_for(Some(pid)).map(|(_, status)| status)
+}
+
+fn wait_for(pid: Option<Pid>) -> Result<(Pid, i32), Error> {
+    let parent = current_pid().ok_or(Error::NoChildren)?;
+    let mut result = None;
+    EXITED.wait_until(|| {
+        result = TABLE.lock().reap(parent, pid);
+        result.is_some()
+    });
+    result.unwrap()
+}
+
+/// process of the running thread, `None` for kernel threads
+pub fn current_pid() -> Option<Pid> {
+    TABLE.lock().owners.get(&thread::current_id()).copied()
+}
+
+pub fn parent(pid: Pid) -> Option<Pid> {
+    TABLE
+        .lock()
+        .processes
+        .get(&pid)
+        .map(|process| process.parent)
+}
+
+pub fn state(pid: Pid) -> Option<State> {
+    TABLE
+        .lock()
+        .processes
+        .get(&pid)
+        .map(|process| process.state)
+}
+
+pub fn name(pid: Pid) -> Option<String> {
+    TABLE
+        .lock()
+        .processes
+        .get(&pid)
+        .map(|process| process.name.clone())
+}
+
+/// Run `f` on the address space of `pid`, `None` if it has none.
+pub fn with_space<R>(pid: Pid, f: impl FnOnce(&mut AddressSpace) -> R) -> Option<R> {
+    let mut table = TABLE.lock();
+    table
+        .processes
+        .get_mut(&pid)
+        .and_then(|process| process.space.as_mut())
+        .map(f)
+}
+
+/// Move the program break of the current process to `end`, or only return it if `None`.
+/// Returns the new break.
+pub fn set_break(end: Option<VirtAddr>) -> Result<VirtAddr, Error> {
+    let pid = current_pid().ok_or(Error::NoSuchProcess)?;
+    let mut table = TABLE.lock();
+    let process = table.get_mut(pid);
+    let brk = process.brk.as_mut().ok_or(Error::NotPermitted)?;
+    let end = match end {
+        Some(end) => end.as_u64(),
+        None => return Ok(VirtAddr::new(brk.end)),
+    };
+    if end < brk.start || end > elf::HEAP_END {
+        return Err(Error::OutOfMemory);
+    }
+    let space = process
+        .space
+        .as_mut()
+        .expect("a process without address space");
+    // the table stays locked, without preemption, while the pages are mapped: give up early
+    // rather than after taking every frame. The page tables take one frame every 512 pages,
+    // and up to 3 more for the upper levels.
+    let pages = (end.saturating_sub(brk.mapped) + PAGE_SIZE - 1) / PAGE_SIZE;
+    if pages + pages / 512 + 3 > vmem::available_frames() {
+        return Err(Error::OutOfMemory);
+    }
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let mapped = brk.mapped;
+    while brk.mapped < end {
+        if space
+            .map(VirtAddr::new(brk.mapped), PAGE_SIZE, flags)
+            .is_err()
+        {
+            if brk.mapped > mapped {
+                space
+                    .unmap(VirtAddr::new(mapped), brk.mapped - mapped)
+                    .expect("failed to unmap the break");
+            }
+            brk.mapped = mapped;
+            return Err(Error::OutOfMemory);
+        }
+        brk.mapped += PAGE_SIZE;
+    }
+    brk.end = end;
+    Ok(VirtAddr::new(end))
+}
+
+/// live and zombie processes, init included
+pub fn count() -> usize {
+    TABLE.lock().processes.len()
+}
+
+#[test_case]
+fn test_spawn_wait() {
+    let pid = spawn("child", || exit(3)).unwrap();
+    assert_eq!(parent(pid), Some(INIT_PID));
+    assert_eq!(name(pid).as_deref(), Some("child"));
+    assert_eq!(waitpid(pid), Ok(3));
+    assert_eq!(state(pid), None);
+    assert_eq!(waitpid(pid), Err(Error::NoSuchProcess));
+    assert_eq!(wait(), Err(Error::NoChildren));
+}
+
+#[test_case]
+fn test_exit_status_of_first_exit() {
+    let pid = spawn("threads", || {
+        spawn_thread(|| exit(2)).unwrap().join();
+        // the process isn't a zombie until its last thread exited
+        assert_eq!(state(current_pid().unwrap()), Some(State::Running));
+        exit(1)
+    })
+    .unwrap();
+    assert_eq!(wait(), Ok((pid, 2)));
+}
+
+#[test_case]
+fn test_orphans_adopted_by_init() {
+    use crate::sync::Semaphore;
+    use core::sync::atomic::{AtomicU64, Ordering};
+    static GRANDCHILD: AtomicU64 = AtomicU64::new(0);
+    static PARENT_EXITED: Semaphore = Semaphore::new(0);
+    let pid = spawn("parent", || {
+        let grandchild = spawn("orphan", || PARENT_EXITED.acquire()).unwrap();
+        GRANDCHILD.store(grandchild, Ordering::Relaxed);
+    })
+    .unwrap();
+    assert_eq!(waitpid(pid), Ok(0));
+    let orphan = GRANDCHILD.load(Ordering::Relaxed);
+    assert_eq!(parent(orphan), Some(INIT_PID));
+    PARENT_EXITED.release();
+    // reaped without init waiting for it
+    while state(orphan).is_some() {
+        thread::yield_now();
+    }
+}
+
+#[test_case]
+fn test_break() {
+    // mov eax, BRK; xor edi, edi; syscall; lea rdi, [rax + 0x2000]; mov rbx, rax
+    // mov eax, BRK; syscall; mov qword [rbx + 0x1ff8], 5: the last word of the new pages
+    // mov rdi, rax; sub rdi, rbx; shr rdi, 12; add rdi, [rbx + 0x1ff8]; xor eax, eax; syscall
+    let binary = elf::build(
+        &[
+            0xb8, 0x09, 0x00, 0x00, 0x00, 0x31, 0xff, 0x0f, 0x05, 0x48, 0x8d, 0xb8, 0x00, 0x20,
+            0x00, 0x00, 0x48, 0x89, 0xc3, 0xb8, 0x09, 0x00, 0x00, 0x00, 0x0f, 0x05, 0x48, 0xc7,
+            0x83, 0xf8, 0x1f, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x48, 0x89, 0xc7, 0x48, 0x29,
+            0xdf, 0x48, 0xc1, 0xef, 0x0c, 0x48, 0x03, 0xbb, 0xf8, 0x1f, 0x00, 0x00, 0x31, 0xc0,
+            0x0f, 0x05,
+        ],
+        0,
+    );
+    let pid = spawn_elf("brk", &binary, &[], &[]).unwrap();
+    assert_eq!(waitpid(pid), Ok(2 + 5));
+    // not loaded from an executable
+    let pid = spawn("no brk", || {
+        assert_eq!(set_break(None), Err(Error::NotPermitted));
+    })
+    .unwrap();
+    assert_eq!(waitpid(pid), Ok(0));
+}
+
+#[test_case]
+fn test_break_out_of_memory() {
+    use address_space::USER_START;
+    let start = USER_START + 0x10_0000;
+    let brk = Break {
+        start,
+        end: start,
+        mapped: start,
+    };
+    let space = AddressSpace::new().unwrap();
+    let pid = spawn_in("huge brk", space, Some(brk), move || {
+        // more than there is, refused before mapping anything
+        let before = vmem::available_frames();
+        assert_eq!(
+            set_break(Some(VirtAddr::new(elf::HEAP_END))),
+            Err(Error::OutOfMemory)
+        );
+        assert_eq!(vmem::available_frames(), before);
+        assert_eq!(set_break(None), Ok(VirtAddr::new(start)));
+        let end = VirtAddr::new(start + 2 * PAGE_SIZE);
+        assert_eq!(set_break(Some(end)), Ok(end));
+    });
+    assert_eq!(waitpid(pid), Ok(0));
+}
@@ -0,0 +1,37 @@
+//! the example programs of sakuya, embedded in the kernel to run them
+//!
+//! They're built by `make userland`, `make test-userland` runs these tests.
+use super::{spawn_elf, waitpid};
+
+/// the executable of the sakuya example `name`
+macro_rules! program {
+    ($name:literal) => {
+        include_bytes!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/target/x86_64-remilia-user/release/examples/",
+            $name
+        ))
+    };
+}
+
+/// sakuya's exit status on panics
+const PANIC_EXIT_STATUS: i32 = 101;
+
+#[test_case]
+fn test_hello() {
+    let argv = ["hello", "Remilia", "Sakuya"];
+    let pid = spawn_elf("hello", program!("hello"), &argv, &["NAME=Patchouli"]).unwrap();
+    assert_eq!(waitpid(pid), Ok(2));
+}
+
+#[test_case]
+fn test_alloc() {
+    let pid = spawn_elf("alloc", program!("alloc"), &["alloc"], &[]).unwrap();
+    assert_eq!(waitpid(pid), Ok(0));
+}
+
+#[test_case]
+fn test_panic() {
+    let pid = spawn_elf("panic", program!("panic"), &["panic"], &[]).unwrap();
+    assert_eq!(waitpid(pid), Ok(PANIC_EXIT_STATUS));
+}
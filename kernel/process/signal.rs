@@ -0,0 +1,573 @@
+//! POSIX-like signals
+//!
+//! A signal sent to a process stays pending until one of its threads is about to return to
+//! user mode with the signal unblocked. It then takes the signal's action: the default one,
+//! which terminates, stops or ignores, or a handler. Handlers run on the user stack, on top of
+//! a frame saving the interrupted registers, and return to a trampoline calling `sigreturn`,
+//! which restores them.
+//!
+//! The masks and actions are shared by the threads of a process. Threads blocked in the kernel
+//! aren't interrupted, signals only take effect on the way back to user mode.
+use core::mem::{self, size_of};
+
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+use super::address_space::{AddressSpace, MapError, USER_END};
+use super::{current_pid, exit, Error, Pid, Process, State, INIT_PID, TABLE};
+use crate::gdt;
+use crate::interrupts::trap::TrapFrame;
+use crate::kdb::probe;
+use crate::sync::WaitQueue;
+use crate::syscall;
+use crate::thread;
+use crate::user;
+use crate::vmem::paging::PAGE_SIZE;
+
+/// signals are numbered from 1 to `NSIG - 1`
+pub const NSIG: usize = 32;
+/// where handlers return to, mapped in the processes which set one: it calls `sigreturn`
+pub const TRAMPOLINE: u64 = USER_END - PAGE_SIZE;
+/// below the user stack pointer, left alone by signal frames
+const RED_ZONE: u64 = 128;
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Signal {
+    SIGHUP = 1,
+    SIGINT = 2,
+    SIGQUIT = 3,
+    SIGILL = 4,
+    SIGTRAP = 5,
+    SIGABRT = 6,
+    SIGBUS = 7,
+    SIGFPE = 8,
+    SIGKILL = 9,
+    SIGUSR1 = 10,
+    SIGSEGV = 11,
+    SIGUSR2 = 12,
+    SIGPIPE = 13,
+    SIGALRM = 14,
+    SIGTERM = 15,
+    SIGSTKFLT = 16,
+    SIGCHLD = 17,
+    SIGCONT = 18,
+    SIGSTOP = 19,
+    SIGTSTP = 20,
+    SIGTTIN = 21,
+    SIGTTOU = 22,
+    SIGURG = 23,
+    SIGXCPU = 24,
+    SIGXFSZ = 25,
+    SIGVTALRM = 26,
+    SIGPROF = 27,
+    SIGWINCH = 28,
+    SIGIO = 29,
+    SIGPWR = 30,
+    SIGSYS = 31,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DefaultAction {
+    Terminate,
+    Ignore,
+    Stop,
+    /// resumes a stopped process, when sent
+    Continue,
+}
+
+impl Signal {
+    const ALL: [Signal; NSIG - 1] = {
+        use Signal::*;
+        [
+            SIGHUP, SIGINT, SIGQUIT, SIGILL, SIGTRAP, SIGABRT, SIGBUS, SIGFPE, SIGKILL, SIGUSR1,
+            SIGSEGV, SIGUSR2, SIGPIPE, SIGALRM, SIGTERM, SIGSTKFLT, SIGCHLD, SIGCONT, SIGSTOP,
+            SIGTSTP, SIGTTIN, SIGTTOU, SIGURG, SIGXCPU, SIGXFSZ, SIGVTALRM, SIGPROF, SIGWINCH,
+            SIGIO, SIGPWR, SIGSYS,
+        ]
+    };
+
+    pub fn from_number(number: u64) -> Option<Signal> {
+        let index = (number as usize).checked_sub(1)?;
+        Signal::ALL.get(index).copied()
+    }
+
+    fn default_action(self) -> DefaultAction {
+        use Signal::*;
+        match self {
+            SIGCHLD | SIGURG | SIGWINCH => DefaultAction::Ignore,
+            SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => DefaultAction::Stop,
+            SIGCONT => DefaultAction::Continue,
+            _ => DefaultAction::Terminate,
+        }
+    }
+}
+
+/// Exit status of a process ended by `signal`, like shells report it.
+pub const fn exit_status(signal: Signal) -> i32 {
+    128 + signal as i32
+}
+
+/// A set of signals, bit `n` for signal `n`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SigSet(u64);
+
+/// can't be blocked, caught nor ignored
+pub const UNBLOCKABLE: SigSet = SigSet::empty().with(Signal::SIGKILL).with(Signal::SIGSTOP);
+const STOP_SIGNALS: SigSet = SigSet::empty()
+    .with(Signal::SIGSTOP)
+    .with(Signal::SIGTSTP)
+    .with(Signal::SIGTTIN)
+    .with(Signal::SIGTTOU);
+
+impl SigSet {
+    pub const fn empty() -> SigSet {
+        SigSet(0)
+    }
+
+    /// The set with bits `bits`, those of no signal are dropped.
+    pub const fn from_bits(bits: u64) -> SigSet {
+        SigSet(bits & ((1 << NSIG) - 2))
+    }
+
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub const fn with(self, signal: Signal) -> SigSet {
+        SigSet(self.0 | 1 << signal as u64)
+    }
+
+    pub const fn contains(self, signal: Signal) -> bool {
+        self.0 & 1 << signal as u64 != 0
+    }
+
+    pub fn insert(&mut self, signal: Signal) {
+        *self = self.with(signal);
+    }
+
+    pub fn remove(&mut self, signal: Signal) {
+        self.0 &= !(1 << signal as u64);
+    }
+
+    pub const fn union(self, other: SigSet) -> SigSet {
+        SigSet(self.0 | other.0)
+    }
+
+    pub const fn difference(self, other: SigSet) -> SigSet {
+        SigSet(self.0 & !other.0)
+    }
+
+    /// the lowest numbered signal
+    fn first(self) -> Option<Signal> {
+        Signal::from_number(self.0.trailing_zeros() as u64)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handler {
+    Default,
+    Ignore,
+    /// called with the signal number, 0, and the address of the saved registers
+    Function(VirtAddr),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Action {
+    pub handler: Handler,
+    /// blocked while the handler runs, with the signal itself
+    pub mask: SigSet,
+}
+
+impl Default for Action {
+    fn default() -> Self {
+        Action {
+            handler: Handler::Default,
+            mask: SigSet::empty(),
+        }
+    }
+}
+
+/// How `set_blocked` changes the mask.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum How {
+    Block,
+    Unblock,
+    SetMask,
+}
+
+/// the signal state of a process
+#[derive(Default)]
+pub(super) struct Signals {
+    pending: SigSet,
+    blocked: SigSet,
+    /// indexed by signal number
+    actions: [Action; NSIG],
+    /// a signal terminated the process: the exit status is set, and `SIGKILL` pending for the
+    /// other threads
+    killed: bool,
+}
+
+impl Signals {
+    fn ignores(&self, signal: Signal) -> bool {
+        match self.actions[signal as usize].handler {
+            Handler::Ignore => true,
+            Handler::Default => matches!(
+                signal.default_action(),
+                DefaultAction::Ignore | DefaultAction::Continue
+            ),
+            Handler::Function(_) => false,
+        }
+    }
+}
+
+/// woken up whenever a stopped process continues
+static CONTINUED: WaitQueue = WaitQueue::new();
+
+impl Process {
+    /// Make `signal` pending, unless it's ignored. Returns whether the process continued.
+    pub(super) fn post(&mut self, signal: Signal) -> bool {
+        let mut continued = false;
+        match signal {
+            Signal::SIGCONT | Signal::SIGKILL => {
+                self.signals.pending = self.signals.pending.difference(STOP_SIGNALS);
+                if self.state == State::Stopped {
+                    self.state = State::Running;
+                    continued = true;
+                }
+            }
+            signal if STOP_SIGNALS.contains(signal) => self.signals.pending.remove(Signal::SIGCONT),
+            _ => {}
+        }
+        if !self.signals.ignores(signal) {
+            self.signals.pending.insert(signal);
+        }
+        continued
+    }
+}
+
+/// Send `signal` to the process `pid`.
+pub fn kill(pid: Pid, signal: Signal) -> Result<(), Error> {
+    if pid == INIT_PID {
+        // it runs the kernel
+        return Err(Error::NotPermitted);
+    }
+    let continued = {
+        let mut table = TABLE.lock();
+        let process = table
+            .processes
+            .get_mut(&pid)
+            .filter(|process| !matches!(process.state, State::Zombie(_)))
+            .ok_or(Error::NoSuchProcess)?;
+        process.post(signal)
+    };
+    if continued {
+        CONTINUED.wake_all();
+    }
+    Ok(())
+}
+
+/// Run `f` on the signal state of the current process, which mustn't be init.
+fn with_current<R>(f: impl FnOnce(&mut Process) -> Result<R, Error>) -> Result<R, Error> {
+    let pid = current_pid().ok_or(Error::NoSuchProcess)?;
+    if pid == INIT_PID {
+        return Err(Error::NotPermitted);
+    }
+    f(TABLE.lock().get_mut(pid))
+}
+
+/// Set the action of `signal` in the current process, returns the previous one.
+pub fn set_action(signal: Signal, action: Action) -> Result<Action, Error> {
+    if UNBLOCKABLE.contains(signal) {
+        return Err(Error::NotPermitted);
+    }
+    with_current(|process| {
+        if let Handler::Function(_) = action.handler {
+            let space = process.space.as_mut().ok_or(Error::NoSuchProcess)?;
+            map_trampoline(space).map_err(|_| Error::OutOfMemory)?;
+        }
+        let action = Action {
+            mask: action.mask.difference(UNBLOCKABLE),
+            ..action
+        };
+        let signals = &mut process.signals;
+        let previous = mem::replace(&mut signals.actions[signal as usize], action);
+        if signals.ignores(signal) {
+            signals.pending.remove(signal);
+        }
+        Ok(previous)
+    })
+}
+
+/// Change the signals blocked in the current process, returns the previous mask.
+pub fn set_blocked(how: How, set: SigSet) -> Result<SigSet, Error> {
+    with_current(|process| {
+        let signals = &mut process.signals;
+        let previous = signals.blocked;
+        let blocked = match how {
+            How::Block => previous.union(set),
+            How::Unblock => previous.difference(set),
+            How::SetMask => set,
+        };
+        signals.blocked = blocked.difference(UNBLOCKABLE);
+        Ok(previous)
+    })
+}
+
+/// signals sent to `pid` but not taken yet
+pub fn pending(pid: Pid) -> Option<SigSet> {
+    TABLE
+        .lock()
+        .processes
+        .get(&pid)
+        .map(|process| process.signals.pending)
+}
+
+fn map_trampoline(space: &mut AddressSpace) -> Result<(), MapError> {
+    let trampoline = VirtAddr::new(TRAMPOLINE);
+    if space.translate(trampoline).is_some() {
+        return Ok(());
+    }
+    // mov eax, SIGRETURN; syscall; ud2
+    let nr = (syscall::nr::SIGRETURN as u32).to_le_bytes();
+    let code = [0xb8, nr[0], nr[1], nr[2], nr[3], 0x0f, 0x05, 0x0f, 0x0b];
+    // read and execute only
+    space.map(trampoline, PAGE_SIZE, PageTableFlags::empty())?;
+    space.write(trampoline, &code)
+}
+
+/// Raise `signal` in the current process for a fault of its user code. Blocking or ignoring
+/// it would run the faulting code again, the default action is restored instead.
+/// Ends the thread right away if it's not in a process.
+pub(crate) fn force(signal: Signal) {
+    let pid = match current_pid() {
+        Some(pid) => pid,
+        None => thread::exit(),
+    };
+    let mut table = TABLE.lock();
+    let signals = &mut table.get_mut(pid).signals;
+    let action = &mut signals.actions[signal as usize];
+    if signals.blocked.contains(signal) || action.handler == Handler::Ignore {
+        action.handler = Handler::Default;
+        signals.blocked.remove(signal);
+    }
+    signals.pending.insert(signal);
+}
+
+enum Next {
+    /// nothing to deliver
+    Nothing,
+    Stopped,
+    /// the signal, its action and the mask before it was taken
+    Signal(Signal, Action, SigSet),
+}
+
+fn dequeue(pid: Pid) -> Next {
+    let mut table = TABLE.lock();
+    let process = table.get_mut(pid);
+    if process.state == State::Stopped {
+        return Next::Stopped;
+    }
+    let signals = &mut process.signals;
+    let signal = match signals.pending.difference(signals.blocked).first() {
+        Some(signal) => signal,
+        None => return Next::Nothing,
+    };
+    // `SIGKILL` stays pending, to end all the threads of the process
+    if signal != Signal::SIGKILL {
+        signals.pending.remove(signal);
+    }
+    Next::Signal(signal, signals.actions[signal as usize], signals.blocked)
+}
+
+/// Take the action of the pending signals of the current process, about to return to user mode
+/// with `frame`. Doesn't return if one ends the thread, and blocks while the process is
+/// stopped.
+pub(crate) fn deliver(frame: &mut TrapFrame) {
+    if !user::is_from_user(frame) {
+        return;
+    }
+    let pid = match current_pid() {
+        Some(pid) => pid,
+        None => return,
+    };
+    loop {
+        let (signal, action, blocked) = match dequeue(pid) {
+            Next::Nothing => return,
+            Next::Stopped => {
+                CONTINUED.wait_until(|| TABLE.lock().get_mut(pid).state != State::Stopped);
+                continue;
+            }
+            Next::Signal(signal, action, blocked) => (signal, action, blocked),
+        };
+        match action.handler {
+            Handler::Ignore => {}
+            Handler::Default => match signal.default_action() {
+                DefaultAction::Ignore | DefaultAction::Continue => {}
+                DefaultAction::Stop => TABLE.lock().get_mut(pid).state = State::Stopped,
+                DefaultAction::Terminate => terminate(pid, signal),
+            },
+            Handler::Function(handler) => {
+                if push_frame(frame, signal, handler, blocked).is_ok() {
+                    let mut table = TABLE.lock();
+                    let signals = &mut table.get_mut(pid).signals;
+                    let mask = action.mask.with(signal);
+                    signals.blocked = blocked.union(mask).difference(UNBLOCKABLE);
+                    return;
+                }
+                // the stack is unusable, a handler for it would fail the same way
+                if signal == Signal::SIGSEGV {
+                    terminate(pid, signal);
+                }
+                force(Signal::SIGSEGV);
+            }
+        }
+    }
+}
+
+/// End the process because of `signal`, starting with the running thread.
+fn terminate(pid: Pid, signal: Signal) -> ! {
+    let status = {
+        let mut table = TABLE.lock();
+        let process = table.get_mut(pid);
+        if !process.signals.killed {
+            process.signals.killed = true;
+            process.exit_status = Some(exit_status(signal));
+            process.signals.pending.insert(Signal::SIGKILL);
+        }
+        process.exit_status.unwrap()
+    };
+    exit(status)
+}
+
+/// What a handler finds on its stack, above the return address to the trampoline.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct SignalFrame {
+    /// the interrupted user registers
+    registers: TrapFrame,
+    /// the mask to restore
+    blocked: u64,
+}
+
+fn as_bytes(frame: &mut SignalFrame) -> &mut [u8] {
+    unsafe { core::slice::from_raw_parts_mut(frame as *mut _ as *mut u8, size_of::<SignalFrame>()) }
+}
+
+/// Save the registers of `frame` on its user stack, and change it to call `handler`.
+fn push_frame(
+    frame: &mut TrapFrame,
+    signal: Signal,
+    handler: VirtAddr,
+    blocked: SigSet,
+) -> Result<(), probe::Fault> {
+    let mut saved = SignalFrame {
+        registers: *frame,
+        blocked: blocked.bits(),
+    };
+    let size = size_of::<SignalFrame>() as u64;
+    let start = frame.rsp.checked_sub(RED_ZONE + size).ok_or(probe::Fault)? & !0xf;
+    // RSP + 8 is 16 bytes aligned at the start of a function
+    let return_address = start - 8;
+    let start = VirtAddr::try_new(start).map_err(|_| probe::Fault)?;
+    let return_address = VirtAddr::try_new(return_address).map_err(|_| probe::Fault)?;
+    user::copy_to_user(start, as_bytes(&mut saved))?;
+    user::copy_to_user(return_address, &TRAMPOLINE.to_le_bytes())?;
+    frame.rip = handler.as_u64();
+    frame.rsp = return_address.as_u64();
+    frame.rdi = signal as u64;
+    frame.rsi = 0;
+    frame.rdx = start.as_u64();
+    frame.rflags &= !(RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG).bits();
+    Ok(())
+}
+
+/// Restore the registers and the mask saved in the signal frame on the user stack, once the
+/// handler returned to the trampoline. Only the arithmetic flags and the direction flag can be
+/// changed in the saved RFLAGS, and the user segments are used whatever the saved ones.
+pub(crate) fn sigreturn(frame: &mut TrapFrame) {
+    let mut saved: SignalFrame = unsafe { mem::zeroed() };
+    let read = VirtAddr::try_new(frame.rsp)
+        .map_err(|_| probe::Fault)
+        .and_then(|start| user::copy_from_user(start, as_bytes(&mut saved)));
+    let valid_rip = VirtAddr::try_new(saved.registers.rip).map_or(false, user::is_user_address);
+    if read.is_err() || !valid_rip {
+        force(Signal::SIGSEGV);
+        return;
+    }
+    let user_flags = RFlags::CARRY_FLAG
+        | RFlags::PARITY_FLAG
+        | RFlags::AUXILIARY_CARRY_FLAG
+        | RFlags::ZERO_FLAG
+        | RFlags::SIGN_FLAG
+        | RFlags::DIRECTION_FLAG
+        | RFlags::OVERFLOW_FLAG;
+    let selectors = gdt::selectors();
+    *frame = TrapFrame {
+        vector: frame.vector,
+        error_code: frame.error_code,
+        cs: selectors.user_code.0 as u64,
+        ss: selectors.user_data.0 as u64,
+        rflags: (saved.registers.rflags & user_flags.bits()) | user::USER_RFLAGS,
+        ..saved.registers
+    };
+    let _ = with_current(|process| {
+        let blocked = SigSet::from_bits(saved.blocked).difference(UNBLOCKABLE);
+        process.signals.blocked = blocked;
+        Ok(())
+    });
+}
+
+/// A user process running an infinite loop.
+#[cfg(test)]
+fn spawn_loop() -> Pid {
+    // jmp $
+    let binary = super::elf::build(&[0xeb, 0xfe], 0);
+    super::spawn_elf("loop", &binary, &[], &[]).unwrap()
+}
+
+#[test_case]
+fn test_kill_terminates() {
+    let pid = spawn_loop();
+    assert_eq!(kill(pid, Signal::SIGTERM), Ok(()));
+    assert_eq!(super::waitpid(pid), Ok(exit_status(Signal::SIGTERM)));
+    assert_eq!(kill(pid, Signal::SIGTERM), Err(Error::NoSuchProcess));
+    assert_eq!(kill(INIT_PID, Signal::SIGTERM), Err(Error::NotPermitted));
+}
+
+#[test_case]
+fn test_stop_continue() {
+    let pid = spawn_loop();
+    // ignored by default
+    kill(pid, Signal::SIGCHLD).unwrap();
+    assert_eq!(pending(pid), Some(SigSet::empty()));
+    kill(pid, Signal::SIGSTOP).unwrap();
+    while super::state(pid) != Some(State::Stopped) {
+        thread::yield_now();
+    }
+    kill(pid, Signal::SIGCONT).unwrap();
+    assert_eq!(super::state(pid), Some(State::Running));
+    kill(pid, Signal::SIGKILL).unwrap();
+    assert_eq!(super::waitpid(pid), Ok(exit_status(Signal::SIGKILL)));
+}
+
+#[test_case]
+fn test_handler() {
+    // lea rsi, [rip + handler]; mov edi, SIGUSR1; xor edx, edx; mov eax, SIGACTION; syscall
+    // mov eax, GETPID; syscall; mov edi, eax; mov esi, SIGUSR1; mov eax, KILL; syscall
+    // mov edi, ebx; xor eax, eax; syscall: exit(rbx)
+    // handler: mov [rdx + 104], rdi; ret: sets the saved rbx to the signal number
+    let binary = super::elf::build(
+        &[
+            0x48, 0x8d, 0x35, 0x29, 0x00, 0x00, 0x00, 0xbf, 0x0a, 0x00, 0x00, 0x00, 0x31, 0xd2,
+            0xb8, 0x06, 0x00, 0x00, 0x00, 0x0f, 0x05, 0xb8, 0x03, 0x00, 0x00, 0x00, 0x0f, 0x05,
+            0x89, 0xc7, 0xbe, 0x0a, 0x00, 0x00, 0x00, 0xb8, 0x05, 0x00, 0x00, 0x00, 0x0f, 0x05,
+            0x89, 0xdf, 0x31, 0xc0, 0x0f, 0x05, 0x48, 0x89, 0x7a, 0x68, 0xc3,
+        ],
+        0,
+    );
+    let pid = super::spawn_elf("handler", &binary, &[], &[]).unwrap();
+    assert_eq!(super::waitpid(pid), Ok(Signal::SIGUSR1 as i32));
+}
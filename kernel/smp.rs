@@ -0,0 +1,363 @@
+//! application processors start up
+//!
+//! The local APICs listed in the MADT are woken up one at a time with INIT-SIPI-SIPI: they
+//! start in real mode at a trampoline page below 1MiB, which switches straight to long mode
+//! on the kernel's page tables, then jumps to `ap_main` on a stack of its own.
+//!
+//! An AP which doesn't show up in time is sent an INIT, which parks it until the next startup
+//! IPI, so what was allocated for it can be freed.
+//!
+//! Nothing is scheduled on the APs yet, they idle with interrupts disabled.
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::registers::control::{Cr0, Cr3, Cr4};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::{
+    mapper::MapToError, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::acpi::madt::{self, Entry};
+use crate::cpu::MAX_CPUS;
+use crate::gdt::{self, ApGdt};
+use crate::interrupts::{idt, lapic};
+use crate::percpu::{self, PerCpu};
+use crate::thread::STACK_SIZE;
+use crate::time::{self, Instant};
+use crate::vmem::{self, paging::PAGE_SIZE, KERNEL_SPACE};
+use crate::{info, warn};
+
+/// the CPUs which reached `ap_main`, and the bootstrap processor
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+/// how long an AP may take to reach `ap_main`
+const START_TIMEOUT: Duration = Duration::from_millis(100);
+
+global_asm!(
+    "
+.pushsection .rodata
+// copied to a page below 1MiB, where the APs start with CS:IP = page:0
+.code16
+.global ap_trampoline
+ap_trampoline:
+    cli
+    cld
+    mov ax, cs
+    mov ds, ax
+    lgdt [AP_GDTR]
+    mov eax, [AP_CR4]
+    mov cr4, eax
+    mov eax, [AP_CR3]
+    mov cr3, eax
+    mov ecx, 0xc0000080
+    mov eax, [AP_EFER]
+    xor edx, edx
+    wrmsr
+    // enabling protection and paging at once, with EFER.LME set, goes straight to long mode
+    mov eax, [AP_CR0]
+    mov cr0, eax
+    jmp fword ptr [AP_LONG_JUMP]
+.code64
+ap_long_mode:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    xor ax, ax
+    mov fs, ax
+    mov gs, ax
+    mov rsp, [rip + ap_stack]
+    mov rdi, [rip + ap_argument]
+    call [rip + ap_entry]
+    ud2
+.align 8
+ap_gdt:
+    .quad 0
+    // 64-bit code then data, like the kernel's GDT
+    .quad 0x00af9a000000ffff
+    .quad 0x00cf92000000ffff
+ap_gdtr:
+    .word ap_gdtr - ap_gdt - 1
+    // offsets until they are relocated, see `Trampoline::install`
+.global ap_gdt_base
+ap_gdt_base:
+    .long ap_gdt - ap_trampoline
+.global ap_long_jump
+ap_long_jump:
+    .long ap_long_mode - ap_trampoline
+    .word 0x08
+.align 8
+.global ap_cr0, ap_cr3, ap_cr4, ap_efer, ap_stack, ap_entry, ap_argument
+ap_cr0: .quad 0
+ap_cr3: .quad 0
+ap_cr4: .quad 0
+ap_efer: .quad 0
+ap_stack: .quad 0
+ap_entry: .quad 0
+ap_argument: .quad 0
+.global ap_trampoline_end
+ap_trampoline_end:
+.set AP_GDTR, ap_gdtr - ap_trampoline
+.set AP_CR0, ap_cr0 - ap_trampoline
+.set AP_CR3, ap_cr3 - ap_trampoline
+.set AP_CR4, ap_cr4 - ap_trampoline
+.set AP_EFER, ap_efer - ap_trampoline
+.set AP_LONG_JUMP, ap_long_jump - ap_trampoline
+.popsection
+"
+);
+
+extern "C" {
+    static ap_trampoline: u8;
+    static ap_trampoline_end: u8;
+    static ap_gdt_base: u8;
+    static ap_long_jump: u8;
+    static ap_cr0: u8;
+    static ap_cr3: u8;
+    static ap_cr4: u8;
+    static ap_efer: u8;
+    static ap_stack: u8;
+    static ap_entry: u8;
+    static ap_argument: u8;
+}
+
+/// what an AP needs to get going, handed over in `ap_argument`. Leaked once it's online.
+struct Start {
+    percpu: Box<PerCpu>,
+    gdt: Box<ApGdt>,
+    stack: Box<[u64]>,
+}
+
+/// the trampoline, copied in its page
+struct Trampoline {
+    frame: PhysFrame,
+}
+
+/// offset of `symbol` in the trampoline
+fn offset(symbol: &u8) -> u64 {
+    symbol as *const u8 as u64 - unsafe { &ap_trampoline as *const u8 as u64 }
+}
+
+fn trampoline_size() -> u64 {
+    offset(unsafe { &ap_trampoline_end })
+}
+
+impl Trampoline {
+    /// Copy the trampoline to `frame` and fill in what doesn't change from one AP to another.
+    fn install(frame: PhysFrame) -> Trampoline {
+        let trampoline = Trampoline { frame };
+        let base = frame.start_address().as_u64();
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                &ap_trampoline as *const u8,
+                vmem::phys_to_virt(frame.start_address()).as_mut_ptr(),
+                trampoline_size() as usize,
+            );
+            let relocate = |symbol: &u8| {
+                let at = trampoline.slot::<u32>(symbol);
+                at.write_unaligned(at.read_unaligned() + base as u32);
+            };
+            relocate(&ap_gdt_base);
+            relocate(&ap_long_jump);
+            // the APs load CR3 in real mode, from 32 bits
+            let (page_table, _) = Cr3::read();
+            assert!(page_table.start_address().as_u64() < 1 << 32);
+            trampoline.set(&ap_cr3, page_table.start_address().as_u64());
+            trampoline.set(&ap_cr4, Cr4::read_raw());
+            trampoline.set(&ap_cr0, Cr0::read_raw());
+            let efer = Efer::read() - EferFlags::LONG_MODE_ACTIVE;
+            trampoline.set(&ap_efer, efer.bits());
+            trampoline.set(
+                &ap_entry,
+                ap_main as extern "C" fn(&'static Start) -> ! as u64,
+            );
+        }
+        trampoline
+    }
+
+    /// SAFETY: `symbol` must be in the trampoline
+    unsafe fn slot<T>(&self, symbol: &u8) -> *mut T {
+        (vmem::phys_to_virt(self.frame.start_address()) + offset(symbol)).as_mut_ptr()
+    }
+
+    /// SAFETY: `symbol` must be one of the 64 bits slots, and no AP may be reading it
+    unsafe fn set(&self, symbol: &u8, value: u64) {
+        self.slot::<u64>(symbol).write_volatile(value);
+    }
+
+    /// the vector of the startup IPIs: the page number
+    fn vector(&self) -> u32 {
+        (self.frame.start_address().as_u64() / PAGE_SIZE) as u32
+    }
+}
+
+/// Find a page for the trampoline: below 1MiB, in the bootloader's memory which isn't used
+/// anymore.
+fn trampoline_frame(memory_map: &MemoryMap) -> Option<PhysFrame> {
+    const LOW_MEMORY_END: u64 = 0x10_0000;
+    memory_map
+        .iter()
+        .filter(|region| region.region_type == MemoryRegionType::Bootloader)
+        .find_map(|region| {
+            // the first page holds the real mode IVT and BIOS data
+            let start = PhysAddr::new(region.range.start_addr().max(PAGE_SIZE)).align_up(PAGE_SIZE);
+            let end = region.range.end_addr().min(LOW_MEMORY_END);
+            if start.as_u64() + PAGE_SIZE <= end {
+                Some(PhysFrame::containing_address(start))
+            } else {
+                None
+            }
+        })
+}
+
+/// The APs run the trampoline at its physical address, once they turned paging on.
+fn identity_map(frame: PhysFrame) -> Result<(), MapToError<Size4KiB>> {
+    let mut guard = KERNEL_SPACE.lock();
+    let space = guard.as_mut().expect("vmem::install wasn't called");
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+    match space.mapper.translate_addr(page.start_address()) {
+        // the bootloader maps itself
+        Some(phys) if phys == frame.start_address() => Ok(()),
+        Some(phys) => Err(MapToError::PageAlreadyMapped(
+            PhysFrame::containing_address(phys),
+        )),
+        None => unsafe {
+            space
+                .mapper
+                .map_to(page, frame, PageTableFlags::PRESENT, &mut space.frames)?
+                .flush();
+            Ok(())
+        },
+    }
+}
+
+/// Returns how many CPUs are running: the bootstrap processor and the started APs.
+pub fn online() -> usize {
+    ONLINE.load(Ordering::Acquire)
+}
+
+/// Start the application processors, after `init_acpi`, `init_lapic` and `init_time`.
+pub fn init_smp(memory_map: &MemoryMap) {
+    if !lapic::is_enabled() {
+        warn!("SMP: no local APIC");
+        return;
+    }
+    let apic_ids: Vec<u32> = match madt::entries() {
+        Some(entries) => entries
+            .filter_map(|entry| match entry {
+                Entry::LocalApic { apic_id, flags, .. } if flags & 1 != 0 => Some(apic_id as u32),
+                _ => None,
+            })
+            .collect(),
+        None => {
+            info!("SMP: no MADT, only running on the bootstrap processor");
+            return;
+        }
+    };
+    let bsp = lapic::id();
+    if apic_ids.iter().all(|&id| id == bsp) {
+        info!("SMP: 1 CPU online");
+        return;
+    }
+    let trampoline = match trampoline_frame(memory_map) {
+        Some(frame) => frame,
+        None => {
+            warn!("SMP: no page below 1MiB for the trampoline");
+            return;
+        }
+    };
+    if let Err(err) = identity_map(trampoline) {
+        warn!("SMP: failed to map the trampoline: {:?}", err);
+        return;
+    }
+    let trampoline = Trampoline::install(trampoline);
+    info!(
+        "SMP: trampoline @ {:p}, {} CPUs in the MADT",
+        trampoline.frame.start_address(),
+        apic_ids.len()
+    );
+
+    for apic_id in apic_ids.into_iter().filter(|&id| id != bsp) {
+        let before = online();
+        if before >= MAX_CPUS {
+            warn!("SMP: only using {} CPUs", MAX_CPUS);
+            break;
+        }
+        let start = Box::new(Start {
+            percpu: percpu::new_ap(before, apic_id),
+            gdt: gdt::new_ap(),
+            stack: vec![0; STACK_SIZE / 8].into_boxed_slice(),
+        });
+        let stack_top = (start.stack.as_ptr() as u64 + STACK_SIZE as u64) & !0xf;
+        unsafe {
+            trampoline.set(&ap_stack, stack_top);
+            trampoline.set(&ap_argument, &*start as *const Start as u64);
+            start_ap(apic_id, trampoline.vector());
+        }
+        let deadline = Instant::now() + START_TIMEOUT;
+        while online() == before && Instant::now() < deadline {
+            core::hint::spin_loop();
+        }
+        if online() == before {
+            // it could still start later, on `start`: park it before freeing it
+            unsafe { lapic::send_ipi(apic_id, lapic::ICR_INIT | lapic::ICR_ASSERT) };
+            time::spin_for(Duration::from_millis(10));
+            // it may have come up in the meantime, it's reset now anyway
+            ONLINE.store(before, Ordering::Release);
+            warn!("SMP: CPU with APIC id {} didn't start", apic_id);
+            drop(start);
+            continue;
+        }
+        // the AP runs on it for good
+        Box::leak(start);
+        info!("SMP: CPU {} (APIC id {}) online", before, apic_id);
+    }
+    info!("SMP: {} CPUs online", online());
+}
+
+/// INIT-SIPI-SIPI, with the delays of the MultiProcessor Specification
+///
+/// SAFETY: the trampoline must be ready for the AP
+unsafe fn start_ap(apic_id: u32, vector: u32) {
+    lapic::send_ipi(apic_id, lapic::ICR_INIT | lapic::ICR_ASSERT);
+    time::spin_for(Duration::from_millis(10));
+    for _ in 0..2 {
+        let before = online();
+        lapic::send_ipi(apic_id, lapic::ICR_STARTUP | lapic::ICR_ASSERT | vector);
+        time::spin_for(Duration::from_micros(200));
+        if online() != before {
+            break;
+        }
+    }
+}
+
+/// where the APs land from the trampoline, on their own stack. `start` is leaked once we're
+/// online, or we're reset by an INIT before it's freed.
+extern "C" fn ap_main(start: &'static Start) -> ! {
+    percpu::install(&start.percpu);
+    start.gdt.load();
+    idt::load_idt();
+    lapic::enable();
+    ONLINE.fetch_add(1, Ordering::AcqRel);
+    loop {
+        // only NMIs wake it up
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn test_trampoline_fits() {
+    assert!(trampoline_size() <= PAGE_SIZE);
+    assert!(offset(unsafe { &ap_argument }) + 8 <= trampoline_size());
+}
+
+#[test_case]
+fn test_online() {
+    assert!(online() >= 1);
+    assert!(online() <= MAX_CPUS);
+}
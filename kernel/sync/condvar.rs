@@ -0,0 +1,109 @@
+//! condition variables, used with `sync::Mutex`
+use core::time::Duration;
+
+use super::{MutexGuard, WaitQueue};
+use crate::time::Instant;
+
+pub struct Condvar {
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Condvar {
+        Condvar {
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Unlock the mutex and block until notified, then lock it again.
+    /// Wakeups can be spurious, the condition must be checked in a loop.
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        self.wait_inner(guard, None).0
+    }
+
+    /// Like `wait`, but gives up after `timeout`. Returns whether it timed out.
+    pub fn wait_timeout<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout: Duration,
+    ) -> (MutexGuard<'a, T>, bool) {
+        self.wait_inner(guard, Some(timeout))
+    }
+
+    /// Block until `condition` is false, returns the guard with it false.
+    pub fn wait_while<'a, T>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    fn wait_inner<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout: Option<Duration>,
+    ) -> (MutexGuard<'a, T>, bool) {
+        let mutex = guard.mutex();
+        let start = Instant::now();
+        // the mutex is released after we're in the queue: a notification sent as soon as
+        // it's unlocked reaches us.
+        let mut guard = Some(guard);
+        self.waiters.block_unless(
+            &mut || {
+                drop(guard.take());
+                false
+            },
+            timeout,
+        );
+        let timed_out = timeout.map_or(false, |timeout| start.elapsed() >= timeout);
+        (mutex.lock(), timed_out)
+    }
+
+    pub fn notify_one(&self) -> bool {
+        self.waiters.wake_one()
+    }
+
+    pub fn notify_all(&self) -> usize {
+        self.waiters.wake_all()
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test_case]
+fn test_condvar() {
+    use super::Mutex;
+    use crate::thread;
+    static QUEUE: Mutex<alloc::vec::Vec<u32>> = Mutex::new(alloc::vec::Vec::new());
+    static NOT_EMPTY: Condvar = Condvar::new();
+    let consumer = thread::spawn(|| {
+        let mut received = 0;
+        while received < 3 {
+            let mut queue = NOT_EMPTY.wait_while(QUEUE.lock(), |queue| queue.is_empty());
+            received += queue.drain(..).count();
+        }
+    });
+    for i in 0..3 {
+        QUEUE.lock().push(i);
+        NOT_EMPTY.notify_one();
+        thread::yield_now();
+    }
+    consumer.join();
+}
+
+#[test_case]
+fn test_condvar_timeout() {
+    use super::Mutex;
+    let mutex = Mutex::new(());
+    let condvar = Condvar::new();
+    let (_guard, timed_out) = condvar.wait_timeout(mutex.lock(), Duration::from_millis(5));
+    assert!(timed_out);
+}
@@ -0,0 +1,13 @@
+//! blocking synchronization, for threads
+//!
+//! Waiting threads sleep in a `WaitQueue` instead of spinning. None of these can be used from
+//! interrupt handlers or deferred work, which can't block: use `locked::IrqLocked` there.
+pub mod condvar;
+pub mod mutex;
+pub mod semaphore;
+pub mod wait_queue;
+
+pub use condvar::Condvar;
+pub use mutex::{Mutex, MutexGuard};
+pub use semaphore::Semaphore;
+pub use wait_queue::WaitQueue;
@@ -0,0 +1,144 @@
+//! mutual exclusion, sleeping while the lock is taken
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use super::WaitQueue;
+
+pub struct Mutex<T> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Mutex<T> {
+        Mutex {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        if self.try_acquire() {
+            Some(MutexGuard::new(self))
+        } else {
+            None
+        }
+    }
+    fn try_acquire(&self) -> bool {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    /// Block until the lock is acquired.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        self.waiters.wait_until(|| self.try_acquire());
+        MutexGuard::new(self)
+    }
+
+    /// Block until the lock is acquired, or `timeout` passed.
+    pub fn lock_timeout(&self, timeout: Duration) -> Option<MutexGuard<'_, T>> {
+        if self
+            .waiters
+            .wait_timeout_until(timeout, || self.try_acquire())
+        {
+            Some(MutexGuard::new(self))
+        } else {
+            None
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    fn unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+        self.waiters.wake_one();
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+    /// `&Mutex<T>` is `Sync` for any `T: Send`, but the guard hands out `&T`
+    _marker: PhantomData<*const ()>,
+}
+
+unsafe impl<T: Sync> Sync for MutexGuard<'_, T> {}
+
+impl<'a, T> MutexGuard<'a, T> {
+    fn new(mutex: &'a Mutex<T>) -> MutexGuard<'a, T> {
+        MutexGuard {
+            mutex,
+            _marker: PhantomData,
+        }
+    }
+    /// the mutex this guard locks, to lock it again after `Condvar::wait`
+    pub(super) fn mutex(&self) -> &'a Mutex<T> {
+        self.mutex
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+#[test_case]
+fn test_mutex_contention() {
+    use crate::thread;
+    static COUNTER: Mutex<u64> = Mutex::new(0);
+    let handles: alloc::vec::Vec<_> = (0..4)
+        .map(|_| {
+            thread::spawn(|| {
+                for _ in 0..100 {
+                    let mut counter = COUNTER.lock();
+                    let value = *counter;
+                    // give the others a chance to see the lock taken
+                    thread::yield_now();
+                    *counter = value + 1;
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join();
+    }
+    assert_eq!(*COUNTER.lock(), 400);
+}
+
+#[test_case]
+fn test_lock_timeout() {
+    let mutex = Mutex::new(());
+    let guard = mutex.lock();
+    assert!(mutex.try_lock().is_none());
+    assert!(mutex.lock_timeout(Duration::from_millis(5)).is_none());
+    drop(guard);
+    assert!(mutex.lock_timeout(Duration::from_millis(5)).is_some());
+}
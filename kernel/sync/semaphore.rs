@@ -0,0 +1,65 @@
+//! counting semaphore
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+
+use super::WaitQueue;
+
+pub struct Semaphore {
+    permits: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            permits: AtomicUsize::new(permits),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        self.permits
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |permits| {
+                permits.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    /// Block until a permit is available, and take it.
+    pub fn acquire(&self) {
+        self.waiters.wait_until(|| self.try_acquire());
+    }
+
+    /// Block until a permit is available or `timeout` passed, returns whether one was taken.
+    pub fn acquire_timeout(&self, timeout: Duration) -> bool {
+        self.waiters
+            .wait_timeout_until(timeout, || self.try_acquire())
+    }
+
+    pub fn release(&self) {
+        self.permits.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    pub fn available(&self) -> usize {
+        self.permits.load(Ordering::Relaxed)
+    }
+}
+
+#[test_case]
+fn test_semaphore() {
+    use crate::thread;
+    static ITEMS: Semaphore = Semaphore::new(0);
+    let consumer = thread::spawn(|| {
+        for _ in 0..3 {
+            ITEMS.acquire();
+        }
+    });
+    for _ in 0..3 {
+        thread::yield_now();
+        ITEMS.release();
+    }
+    consumer.join();
+    assert_eq!(ITEMS.available(), 0);
+    assert!(!ITEMS.acquire_timeout(Duration::from_millis(5)));
+}
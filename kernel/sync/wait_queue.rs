@@ -0,0 +1,143 @@
+//! threads waiting for a condition
+use alloc::vec::Vec;
+use core::time::Duration;
+
+use x86_64::instructions::interrupts;
+
+use crate::cpu;
+use crate::locked::IrqLocked;
+use crate::thread::{self, preempt, scheduler, ThreadId};
+use crate::time::{Instant, Timer};
+
+/// FIFO of blocked threads.
+///
+/// Waiters always check their condition again when woken up, so spurious wakeups are fine.
+pub struct WaitQueue {
+    waiters: IrqLocked<Vec<ThreadId>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> WaitQueue {
+        WaitQueue {
+            waiters: IrqLocked::new(Vec::new()),
+        }
+    }
+
+    /// Block until `condition` holds. It's checked with the queue locked, so a thread
+    /// making it true then waking the queue can't be missed.
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        while !self.block_unless(&mut condition, None) {}
+    }
+
+    /// Block until `condition` holds, or `timeout` passed. Returns whether the condition holds.
+    pub fn wait_timeout_until(
+        &self,
+        timeout: Duration,
+        mut condition: impl FnMut() -> bool,
+    ) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let left = deadline.duration_since(Instant::now());
+            if left == Duration::ZERO {
+                return self.block_unless(&mut condition, Some(left));
+            }
+            if self.block_unless(&mut condition, Some(left)) {
+                return true;
+            }
+        }
+    }
+
+    /// Block once, unless `condition` holds: returns true without blocking if it does.
+    /// Also woken up after `timeout`, a zero timeout only checks the condition.
+    pub(super) fn block_unless(
+        &self,
+        condition: &mut dyn FnMut() -> bool,
+        timeout: Option<Duration>,
+    ) -> bool {
+        debug_assert!(preempt::is_enabled(), "blocking with preemption disabled");
+        interrupts::without_interrupts(|| {
+            let id = thread::current_id();
+            {
+                let mut waiters = self.waiters.lock();
+                if condition() {
+                    return true;
+                }
+                if timeout == Some(Duration::ZERO) {
+                    return false;
+                }
+                waiters.push(id);
+            }
+            // nothing can wake us up before we block: interrupts are disabled, and the
+            // application processors don't run threads
+            assert_eq!(cpu::id(), 0, "blocking on an application processor");
+            let timer = timeout.map(|timeout| {
+                Timer::after(timeout, move || {
+                    scheduler::wake(id);
+                })
+            });
+            scheduler::block();
+            if let Some(timer) = timer {
+                timer.cancel();
+            }
+            // still there if woken up by the timer
+            self.waiters.lock().retain(|&waiter| waiter != id);
+            false
+        })
+    }
+
+    /// Wake the longest waiting thread, returns whether there was one.
+    pub fn wake_one(&self) -> bool {
+        let mut waiters = self.waiters.lock();
+        while !waiters.is_empty() {
+            // it might have been woken up by its timeout already
+            if scheduler::wake(waiters.remove(0)) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Wake all the waiting threads, returns how many there were.
+    pub fn wake_all(&self) -> usize {
+        let mut waiters = self.waiters.lock();
+        waiters
+            .drain(..)
+            .filter(|&waiter| scheduler::wake(waiter))
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiters.lock().is_empty()
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test_case]
+fn test_wait_wake() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+    static QUEUE: WaitQueue = WaitQueue::new();
+    static READY: AtomicBool = AtomicBool::new(false);
+    let waiter = thread::spawn(|| QUEUE.wait_until(|| READY.load(Ordering::Relaxed)));
+    // let it block
+    thread::yield_now();
+    assert!(!QUEUE.is_empty());
+    READY.store(true, Ordering::Relaxed);
+    assert!(QUEUE.wake_one());
+    waiter.join();
+    assert!(QUEUE.is_empty());
+}
+
+#[test_case]
+fn test_wait_timeout() {
+    static QUEUE: WaitQueue = WaitQueue::new();
+    let start = Instant::now();
+    assert!(!QUEUE.wait_timeout_until(Duration::from_millis(10), || false));
+    assert!(start.elapsed() >= Duration::from_millis(10));
+    assert!(QUEUE.is_empty());
+    assert!(QUEUE.wait_timeout_until(Duration::from_millis(10), || true));
+}
@@ -0,0 +1,130 @@
+//! the SYSCALL entry point, and the `int 0x80` gate
+//!
+//! SYSCALL leaves the user stack in place: the stub finds the thread's kernel stack in the CPU's
+//! `PerCpu` block, reached through GS after a `swapgs`. It builds a `TrapFrame` like the
+//! interrupt stubs do, so both entries share `handle`, and returns with SYSRET. A frame restored by
+//! `sigreturn` goes back through `trap_return` instead: SYSRET can't restore RCX and R11.
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+use super::handle;
+use crate::gdt;
+use crate::info;
+use crate::interrupts::trap::{self, TrapFrame};
+use crate::percpu;
+use crate::process::signal;
+
+/// vector of the `int 0x80` gate, also the `vector` of the frames built by the SYSCALL stub
+pub const SYSCALL_VECTOR: u8 = 0x80;
+
+// the selectors are hardcoded in the stub, like the offsets in `PerCpu`
+const USER_DATA_SELECTOR: u16 = 0x1b;
+const USER_CODE_SELECTOR: u16 = 0x23;
+
+global_asm!(
+    "
+.pushsection .text
+.global syscall_entry
+syscall_entry:
+    swapgs
+    mov gs:[16], rsp
+    mov rsp, gs:[8]
+    push 0x1b
+    push qword ptr gs:[16]
+    push r11
+    push 0x23
+    push rcx
+    push 0
+    push 0x80
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    mov rdi, rsp
+    cld
+    call syscall_dispatch
+    test al, al
+    jnz trap_return
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    add rsp, 16
+    // SYSRET takes RIP from RCX and RFLAGS from R11, interrupts are disabled until then
+    pop rcx
+    add rsp, 8
+    pop r11
+    swapgs
+    pop rsp
+    sysretq
+.popsection
+"
+);
+
+extern "C" {
+    fn syscall_entry();
+}
+
+/// Returns whether all the registers must be restored from the frame.
+#[no_mangle]
+extern "C" fn syscall_dispatch(frame: &mut TrapFrame) -> bool {
+    let restored = handle(frame);
+    // `trap_dispatch` does it for `int 0x80`
+    signal::deliver(frame);
+    restored
+}
+
+fn int80_handler(frame: &mut TrapFrame) {
+    handle(frame);
+}
+
+/// Enable SYSCALL, and the `int 0x80` gate. The IDT already allows it from ring 3.
+pub fn init_syscalls() {
+    let selectors = gdt::selectors();
+    assert_eq!(selectors.user_data.0, USER_DATA_SELECTOR);
+    assert_eq!(selectors.user_code.0, USER_CODE_SELECTOR);
+    Star::write(
+        selectors.user_code,
+        selectors.user_data,
+        selectors.kernel_code,
+        selectors.kernel_data,
+    )
+    .expect("the GDT doesn't suit SYSCALL");
+    LStar::write(VirtAddr::new(
+        syscall_entry as unsafe extern "C" fn() as u64,
+    ));
+    // the stub runs with interrupts disabled until it's on the kernel stack
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG);
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+    trap::register(SYSCALL_VECTOR, int80_handler);
+    info!("syscalls enabled");
+}
+
+/// Set the stack the SYSCALL stub switches to, see `user::set_kernel_stack`.
+pub(crate) fn set_kernel_stack(top: VirtAddr) {
+    // only read by the stub, with interrupts disabled
+    percpu::current().set_kernel_rsp(top.as_u64());
+}
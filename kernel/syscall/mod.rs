@@ -0,0 +1,345 @@
+//! system calls
+//!
+//! The number is passed in RAX and the arguments in RDI, RSI, RDX, R10, R8 and R9, like Linux.
+//! The result is returned in RAX: a value, or a negated `Errno`.
+pub mod entry;
+
+use alloc::vec;
+use core::str;
+
+use x86_64::instructions::interrupts;
+use x86_64::VirtAddr;
+
+pub use entry::{init_syscalls, SYSCALL_VECTOR};
+
+use crate::interrupts::trap::TrapFrame;
+use crate::process::signal::{self, Action, Handler, How, SigSet, Signal};
+use crate::process::{self, Pid};
+use crate::thread;
+use crate::time::Instant;
+use crate::user;
+use crate::{print, vgaprint};
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum Errno {
+    EPERM = 1,
+    ESRCH = 3,
+    EINTR = 4,
+    ENOEXEC = 8,
+    EBADF = 9,
+    ECHILD = 10,
+    EAGAIN = 11,
+    ENOMEM = 12,
+    EFAULT = 14,
+    EINVAL = 22,
+    ENOSYS = 38,
+}
+
+pub type SyscallResult = Result<u64, Errno>;
+
+impl From<process::Error> for Errno {
+    fn from(err: process::Error) -> Errno {
+        match err {
+            process::Error::NoSuchProcess => Errno::ESRCH,
+            process::Error::NoChildren => Errno::ECHILD,
+            process::Error::OutOfMemory => Errno::ENOMEM,
+            process::Error::InvalidExecutable(_) => Errno::ENOEXEC,
+            process::Error::NotPermitted => Errno::EPERM,
+        }
+    }
+}
+
+fn encode(result: SyscallResult) -> u64 {
+    match result {
+        Ok(value) => value,
+        Err(errno) => (-(errno as i64)) as u64,
+    }
+}
+
+/// Decoding of a syscall argument from its register.
+pub trait FromArg: Sized {
+    fn from_arg(value: u64) -> Result<Self, Errno>;
+}
+
+impl FromArg for u64 {
+    fn from_arg(value: u64) -> Result<Self, Errno> {
+        Ok(value)
+    }
+}
+impl FromArg for usize {
+    fn from_arg(value: u64) -> Result<Self, Errno> {
+        Ok(value as usize)
+    }
+}
+/// 32 bits arguments only use the lower half of the register
+impl FromArg for u32 {
+    fn from_arg(value: u64) -> Result<Self, Errno> {
+        Ok(value as u32)
+    }
+}
+impl FromArg for i32 {
+    fn from_arg(value: u64) -> Result<Self, Errno> {
+        Ok(value as i32)
+    }
+}
+
+/// An address in user space, not checked to be mapped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserAddr(pub VirtAddr);
+
+impl FromArg for UserAddr {
+    fn from_arg(value: u64) -> Result<Self, Errno> {
+        match VirtAddr::try_new(value) {
+            Ok(addr) if user::is_user_address(addr) => Ok(UserAddr(addr)),
+            _ => Err(Errno::EFAULT),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clock {
+    /// since boot
+    Monotonic,
+    /// since the unix epoch
+    Realtime,
+}
+
+impl FromArg for Clock {
+    fn from_arg(value: u64) -> Result<Self, Errno> {
+        match value {
+            0 => Ok(Clock::Monotonic),
+            1 => Ok(Clock::Realtime),
+            _ => Err(Errno::EINVAL),
+        }
+    }
+}
+
+impl FromArg for Signal {
+    fn from_arg(value: u64) -> Result<Self, Errno> {
+        Signal::from_number(value).ok_or(Errno::EINVAL)
+    }
+}
+
+impl FromArg for SigSet {
+    fn from_arg(value: u64) -> Result<Self, Errno> {
+        Ok(SigSet::from_bits(value))
+    }
+}
+
+/// the default action of a signal, for `SIGACTION`
+pub const SIG_DFL: u64 = 0;
+/// ignores a signal, for `SIGACTION`
+pub const SIG_IGN: u64 = 1;
+
+/// `SIG_DFL`, `SIG_IGN` or the address of a function
+impl FromArg for Handler {
+    fn from_arg(value: u64) -> Result<Self, Errno> {
+        match value {
+            SIG_DFL => Ok(Handler::Default),
+            SIG_IGN => Ok(Handler::Ignore),
+            addr => UserAddr::from_arg(addr).map(|addr| Handler::Function(addr.0)),
+        }
+    }
+}
+
+impl FromArg for How {
+    fn from_arg(value: u64) -> Result<Self, Errno> {
+        match value {
+            0 => Ok(How::Block),
+            1 => Ok(How::Unblock),
+            2 => Ok(How::SetMask),
+            _ => Err(Errno::EINVAL),
+        }
+    }
+}
+
+/// Declares the syscall numbers, and `dispatch` decoding the arguments of each handler.
+macro_rules! syscalls {
+    ($($name:ident = $nr:literal => $handler:ident($($arg:ident: $ty:ty),*);)*) => {
+        pub mod nr {
+            $(pub const $name: u64 = $nr;)*
+        }
+
+        pub fn name(nr: u64) -> Option<&'static str> {
+            match nr {
+                $(nr::$name => Some(stringify!($name)),)*
+                _ => None,
+            }
+        }
+
+        fn dispatch(nr: u64, args: [u64; 6]) -> SyscallResult {
+            match nr {
+                $(nr::$name => {
+                    let mut _args = args.iter().copied();
+                    $(let $arg = <$ty as FromArg>::from_arg(_args.next().unwrap())?;)*
+                    $handler($($arg),*)
+                })*
+                _ => Err(Errno::ENOSYS),
+            }
+        }
+    };
+}
+
+syscalls! {
+    EXIT = 0 => sys_exit(status: i32);
+    WRITE = 1 => sys_write(fd: u32, buf: UserAddr, len: usize);
+    YIELD = 2 => sys_yield();
+    GETPID = 3 => sys_getpid();
+    TIME = 4 => sys_time(clock: Clock);
+    KILL = 5 => sys_kill(pid: u64, signal: Signal);
+    SIGACTION = 6 => sys_sigaction(signal: Signal, handler: Handler, mask: SigSet);
+    SIGPROCMASK = 7 => sys_sigprocmask(how: How, set: SigSet);
+    SIGRETURN = 8 => sys_sigreturn();
+    BRK = 9 => sys_brk(end: u64);
+}
+
+/// Run the syscall described by `frame`, from the SYSCALL stub or `int 0x80`.
+/// Returns whether it changed more than RAX in the frame.
+fn handle(frame: &mut TrapFrame) -> bool {
+    // syscalls can block
+    interrupts::enable();
+    let restored = if frame.rax == nr::SIGRETURN {
+        signal::sigreturn(frame);
+        true
+    } else {
+        let args = [
+            frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9,
+        ];
+        frame.rax = encode(dispatch(frame.rax, args));
+        false
+    };
+    interrupts::disable();
+    restored
+}
+
+fn sys_exit(status: i32) -> SyscallResult {
+    match process::current_pid() {
+        Some(_) => process::exit(status),
+        None => thread::exit(),
+    }
+}
+
+/// most bytes written at once
+const MAX_WRITE: usize = 4096;
+
+fn sys_write(fd: u32, buf: UserAddr, len: usize) -> SyscallResult {
+    if fd != 1 && fd != 2 {
+        return Err(Errno::EBADF);
+    }
+    let mut bytes = vec![0; len.min(MAX_WRITE)];
+    user::copy_from_user(buf.0, &mut bytes).map_err(|_| Errno::EFAULT)?;
+    let text = match str::from_utf8(&bytes) {
+        Ok(text) => text,
+        // a character cut by `MAX_WRITE`, it's written by the next call
+        Err(err) if err.error_len().is_none() && err.valid_up_to() > 0 => unsafe {
+            str::from_utf8_unchecked(&bytes[..err.valid_up_to()])
+        },
+        Err(_) => return Err(Errno::EINVAL),
+    };
+    vgaprint!("{}", text);
+    print!("{}", text);
+    Ok(text.len() as u64)
+}
+
+fn sys_yield() -> SyscallResult {
+    thread::yield_now();
+    Ok(0)
+}
+
+/// 0 for kernel threads
+fn sys_getpid() -> SyscallResult {
+    Ok(process::current_pid().unwrap_or(0))
+}
+
+fn sys_kill(pid: Pid, signal: Signal) -> SyscallResult {
+    signal::kill(pid, signal)?;
+    Ok(0)
+}
+
+/// Returns the previous handler.
+fn sys_sigaction(signal: Signal, handler: Handler, mask: SigSet) -> SyscallResult {
+    let previous =
+        signal::set_action(signal, Action { handler, mask }).map_err(|err| match err {
+            process::Error::NotPermitted => Errno::EINVAL,
+            err => err.into(),
+        })?;
+    Ok(match previous.handler {
+        Handler::Default => SIG_DFL,
+        Handler::Ignore => SIG_IGN,
+        Handler::Function(addr) => addr.as_u64(),
+    })
+}
+
+/// Returns the previous mask.
+fn sys_sigprocmask(how: How, set: SigSet) -> SyscallResult {
+    Ok(signal::set_blocked(how, set)?.bits())
+}
+
+/// `handle` runs it, it needs the frame
+fn sys_sigreturn() -> SyscallResult {
+    unreachable!("sigreturn goes through handle")
+}
+
+/// Move the program break to `end`, or only return it if 0. Returns the new break.
+fn sys_brk(end: u64) -> SyscallResult {
+    let end = match end {
+        0 => None,
+        end => Some(VirtAddr::try_new(end).map_err(|_| Errno::ENOMEM)?),
+    };
+    match process::set_break(end) {
+        Ok(end) => Ok(end.as_u64()),
+        Err(process::Error::NoSuchProcess) => Err(Errno::ESRCH),
+        Err(_) => Err(Errno::ENOMEM),
+    }
+}
+
+/// in nanoseconds
+fn sys_time(clock: Clock) -> SyscallResult {
+    match clock {
+        Clock::Monotonic => Ok(Instant::now().as_nanos()),
+        Clock::Realtime => crate::devices::rtc::now_ms()
+            .map(|ms| ms * 1_000_000)
+            .ok_or(Errno::EAGAIN),
+    }
+}
+
+#[test_case]
+fn test_syscall_getpid() {
+    // mov eax, GETPID; syscall; mov edi, eax; mov eax, EXIT; syscall
+    let status = user::run_user(&[
+        0xb8, 0x03, 0x00, 0x00, 0x00, 0x0f, 0x05, 0x89, 0xc7, 0xb8, 0x00, 0x00, 0x00, 0x00, 0x0f,
+        0x05,
+    ]);
+    // the pids are above init's
+    assert!(status > process::INIT_PID as i32);
+}
+
+#[test_case]
+fn test_int80_write() {
+    // mov eax, WRITE; mov edi, 1; lea rsi, [rip + 13]; mov edx, 3; int 0x80
+    // mov edi, eax; xor eax, eax; int 0x80; "hi\n"
+    let status = user::run_user(&[
+        0xb8, 0x01, 0x00, 0x00, 0x00, 0xbf, 0x01, 0x00, 0x00, 0x00, 0x48, 0x8d, 0x35, 0x0d, 0x00,
+        0x00, 0x00, 0xba, 0x03, 0x00, 0x00, 0x00, 0xcd, 0x80, 0x89, 0xc7, 0x31, 0xc0, 0xcd, 0x80,
+        b'h', b'i', b'\n',
+    ]);
+    assert_eq!(status, 3);
+}
+
+#[test_case]
+fn test_syscall_errors() {
+    // mov eax, WRITE; mov edi, 1; xor esi, esi; mov edx, 3; syscall
+    // mov edi, eax; xor eax, eax; syscall
+    let status = user::run_user(&[
+        0xb8, 0x01, 0x00, 0x00, 0x00, 0xbf, 0x01, 0x00, 0x00, 0x00, 0x31, 0xf6, 0xba, 0x03, 0x00,
+        0x00, 0x00, 0x0f, 0x05, 0x89, 0xc7, 0x31, 0xc0, 0x0f, 0x05,
+    ]);
+    assert_eq!(status, -(Errno::EFAULT as i32));
+    // mov eax, 999; syscall; mov edi, eax; xor eax, eax; syscall
+    let status = user::run_user(&[
+        0xb8, 0xe7, 0x03, 0x00, 0x00, 0x0f, 0x05, 0x89, 0xc7, 0x31, 0xc0, 0x0f, 0x05,
+    ]);
+    assert_eq!(status, -(Errno::ENOSYS as i32));
+}
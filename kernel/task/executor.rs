@@ -0,0 +1,191 @@
+//! runs `Task`s until they complete
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::future::Future;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Waker};
+
+use pache::ring::Ring;
+
+use super::{Task, TaskId};
+use crate::{thread, time};
+
+/// most tasks an executor runs at once, each is in the queue at most once
+pub const MAX_TASKS: usize = 256;
+
+type TaskQueue = Ring<TaskId, MAX_TASKS>;
+
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+    /// woken up tasks
+    queue: Arc<TaskQueue>,
+    wakers: BTreeMap<TaskId, Arc<TaskWaker>>,
+}
+
+impl Executor {
+    pub fn new() -> Executor {
+        Executor {
+            tasks: BTreeMap::new(),
+            queue: Arc::new(Ring::new()),
+            wakers: BTreeMap::new(),
+        }
+    }
+
+    /// Add a task, it's polled once the executor runs.
+    pub fn spawn(&mut self, future: impl Future<Output = ()> + Send + 'static) -> TaskId {
+        assert!(self.tasks.len() < MAX_TASKS, "too many tasks");
+        let task = Task::new(future);
+        let id = task.id;
+        self.tasks.insert(id, task);
+        let waker = Arc::new(TaskWaker {
+            id,
+            queued: AtomicBool::new(false),
+            queue: self.queue.clone(),
+        });
+        waker.schedule();
+        self.wakers.insert(id, waker);
+        id
+    }
+
+    /// number of tasks that didn't complete yet
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    /// Poll the woken up tasks, until none is.
+    pub fn run_ready(&mut self) {
+        while let Some(id) = self.queue.pop() {
+            let task = match self.tasks.get_mut(&id) {
+                Some(task) => task,
+                // woken up after it completed
+                None => continue,
+            };
+            let task_waker = &self.wakers[&id];
+            // woken up while it's polled: it's queued again
+            task_waker.queued.store(false, Ordering::Release);
+            let waker = Waker::from(task_waker.clone());
+            if task.poll(&mut Context::from_waker(&waker)).is_ready() {
+                self.tasks.remove(&id);
+                self.wakers.remove(&id);
+            }
+        }
+    }
+
+    /// Run until all the tasks completed, halting while none is woken up.
+    pub fn run(&mut self) {
+        loop {
+            self.run_ready();
+            if self.tasks.is_empty() {
+                return;
+            }
+            self.sleep_if_idle();
+        }
+    }
+
+    fn sleep_if_idle(&self) {
+        if thread::has_ready() {
+            thread::yield_now();
+        } else {
+            // IRQ handlers and the deferred work run at their end wake tasks before we're back
+            // from the hlt, and the queue is checked with interrupts disabled, so no wakeup
+            // can be missed between the check and the hlt.
+            time::idle_unless(|| !self.queue.is_empty());
+        }
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct TaskWaker {
+    id: TaskId,
+    /// whether the task is in the queue already
+    queued: AtomicBool,
+    queue: Arc<TaskQueue>,
+}
+
+impl TaskWaker {
+    /// Queue the task unless it already is. Doesn't allocate, so it's safe from IRQ handlers.
+    fn schedule(&self) {
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            // can't be full: there are at most `MAX_TASKS` tasks, each queued at most once
+            if self.queue.push(self.id).is_err() {
+                panic!("task queue full");
+            }
+        }
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.schedule();
+    }
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.schedule();
+    }
+}
+
+#[test_case]
+fn test_tasks_interleave() {
+    use crate::locked::IrqLocked;
+    use alloc::vec::Vec;
+    static TRACE: IrqLocked<Vec<(u32, u32)>> = IrqLocked::new(Vec::new());
+    let mut executor = Executor::new();
+    for n in 0..2 {
+        executor.spawn(async move {
+            for i in 0..3 {
+                TRACE.lock().push((n, i));
+                super::yield_now().await;
+            }
+        });
+    }
+    assert_eq!(executor.len(), 2);
+    executor.run();
+    assert!(executor.is_empty());
+    assert_eq!(
+        *TRACE.lock(),
+        [(0, 0), (1, 0), (0, 1), (1, 1), (0, 2), (1, 2)]
+    );
+}
+
+#[test_case]
+fn test_woken_by_timer() {
+    use super::AtomicWaker;
+    use crate::time::{Instant, Timer};
+    use core::pin::Pin;
+    use core::task::Poll;
+    use core::time::Duration;
+
+    static WAKER: AtomicWaker = AtomicWaker::new();
+    static FIRED: AtomicBool = AtomicBool::new(false);
+    struct Fired;
+    impl Future for Fired {
+        type Output = ();
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            WAKER.register(cx.waker());
+            if FIRED.load(Ordering::Acquire) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        }
+    }
+    let start = Instant::now();
+    let mut executor = Executor::new();
+    executor.spawn(Fired);
+    Timer::after(Duration::from_millis(5), || {
+        FIRED.store(true, Ordering::Release);
+        WAKER.wake();
+    });
+    // halts until the timer wakes the task
+    executor.run();
+    assert!(start.elapsed() >= Duration::from_millis(5));
+    WAKER.take();
+}
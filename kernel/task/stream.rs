@@ -0,0 +1,162 @@
+//! asynchronous sequences of values
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll};
+
+use pache::ring::Ring;
+
+use super::AtomicWaker;
+
+/// The asynchronous version of `Iterator`.
+pub trait Stream {
+    type Item;
+    /// `Ready(None)` once the stream ended. When `Pending`, the task is woken up once a
+    /// value is available.
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>>;
+}
+
+pub trait StreamExt: Stream {
+    /// Next value of the stream, `None` once it ended.
+    fn next(&mut self) -> Next<'_, Self>
+    where
+        Self: Unpin,
+    {
+        Next { stream: self }
+    }
+}
+
+impl<S: Stream + ?Sized> StreamExt for S {}
+
+pub struct Next<'a, S: ?Sized> {
+    stream: &'a mut S,
+}
+
+impl<S: Stream + Unpin + ?Sized> Future for Next<'_, S> {
+    type Output = Option<S::Item>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut *self.get_mut().stream).poll_next(cx)
+    }
+}
+
+/// Values pushed by an interrupt handler, read by a single `Receiver`.
+pub struct IrqQueue<T, const N: usize> {
+    values: Ring<T, N>,
+    waker: AtomicWaker,
+    /// whether there is a receiver, values are dropped otherwise
+    open: AtomicBool,
+    dropped: AtomicU64,
+}
+
+impl<T: Send, const N: usize> IrqQueue<T, N> {
+    /// `N` must be a power of 2
+    pub fn new() -> Self {
+        IrqQueue {
+            values: Ring::new(),
+            waker: AtomicWaker::new(),
+            open: AtomicBool::new(false),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Queue `value` and wake the receiver. Safe to call from interrupt context.
+    ///
+    /// Returns false if there is no receiver, or if the queue is full and `value` was dropped.
+    pub fn push(&self, value: T) -> bool {
+        if !self.open.load(Ordering::Acquire) {
+            return false;
+        }
+        if self.values.push(value).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        self.waker.wake();
+        true
+    }
+
+    /// The receiving end, `None` if it's already taken.
+    pub fn receiver(&'static self) -> Option<Receiver<T, N>> {
+        if self.open.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+        Some(Receiver { queue: self })
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open.load(Ordering::Acquire)
+    }
+
+    /// values dropped because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<T: Send, const N: usize> Default for IrqQueue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A never ending `Stream` of the values pushed in an `IrqQueue`.
+pub struct Receiver<T: 'static, const N: usize> {
+    queue: &'static IrqQueue<T, N>,
+}
+
+impl<T: Send, const N: usize> Stream for Receiver<T, N> {
+    type Item = T;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let queue = self.queue;
+        if let Some(value) = queue.values.pop() {
+            return Poll::Ready(Some(value));
+        }
+        queue.waker.register(cx.waker());
+        // a value pushed before the waker was registered didn't wake us
+        match queue.values.pop() {
+            Some(value) => Poll::Ready(Some(value)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<T: 'static, const N: usize> Drop for Receiver<T, N> {
+    fn drop(&mut self) {
+        self.queue.open.store(false, Ordering::Release);
+        self.queue.waker.take();
+        // stale for the next receiver
+        while self.queue.values.pop().is_some() {}
+    }
+}
+
+#[test_case]
+fn test_irq_queue_stream() {
+    use super::Executor;
+    use crate::interrupts::deferred::{self, Work};
+    use lazy_static::lazy_static;
+
+    lazy_static! {
+        static ref QUEUE: IrqQueue<u8, 8> = IrqQueue::new();
+    }
+    static SUM: AtomicU64 = AtomicU64::new(0);
+    assert!(!QUEUE.push(0), "pushed without a receiver");
+    let mut receiver = QUEUE.receiver().unwrap();
+    assert!(QUEUE.receiver().is_none());
+    let mut executor = Executor::new();
+    executor.spawn(async move {
+        while let Some(value) = receiver.next().await {
+            SUM.fetch_add(value as u64, Ordering::Relaxed);
+            if value == 0 {
+                break;
+            }
+        }
+    });
+    executor.run_ready();
+    assert!(!executor.is_empty());
+    // pushed from deferred context, like the IRQ handlers' work
+    for &value in &[1, 2, 3, 0] {
+        deferred::schedule(Work::new(|value| assert!(QUEUE.push(value as u8)), value));
+    }
+    executor.run();
+    assert_eq!(SUM.load(Ordering::Relaxed), 6);
+    assert!(!QUEUE.is_open());
+}
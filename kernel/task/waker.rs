@@ -0,0 +1,47 @@
+//! a waker slot shared with interrupt handlers
+use core::task::Waker;
+
+use crate::locked::IrqLocked;
+
+/// Holds the waker of the task waiting for an event, the event's source wakes it.
+///
+/// The waker is only woken by reference and stays registered, so the IRQ handler never drops
+/// the last reference to it: the executor's wakers don't allocate or free when woken by
+/// reference. The task may then see spurious wakeups.
+pub struct AtomicWaker {
+    waker: IrqLocked<Option<Waker>>,
+}
+
+impl AtomicWaker {
+    pub const fn new() -> AtomicWaker {
+        AtomicWaker {
+            waker: IrqLocked::new(None),
+        }
+    }
+
+    /// Wake `waker` on the next event, instead of the previous one.
+    pub fn register(&self, waker: &Waker) {
+        let mut registered = self.waker.lock();
+        match registered.as_ref() {
+            Some(previous) if previous.will_wake(waker) => {}
+            _ => *registered = Some(waker.clone()),
+        }
+    }
+
+    /// Safe to call from interrupt context.
+    pub fn wake(&self) {
+        if let Some(waker) = self.waker.lock().as_ref() {
+            waker.wake_by_ref();
+        }
+    }
+
+    pub fn take(&self) -> Option<Waker> {
+        self.waker.lock().take()
+    }
+}
+
+impl Default for AtomicWaker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,74 @@
+//! saved register state of the threads that aren't running, and switching between them.
+//!
+//! A thread's context is the callee-saved registers pushed on its own stack, followed by the
+//! address to resume at; all there is to keep is its stack pointer.
+use core::mem::size_of;
+
+global_asm!(
+    "
+.pushsection .text
+.global switch_context
+switch_context:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov rsp, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+.popsection
+"
+);
+
+extern "C" {
+    /// Save the current context, storing the stack pointer in `*save`, and resume the one
+    /// saved with `restore`. Returns when the current context is resumed.
+    fn switch_context(save: *mut u64, restore: u64);
+}
+
+/// as pushed by `switch_context`
+#[repr(C)]
+struct InitialContext {
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    rbx: u64,
+    /// 0 to end the backtraces
+    rbp: u64,
+    ret: u64,
+    /// keeps the stack aligned as if `ret` was called
+    pad: u64,
+}
+
+/// Build a context on `stack` that starts executing `entry`, returns its stack pointer.
+///
+/// SAFETY: `stack_top` must be the 16 bytes aligned end of an unused stack.
+pub unsafe fn init(stack_top: u64, entry: extern "C" fn() -> !) -> u64 {
+    let rsp = stack_top - size_of::<InitialContext>() as u64;
+    (rsp as *mut InitialContext).write(InitialContext {
+        r15: 0,
+        r14: 0,
+        r13: 0,
+        r12: 0,
+        rbx: 0,
+        rbp: 0,
+        ret: entry as u64,
+        pad: 0,
+    });
+    rsp
+}
+
+/// SAFETY: interrupts must be disabled, `save` must stay valid until the context is resumed,
+/// and `restore` come from `init` or a previous `switch`.
+pub unsafe fn switch(save: *mut u64, restore: u64) {
+    switch_context(save, restore)
+}
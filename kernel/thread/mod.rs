@@ -0,0 +1,255 @@
+//! kernel threads
+//!
+//! Every thread has its own stack on the kernel heap, freed once the thread exited.
+//! They're scheduled round-robin, and preempted when their time slice expires.
+//! A thread blocked in a `sync::WaitQueue` doesn't run until it's woken up.
+pub mod context;
+pub mod preempt;
+pub mod scheduler;
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use pache::KiB;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+
+pub use scheduler::{current_id, exit, has_ready, init_threads, wake, yield_now};
+
+use crate::sync::WaitQueue;
+
+pub type ThreadId = u64;
+
+/// the thread the kernel booted on, running on the bootloader's stack
+pub const BOOT_THREAD_ID: ThreadId = 0;
+
+pub const STACK_SIZE: usize = 16 * KiB as usize;
+/// ticks a thread runs before it's preempted, if another one is ready
+pub const TIME_SLICE: u32 = 10;
+/// written at the bottom of the stacks, to catch overflows when switching away
+const STACK_CANARY: u64 = 0x5ca1_ab1e_c0ff_ee00;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    Ready,
+    Blocked,
+    Dead,
+}
+
+pub struct Thread {
+    id: ThreadId,
+    state: State,
+    /// saved stack pointer, while not running
+    rsp: u64,
+    /// ticks left before it's preempted
+    slice: u32,
+    /// ticks it was running for
+    run_ticks: u64,
+    /// `None` for the boot thread, which runs on the bootloader's stack
+    stack: Option<Box<[u64]>>,
+    entry: Option<Box<dyn FnOnce() + Send>>,
+    exit: Arc<Exit>,
+    /// level 4 table loaded while it runs, `None` for the kernel's
+    page_table: Option<PhysFrame>,
+}
+
+/// shared with the `JoinHandle`
+#[derive(Default)]
+pub(crate) struct Exit {
+    finished: AtomicBool,
+    joiners: WaitQueue,
+}
+
+impl Thread {
+    fn new(id: ThreadId, entry: Box<dyn FnOnce() + Send>) -> Box<Thread> {
+        let mut stack = vec![0u64; STACK_SIZE / 8].into_boxed_slice();
+        stack[0] = STACK_CANARY;
+        let rsp = unsafe { context::init(stack_top(&stack), thread_start) };
+        Box::new(Thread {
+            id,
+            state: State::Ready,
+            rsp,
+            slice: TIME_SLICE,
+            run_ticks: 0,
+            stack: Some(stack),
+            entry: Some(entry),
+            exit: Arc::default(),
+            page_table: None,
+        })
+    }
+    /// the thread the kernel booted on
+    fn boot() -> Box<Thread> {
+        Box::new(Thread {
+            id: BOOT_THREAD_ID,
+            state: State::Running,
+            rsp: 0,
+            slice: TIME_SLICE,
+            run_ticks: 0,
+            stack: None,
+            entry: None,
+            exit: Arc::default(),
+            page_table: None,
+        })
+    }
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+    pub fn state(&self) -> State {
+        self.state
+    }
+    pub fn run_ticks(&self) -> u64 {
+        self.run_ticks
+    }
+    /// where its stack starts, the CPU switches to it on interrupts in user mode
+    fn kernel_stack_top(&self) -> Option<VirtAddr> {
+        self.stack
+            .as_deref()
+            .map(|stack| VirtAddr::new(stack_top(stack)))
+    }
+    fn check_stack(&self) {
+        if let Some(stack) = &self.stack {
+            assert!(
+                stack[0] == STACK_CANARY,
+                "stack overflow in thread {}",
+                self.id
+            );
+        }
+    }
+}
+
+fn stack_top(stack: &[u64]) -> u64 {
+    (stack.as_ptr() as u64 + STACK_SIZE as u64) & !0xf
+}
+
+/// first code run by every spawned thread, see `context::init`
+extern "C" fn thread_start() -> ! {
+    // we're coming from `context::switch`, which is always called with interrupts disabled.
+    scheduler::finish_switch();
+    x86_64::instructions::interrupts::enable();
+    let entry = scheduler::take_entry();
+    entry();
+    exit()
+}
+
+pub struct JoinHandle {
+    id: ThreadId,
+    exit: Arc<Exit>,
+}
+
+impl JoinHandle {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+    pub fn is_finished(&self) -> bool {
+        self.exit.finished.load(Ordering::Acquire)
+    }
+    /// Block until the thread exited.
+    pub fn join(self) {
+        self.exit.joiners.wait_until(|| self.is_finished());
+    }
+}
+
+/// Start a thread running `f`. It only runs once the current thread yields.
+pub fn spawn<F: FnOnce() + Send + 'static>(f: F) -> JoinHandle {
+    spawn_with(None, f)
+}
+
+/// Like `spawn`, but the thread runs with `page_table` as its level 4 table.
+pub(crate) fn spawn_in<F: FnOnce() + Send + 'static>(page_table: PhysFrame, f: F) -> JoinHandle {
+    spawn_with(Some(page_table), f)
+}
+
+fn spawn_with<F: FnOnce() + Send + 'static>(page_table: Option<PhysFrame>, f: F) -> JoinHandle {
+    let thread = scheduler::add(|id| {
+        let mut thread = Thread::new(id, Box::new(f));
+        thread.page_table = page_table;
+        thread
+    });
+    JoinHandle {
+        id: thread.0,
+        exit: thread.1,
+    }
+}
+
+#[test_case]
+fn test_spawn_join() {
+    use core::sync::atomic::AtomicU64;
+    static SUM: AtomicU64 = AtomicU64::new(0);
+    let handles: alloc::vec::Vec<_> = (1..=4)
+        .map(|i| {
+            spawn(move || {
+                SUM.fetch_add(i, Ordering::Relaxed);
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join();
+    }
+    assert_eq!(SUM.load(Ordering::Relaxed), 10);
+}
+
+#[test_case]
+fn test_yield_interleaves() {
+    use crate::locked::IrqLocked;
+    use alloc::vec::Vec;
+    static TRACE: IrqLocked<Vec<(u64, usize)>> = IrqLocked::new(Vec::new());
+    let spawn_worker = |n: u64| {
+        spawn(move || {
+            for i in 0..3 {
+                TRACE.lock().push((n, i));
+                yield_now();
+            }
+        })
+    };
+    let (a, b) = (spawn_worker(1), spawn_worker(2));
+    a.join();
+    b.join();
+    assert_eq!(
+        *TRACE.lock(),
+        [(1, 0), (2, 0), (1, 1), (2, 1), (1, 2), (2, 2)]
+    );
+}
+
+#[test_case]
+fn test_cpu_bound_thread_is_preempted() {
+    static STOP: AtomicBool = AtomicBool::new(false);
+    let spinner = spawn(|| {
+        while !STOP.load(Ordering::Relaxed) {
+            core::hint::spin_loop();
+        }
+    });
+    // only returns once the spinner was preempted
+    yield_now();
+    STOP.store(true, Ordering::Relaxed);
+    spinner.join();
+}
+
+#[test_case]
+fn test_preempt_disabled() {
+    static RAN: AtomicBool = AtomicBool::new(false);
+    let spinner = spawn(|| RAN.store(true, Ordering::Relaxed));
+    {
+        let _guard = preempt::guard();
+        // several slices
+        crate::devices::pit::busy_wait_us(3 * TIME_SLICE as u64 * 1000);
+        assert!(!RAN.load(Ordering::Relaxed));
+    }
+    // the deferred switch happened when the guard was dropped
+    assert!(RAN.load(Ordering::Relaxed));
+    spinner.join();
+}
+
+#[test_case]
+fn test_exit_frees_stack() {
+    let before = scheduler::count();
+    let handle = spawn(|| {});
+    assert_eq!(scheduler::count(), before + 1);
+    handle.join();
+    // the dead thread is reaped by the next switch
+    yield_now();
+    assert_eq!(scheduler::count(), before);
+    assert_eq!(scheduler::dead_count(), 0);
+}
@@ -0,0 +1,73 @@
+//! preemption of the running thread when its time slice expires
+//!
+//! The timer interrupt only flags that a switch is needed, it happens on the way out of the
+//! interrupt, unless the thread disabled preemption or had interrupts masked. It's then
+//! deferred until preemption is enabled again, or the next interrupt.
+//!
+//! Both the count and the flag are per CPU: a lock held on one CPU doesn't keep the others
+//! from switching.
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use x86_64::instructions::interrupts;
+use x86_64::registers::rflags::RFlags;
+
+use super::scheduler;
+use crate::interrupts::deferred;
+use crate::interrupts::trap::TrapFrame;
+
+crate::percpu! {
+    /// nested `disable` calls
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    /// the running thread's time slice expired while another thread is ready
+    static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+}
+
+pub fn disable() {
+    COUNT.get().fetch_add(1, Ordering::Acquire);
+}
+
+/// Re-enable preemption, and run the deferred work then switch right away if they were
+/// held back.
+pub fn enable() {
+    let previous = COUNT.get().fetch_sub(1, Ordering::Release);
+    debug_assert!(previous > 0, "unbalanced preempt::enable");
+    if previous == 1 && interrupts::are_enabled() {
+        if deferred::has_pending() {
+            deferred::run_pending();
+        }
+        if NEED_RESCHED.get().load(Ordering::Relaxed) {
+            scheduler::yield_now();
+        }
+    }
+}
+
+pub fn is_enabled() -> bool {
+    COUNT.get().load(Ordering::Relaxed) == 0
+}
+
+/// Disables preemption until it's dropped.
+pub struct PreemptGuard(());
+
+pub fn guard() -> PreemptGuard {
+    disable();
+    PreemptGuard(())
+}
+impl Drop for PreemptGuard {
+    fn drop(&mut self) {
+        enable();
+    }
+}
+
+pub(super) fn set_need_resched(need: bool) {
+    NEED_RESCHED.get().store(need, Ordering::Relaxed);
+}
+
+/// Called at the end of IRQs, switches thread if the slice of the interrupted one expired.
+pub fn on_irq_exit(frame: &TrapFrame) {
+    let interruptible = RFlags::from_bits_truncate(frame.rflags).contains(RFlags::INTERRUPT_FLAG);
+    if NEED_RESCHED.get().load(Ordering::Relaxed) && is_enabled() && interruptible {
+        // the interrupted context stays on the thread's stack, under this handler's frame,
+        // and is resumed by `iretq` once the thread is switched back to.
+        scheduler::preempt();
+    }
+}
@@ -0,0 +1,258 @@
+//! round-robin scheduler
+//!
+//! The scheduler owns all the threads: the running one, the ready ones in a FIFO, the blocked
+//! ones until they're woken up, and the dead ones until their stack isn't used anymore.
+//! When no thread is ready, the idle thread runs.
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem;
+use core::sync::atomic::Ordering;
+
+use lazy_static::lazy_static;
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::Cr3;
+
+use super::{context, preempt, Exit, State, Thread, ThreadId, TIME_SLICE};
+use crate::info;
+use crate::locked::IrqLocked;
+use crate::user;
+use crate::vmem;
+
+#[derive(Default)]
+struct Scheduler {
+    current: Option<Box<Thread>>,
+    ready: VecDeque<Box<Thread>>,
+    blocked: BTreeMap<ThreadId, Box<Thread>>,
+    /// exited threads, freed after switching away from them
+    dead: Vec<Box<Thread>>,
+    /// runs when no other thread can, it's never in `ready`
+    idle: Option<Box<Thread>>,
+    idle_id: ThreadId,
+    next_id: ThreadId,
+}
+
+impl Scheduler {
+    fn is_idle(&self, thread: &Thread) -> bool {
+        thread.id == self.idle_id
+    }
+    fn idle_is_current(&self) -> bool {
+        self.current
+            .as_ref()
+            .map_or(false, |current| self.is_idle(current))
+    }
+}
+
+lazy_static! {
+    static ref SCHEDULER: IrqLocked<Scheduler> = IrqLocked::new(Scheduler::default());
+}
+
+/// Make the code running since boot the first thread.
+pub fn init_threads() {
+    let mut scheduler = SCHEDULER.lock();
+    scheduler.current = Some(Thread::boot());
+    scheduler.idle = Some(Thread::new(1, Box::new(|| crate::idle_loop())));
+    scheduler.idle_id = 1;
+    scheduler.next_id = 2;
+    info!("threads enabled");
+}
+
+/// Create a thread with the next id and queue it, returns its id and its exit status.
+pub(super) fn add(new: impl FnOnce(ThreadId) -> Box<Thread>) -> (ThreadId, Arc<Exit>) {
+    let mut scheduler = SCHEDULER.lock();
+    assert!(scheduler.current.is_some(), "threads aren't initialized");
+    let id = scheduler.next_id;
+    scheduler.next_id += 1;
+    // allocating the stack with interrupts disabled is fine, it's all done in the heap's lock
+    let thread = new(id);
+    let exit = thread.exit.clone();
+    scheduler.ready.push_back(thread);
+    (id, exit)
+}
+
+/// id of the running thread, 0 before `init_threads`
+pub fn current_id() -> ThreadId {
+    SCHEDULER
+        .lock()
+        .current
+        .as_ref()
+        .map_or(0, |thread| thread.id)
+}
+
+/// number of live threads, the running one included but not the idle thread
+pub fn count() -> usize {
+    let scheduler = SCHEDULER.lock();
+    let current = scheduler.current.is_some() && !scheduler.idle_is_current();
+    current as usize + scheduler.ready.len() + scheduler.blocked.len()
+}
+
+/// exited threads whose stack wasn't freed yet
+pub fn dead_count() -> usize {
+    SCHEDULER.lock().dead.len()
+}
+
+/// Switch to the next ready thread, putting the current one in `state`.
+/// Returns false, without switching, if there is no other thread to run and the current one
+/// can keep running. Otherwise the idle thread takes over.
+///
+/// Must be called with interrupts disabled, they're still disabled when it returns.
+fn reschedule(state: State) -> bool {
+    debug_assert!(!interrupts::are_enabled());
+    let switch = {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = &mut *scheduler;
+        preempt::set_need_resched(false);
+        let current = match scheduler.current.as_mut() {
+            Some(current) => current,
+            None => return false,
+        };
+        current.slice = TIME_SLICE;
+        let next = match scheduler.ready.pop_front() {
+            Some(next) => Some(next),
+            None if state == State::Ready => None,
+            None => scheduler.idle.take(),
+        };
+        next.map(|next| switch_to(scheduler, next, state))
+    };
+    // not panicking with the lock held, the panic handler might need it
+    let (save, restore) = match switch {
+        Some(switch) => switch,
+        None if state == State::Ready => return false,
+        None => panic!("no thread left to run"),
+    };
+    unsafe { context::switch(save, restore) };
+    finish_switch();
+    true
+}
+
+/// Make `next` the current thread and put the previous one in `state`.
+/// Returns where to save the previous context and the context to restore.
+fn switch_to(scheduler: &mut Scheduler, mut next: Box<Thread>, state: State) -> (*mut u64, u64) {
+    next.state = State::Running;
+    let page_table = next.page_table.unwrap_or_else(vmem::kernel_page_table);
+    let (current_table, flags) = Cr3::read();
+    if current_table != page_table {
+        // the kernel, and the stacks on its heap, are mapped in every address space
+        unsafe { Cr3::write(page_table, flags) };
+    }
+    if let Some(top) = next.kernel_stack_top() {
+        user::set_kernel_stack(top);
+    }
+    let restore = next.rsp;
+    let mut previous = scheduler.current.replace(next).unwrap();
+    previous.check_stack();
+    previous.state = state;
+    // the thread is boxed, its `rsp` stays put wherever the box goes
+    let save = &mut previous.rsp as *mut u64;
+    if scheduler.is_idle(&previous) {
+        debug_assert_eq!(state, State::Ready, "the idle thread can only yield");
+        previous.state = State::Ready;
+        scheduler.idle = Some(previous);
+        return (save, restore);
+    }
+    match state {
+        State::Ready => scheduler.ready.push_back(previous),
+        State::Blocked => {
+            scheduler.blocked.insert(previous.id, previous);
+        }
+        State::Dead => scheduler.dead.push(previous),
+        State::Running => unreachable!("the previous thread can't keep running"),
+    }
+    (save, restore)
+}
+
+/// Run on the new thread after each switch: free the threads that exited, now that we're
+/// off their stack.
+pub(super) fn finish_switch() {
+    let dead = mem::take(&mut SCHEDULER.lock().dead);
+    drop(dead);
+}
+
+/// the entry point of the running thread, once
+pub(super) fn take_entry() -> Box<dyn FnOnce() + Send> {
+    let mut scheduler = SCHEDULER.lock();
+    let current = scheduler.current.as_mut().unwrap();
+    current.entry.take().expect("thread entry already taken")
+}
+
+/// Let the other ready threads run.
+pub fn yield_now() {
+    debug_assert!(preempt::is_enabled(), "yielding with preemption disabled");
+    interrupts::without_interrupts(|| reschedule(State::Ready));
+}
+
+/// Block the running thread until `wake` is called with its id.
+///
+/// Must be called with interrupts disabled, so that it can't be woken up before it's blocked.
+pub(crate) fn block() {
+    debug_assert!(!interrupts::are_enabled());
+    reschedule(State::Blocked);
+}
+
+/// Make a blocked thread ready again. Returns false if it wasn't blocked.
+pub fn wake(id: ThreadId) -> bool {
+    let mut scheduler = SCHEDULER.lock();
+    let mut thread = match scheduler.blocked.remove(&id) {
+        Some(thread) => thread,
+        None => return false,
+    };
+    thread.state = State::Ready;
+    scheduler.ready.push_back(thread);
+    if scheduler.idle_is_current() {
+        // don't wait for the idle thread's slice to expire
+        preempt::set_need_resched(true);
+    }
+    true
+}
+
+/// Switch from an IRQ handler, see `preempt::on_irq_exit`.
+pub(super) fn preempt() {
+    reschedule(State::Ready);
+}
+
+/// Whether a thread is waiting for the CPU.
+pub fn has_ready() -> bool {
+    !SCHEDULER.lock().ready.is_empty()
+}
+
+/// Account a tick to the running thread, called by the timer interrupt.
+pub fn tick() {
+    let mut scheduler = SCHEDULER.lock();
+    let has_ready = !scheduler.ready.is_empty();
+    if has_ready && scheduler.idle_is_current() {
+        preempt::set_need_resched(true);
+    }
+    if let Some(current) = scheduler.current.as_mut() {
+        current.run_ticks += 1;
+        current.slice = current.slice.saturating_sub(1);
+        if current.slice == 0 {
+            if has_ready {
+                preempt::set_need_resched(true);
+            } else {
+                // nobody to yield to, start a new slice
+                current.slice = TIME_SLICE;
+            }
+        }
+    }
+}
+
+/// Terminate the running thread.
+pub fn exit() -> ! {
+    interrupts::disable();
+    let (is_boot, exit) = {
+        let scheduler = SCHEDULER.lock();
+        let current = scheduler
+            .current
+            .as_ref()
+            .expect("threads aren't initialized");
+        (current.stack.is_none(), current.exit.clone())
+    };
+    assert!(!is_boot, "the boot thread can't exit");
+    exit.finished.store(true, Ordering::Release);
+    // without the scheduler's lock, waking takes it
+    exit.joiners.wake_all();
+    drop(exit);
+    reschedule(State::Dead);
+    unreachable!("a dead thread was resumed")
+}
@@ -0,0 +1,175 @@
+//! monotonic clock
+//!
+//! Backed by the invariant TSC when there is one, then the HPET, then the PIT ticks.
+pub mod timer;
+pub mod tsc;
+
+use core::convert::TryFrom;
+use core::fmt;
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+pub use core::time::Duration;
+pub use timer::Timer;
+
+use x86_64::instructions::interrupts;
+
+use crate::devices::{hpet, pit};
+use crate::thread;
+use crate::{info, warn};
+
+pub fn init_time() {
+    match tsc::init() {
+        Some(frequency) => info!(
+            "clock: invariant TSC @ {}.{:03}MHz",
+            frequency / 1_000_000,
+            frequency / 1000 % 1000
+        ),
+        None if hpet::is_enabled() => info!("clock: no invariant TSC, using the HPET"),
+        None => warn!("clock: no invariant TSC nor HPET, falling back to the PIT"),
+    }
+}
+
+/// Whether the clock doesn't depend on the PIT ticks.
+pub fn is_precise() -> bool {
+    tsc::frequency().is_some() || hpet::is_enabled()
+}
+
+/// Halt until the next interrupt, without ticking in the meantime.
+///
+/// The PIT only fires for the next timer, or not at all when there is none.
+pub fn idle() {
+    idle_unless(|| false)
+}
+
+/// `idle`, unless `busy` returns true. It's called with interrupts disabled, so nothing an
+/// interrupt handler does can be missed between it and the hlt.
+pub fn idle_unless(busy: impl FnOnce() -> bool) {
+    interrupts::disable();
+    if busy() {
+        interrupts::enable();
+        return;
+    }
+    if thread::has_ready() {
+        // the thread woken up by the last interrupt
+        interrupts::enable();
+        return;
+    }
+    let wait = timer::next_deadline().map(|deadline| deadline.saturating_sub(pit::ticks()));
+    if wait == Some(0) {
+        // the timer interrupt already scheduled the expired timers
+        interrupts::enable();
+        return;
+    }
+    let tickless = pit::enter_idle(wait);
+    // no interrupt can be missed between the check and the hlt
+    interrupts::enable_and_hlt();
+    if tickless {
+        // the wakeup interrupt probably restarted the tick already
+        interrupts::without_interrupts(pit::exit_idle);
+    }
+}
+
+/// A point in time, with nanosecond resolution, since the clock was initialized.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Instant {
+        let nanos = tsc::nanos()
+            .or_else(hpet::nanos)
+            .unwrap_or_else(|| pit::uptime().as_nanos() as u64);
+        Instant(nanos)
+    }
+    /// saturates to zero if `earlier` is later than `self`
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.0.checked_add(nanos).map(Instant)
+    }
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.0.checked_sub(nanos).map(Instant)
+    }
+    pub fn as_nanos(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+    fn add(self, rhs: Duration) -> Instant {
+        self.checked_add(rhs)
+            .expect("overflow when adding duration to instant")
+    }
+}
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+    fn sub(self, rhs: Duration) -> Instant {
+        self.checked_sub(rhs)
+            .expect("overflow when subtracting duration from instant")
+    }
+}
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, rhs: Duration) {
+        *self = *self - rhs;
+    }
+}
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+impl fmt::Debug for Instant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Instant({:?})", Duration::from_nanos(self.0))
+    }
+}
+
+/// Spin until `duration` passed, for delays shorter than a tick.
+pub fn spin_for(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+#[test_case]
+fn test_monotonic() {
+    let mut last = Instant::now();
+    for _ in 0..1000 {
+        let now = Instant::now();
+        assert!(now >= last);
+        last = now;
+    }
+}
+
+#[test_case]
+fn test_against_pit() {
+    let start = Instant::now();
+    pit::sleep_ms(50);
+    let elapsed = start.elapsed();
+    // the sleep can overshoot by a tick, and the calibration isn't perfect
+    assert!(elapsed >= Duration::from_millis(45), "{:?}", elapsed);
+    assert!(elapsed <= Duration::from_millis(70), "{:?}", elapsed);
+}
+
+#[test_case]
+fn test_arithmetic() {
+    let now = Instant::now();
+    let later = now + Duration::from_micros(1500);
+    assert_eq!(later - now, Duration::from_micros(1500));
+    assert_eq!(now - later, Duration::ZERO);
+    assert_eq!(later - Duration::from_micros(1500), now);
+    assert_eq!(Instant(0).checked_sub(Duration::from_nanos(1)), None);
+}
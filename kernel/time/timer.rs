@@ -0,0 +1,250 @@
+//! one-shot and periodic timers, at tick granularity
+//!
+//! Pending timers sit in a queue ordered by deadline. The timer interrupt only compares the
+//! tick count with the earliest deadline, and schedules the expired callbacks to run as
+//! deferred work. Deferred work is held back while the interrupted code holds a lock, the
+//! heap's included, so the callbacks and the queue can allocate.
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+
+use lazy_static::lazy_static;
+
+use crate::devices::pit;
+use crate::interrupts::deferred::{self, Work};
+use crate::locked::IrqLocked;
+
+type Callback = Box<dyn FnMut() + Send>;
+
+struct Entry {
+    callback: Callback,
+    /// in ticks
+    deadline: u64,
+    /// in ticks, for periodic timers
+    period: Option<u64>,
+}
+
+#[derive(Default)]
+struct Timers {
+    /// (deadline, id) of the timers in `entries`
+    queue: BTreeSet<(u64, u64)>,
+    entries: BTreeMap<u64, Entry>,
+    next_id: u64,
+    /// the timer whose callback is running, it's not in `entries` meanwhile
+    running: Option<u64>,
+    running_cancelled: bool,
+}
+
+lazy_static! {
+    static ref TIMERS: IrqLocked<Timers> = IrqLocked::new(Timers::default());
+}
+/// earliest deadline in the queue, `u64::MAX` if none
+static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+/// whether `run_expired` is already queued
+static SCHEDULED: AtomicBool = AtomicBool::new(false);
+
+impl Timers {
+    fn update_next_deadline(&self) {
+        let next = self
+            .queue
+            .iter()
+            .next()
+            .map_or(u64::MAX, |&(deadline, _)| deadline);
+        NEXT_DEADLINE.store(next, Ordering::Relaxed);
+    }
+    fn insert(&mut self, id: u64, entry: Entry) {
+        self.queue.insert((entry.deadline, id));
+        self.entries.insert(id, entry);
+        self.update_next_deadline();
+    }
+    fn remove(&mut self, id: u64) -> Option<Entry> {
+        let entry = self.entries.remove(&id)?;
+        self.queue.remove(&(entry.deadline, id));
+        self.update_next_deadline();
+        Some(entry)
+    }
+    /// pop the next expired timer
+    fn pop_expired(&mut self, now: u64) -> Option<(u64, Entry)> {
+        match self.queue.iter().next() {
+            Some(&(deadline, id)) if deadline <= now => Some((id, self.remove(id)?)),
+            _ => None,
+        }
+    }
+}
+
+/// A pending timer, dropping it doesn't cancel it.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Timer(u64);
+
+impl Timer {
+    /// Run `callback` once, in deferred context, after at least `delay`.
+    pub fn after(delay: Duration, callback: impl FnMut() + Send + 'static) -> Timer {
+        Timer::new(delay, None, Box::new(callback))
+    }
+    /// Run `callback` every `period`, in deferred context, until the timer is cancelled.
+    pub fn every(period: Duration, callback: impl FnMut() + Send + 'static) -> Timer {
+        let ticks = pit::to_ticks(period).max(1);
+        Timer::new(period, Some(ticks), Box::new(callback))
+    }
+    fn new(delay: Duration, period: Option<u64>, callback: Callback) -> Timer {
+        // the current tick is already partly elapsed
+        let deadline = pit::ticks()
+            .saturating_add(pit::to_ticks(delay))
+            .saturating_add(1);
+        let mut timers = TIMERS.lock();
+        let id = timers.next_id;
+        timers.next_id += 1;
+        timers.insert(
+            id,
+            Entry {
+                callback,
+                deadline,
+                period,
+            },
+        );
+        Timer(id)
+    }
+
+    /// Returns whether the timer was still pending. A periodic timer whose callback is
+    /// running won't be rearmed.
+    pub fn cancel(self) -> bool {
+        let mut timers = TIMERS.lock();
+        if timers.running == Some(self.0) {
+            let pending = !timers.running_cancelled;
+            timers.running_cancelled = true;
+            return pending;
+        }
+        timers.remove(self.0).is_some()
+    }
+
+    pub fn is_pending(&self) -> bool {
+        let timers = TIMERS.lock();
+        timers.entries.contains_key(&self.0)
+            || (timers.running == Some(self.0) && !timers.running_cancelled)
+    }
+}
+
+/// Earliest tick at which a timer expires, `None` if there is no pending timer.
+pub fn next_deadline() -> Option<u64> {
+    match NEXT_DEADLINE.load(Ordering::Relaxed) {
+        u64::MAX => None,
+        deadline => Some(deadline),
+    }
+}
+
+/// called by the timer interrupt
+pub fn on_tick(ticks: u64) {
+    if ticks < NEXT_DEADLINE.load(Ordering::Relaxed) || SCHEDULED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    if !deferred::schedule(Work::new(run_expired, 0)) {
+        // retry on the next tick
+        SCHEDULED.store(false, Ordering::Relaxed);
+    }
+}
+
+fn run_expired(_: usize) {
+    SCHEDULED.store(false, Ordering::Relaxed);
+    let now = pit::ticks();
+    loop {
+        let (id, mut entry) = {
+            let mut timers = TIMERS.lock();
+            match timers.pop_expired(now) {
+                Some(expired) => {
+                    timers.running = Some(expired.0);
+                    timers.running_cancelled = false;
+                    expired
+                }
+                None => return,
+            }
+        };
+        // without the lock, so the callback can use timers
+        (entry.callback)();
+        let mut timers = TIMERS.lock();
+        timers.running = None;
+        if let (Some(period), false) = (entry.period, timers.running_cancelled) {
+            // skip the periods we missed rather than running the callback in a burst
+            let missed = now.saturating_sub(entry.deadline) / period;
+            entry.deadline = entry
+                .deadline
+                .saturating_add((missed + 1).saturating_mul(period));
+            timers.insert(id, entry);
+        }
+    }
+}
+
+#[test_case]
+fn test_after() {
+    static FIRED: AtomicBool = AtomicBool::new(false);
+    let timer = Timer::after(Duration::from_millis(5), || {
+        FIRED.store(true, Ordering::Relaxed)
+    });
+    assert!(timer.is_pending());
+    pit::sleep_ms(2);
+    assert!(!FIRED.load(Ordering::Relaxed));
+    pit::sleep_ms(10);
+    assert!(FIRED.load(Ordering::Relaxed));
+    assert!(!timer.is_pending());
+    assert!(!timer.cancel());
+}
+
+#[test_case]
+fn test_every_and_cancel() {
+    static COUNT: AtomicU64 = AtomicU64::new(0);
+    let timer = Timer::every(Duration::from_millis(2), || {
+        COUNT.fetch_add(1, Ordering::Relaxed);
+    });
+    pit::sleep_ms(21);
+    assert!(timer.cancel());
+    let count = COUNT.load(Ordering::Relaxed);
+    assert!(count >= 8, "{} runs in 21ms", count);
+    pit::sleep_ms(10);
+    assert_eq!(COUNT.load(Ordering::Relaxed), count);
+}
+
+#[test_case]
+fn test_cancel_before_expiry() {
+    static FIRED: AtomicBool = AtomicBool::new(false);
+    let timer = Timer::after(Duration::from_millis(3), || {
+        FIRED.store(true, Ordering::Relaxed)
+    });
+    assert!(timer.cancel());
+    pit::sleep_ms(10);
+    assert!(!FIRED.load(Ordering::Relaxed));
+}
+
+#[test_case]
+fn test_after_forever() {
+    let timer = Timer::after(Duration::MAX, || panic!("a timer for ever fired"));
+    pit::sleep_ms(5);
+    assert!(timer.is_pending());
+    assert!(timer.cancel());
+}
+
+#[test_case]
+fn test_cancel_drops_deadline() {
+    // nothing expires meanwhile
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let before = next_deadline();
+        let timer = Timer::after(Duration::from_secs(10), || {});
+        assert!(timer.cancel());
+        assert_eq!(next_deadline(), before);
+    });
+}
+
+#[test_case]
+fn test_held_back_by_locks() {
+    static FIRED: AtomicBool = AtomicBool::new(false);
+    let lock = crate::locked::Locked::new(());
+    Timer::after(Duration::from_millis(1), || {
+        FIRED.store(true, Ordering::Relaxed)
+    });
+    {
+        let _guard = lock.lock();
+        pit::busy_wait_us(10_000);
+        assert!(!FIRED.load(Ordering::Relaxed));
+    }
+    // run when the guard was dropped
+    assert!(FIRED.load(Ordering::Relaxed));
+}
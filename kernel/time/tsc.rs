@@ -0,0 +1,75 @@
+//! timestamp counter
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::instructions::interrupts;
+
+use crate::cpu::rdtsc;
+use crate::devices::{hpet, pit};
+
+/// how long to count TSC cycles against the reference clock
+const CALIBRATION_US: u64 = 50_000;
+
+/// TSC frequency in Hz, 0 if the TSC can't be used as a clock
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+/// TSC value for `Instant` 0
+static EPOCH: AtomicU64 = AtomicU64::new(0);
+
+/// Whether the TSC runs at a constant rate in every P-, C- and T-state.
+pub fn is_invariant() -> bool {
+    let max_extended = unsafe { __cpuid(0x8000_0000) }.eax;
+    // CPUID.80000007H:EDX[8]
+    max_extended >= 0x8000_0007 && unsafe { __cpuid(0x8000_0007) }.edx & (1 << 8) != 0
+}
+
+/// Measure the TSC frequency against the HPET, or the PIT without one. Returns it in Hz.
+pub fn calibrate() -> u64 {
+    // we only want to count the reference clock, not the IRQ handlers
+    interrupts::without_interrupts(|| match hpet::frequency() {
+        Some(hpet_frequency) => {
+            let hpet_ticks = hpet_frequency * CALIBRATION_US / 1_000_000;
+            // it has a frequency, so it's there
+            let counter = || hpet::counter().unwrap();
+            let (hpet_start, start) = (counter(), rdtsc());
+            let mut elapsed = 0;
+            while elapsed < hpet_ticks {
+                core::hint::spin_loop();
+                elapsed = counter() - hpet_start;
+            }
+            let cycles = rdtsc() - start;
+            (cycles as u128 * hpet_frequency as u128 / elapsed as u128) as u64
+        }
+        None => {
+            let start = rdtsc();
+            pit::busy_wait_us(CALIBRATION_US);
+            let cycles = rdtsc() - start;
+            cycles * 1_000_000 / CALIBRATION_US
+        }
+    })
+}
+
+/// Use the TSC as the clock, if it is invariant.
+pub(super) fn init() -> Option<u64> {
+    if !is_invariant() {
+        return None;
+    }
+    let frequency = calibrate();
+    EPOCH.store(rdtsc(), Ordering::Relaxed);
+    FREQUENCY.store(frequency, Ordering::Relaxed);
+    Some(frequency)
+}
+
+/// TSC frequency in Hz, `None` if it isn't used as the clock.
+pub fn frequency() -> Option<u64> {
+    match FREQUENCY.load(Ordering::Relaxed) {
+        0 => None,
+        frequency => Some(frequency),
+    }
+}
+
+/// nanoseconds since `init`, or `None` if the TSC isn't used as the clock.
+pub(super) fn nanos() -> Option<u64> {
+    let frequency = frequency()?;
+    let cycles = rdtsc().wrapping_sub(EPOCH.load(Ordering::Relaxed));
+    Some((cycles as u128 * 1_000_000_000 / frequency as u128) as u64)
+}
@@ -0,0 +1,233 @@
+//! running code in ring 3
+//!
+//! A thread enters user mode with `enter_user`, and comes back to the kernel on interrupts,
+//! on the stack set in the TSS when it was switched to. A fault in user mode raises a signal
+//! in its process, or ends the thread if it has none.
+use x86_64::registers::control::Cr2;
+use x86_64::VirtAddr;
+
+use crate::gdt;
+use crate::interrupts::idt::{
+    BREAKPOINT_VECTOR, DEBUG_VECTOR, DOUBLE_FAULT_VECTOR, NMI_VECTOR, PAGE_FAULT_VECTOR,
+};
+use crate::interrupts::stats;
+use crate::interrupts::trap::TrapFrame;
+use crate::kdb::probe;
+use crate::process::signal::{self, Signal};
+use crate::process::{self, address_space::USER_END, address_space::USER_START};
+use crate::syscall;
+use crate::thread;
+use crate::warn;
+
+const DIVIDE_ERROR_VECTOR: u8 = 0;
+const INVALID_OPCODE_VECTOR: u8 = 6;
+const X87_FLOATING_POINT_VECTOR: u8 = 16;
+const ALIGNMENT_CHECK_VECTOR: u8 = 17;
+const MACHINE_CHECK_VECTOR: u8 = 18;
+const SIMD_FLOATING_POINT_VECTOR: u8 = 19;
+/// RFLAGS in user mode: interrupts enabled, and the reserved bit 1
+pub(crate) const USER_RFLAGS: u64 = 0x202;
+
+pub fn is_user_address(addr: VirtAddr) -> bool {
+    (USER_START..USER_END).contains(&addr.as_u64())
+}
+
+/// Set the stack used when entering the kernel from user mode: on interrupts, through the TSS,
+/// and on syscalls. Called on every switch to a thread with its own stack.
+pub fn set_kernel_stack(top: VirtAddr) {
+    gdt::set_kernel_stack(top);
+    syscall::entry::set_kernel_stack(top);
+}
+
+/// Copy user memory at `src` to `dst`. Fails if it's not all mapped user memory.
+pub fn copy_from_user(src: VirtAddr, dst: &mut [u8]) -> Result<(), probe::Fault> {
+    check_user_range(src, dst.len())?;
+    for (i, byte) in dst.iter_mut().enumerate() {
+        *byte = probe::read_u8(src + i)?;
+    }
+    Ok(())
+}
+
+/// Copy `src` to user memory at `dst`. Fails if it's not all mapped, writable user memory.
+pub fn copy_to_user(dst: VirtAddr, src: &[u8]) -> Result<(), probe::Fault> {
+    check_user_range(dst, src.len())?;
+    for (i, &byte) in src.iter().enumerate() {
+        // user memory has no invariant to break
+        unsafe { probe::write_u8(dst + i, byte)? };
+    }
+    Ok(())
+}
+
+fn check_user_range(start: VirtAddr, len: usize) -> Result<(), probe::Fault> {
+    match start.as_u64().checked_add(len as u64) {
+        Some(end) if is_user_address(start) && end <= USER_END => Ok(()),
+        _ => Err(probe::Fault),
+    }
+}
+
+/// Whether the interrupted code was running in user mode.
+pub fn is_from_user(frame: &TrapFrame) -> bool {
+    frame.cs & 3 == 3
+}
+
+/// Jump to `entry` in user mode, with the stack pointer at `stack`.
+///
+/// Only user addresses are allowed, the kernel is out of reach from there: the thread only
+/// comes back to the kernel through interrupts, and ends if the user code faults.
+pub fn enter_user(entry: VirtAddr, stack: VirtAddr) -> ! {
+    assert!(
+        is_user_address(entry) && stack.as_u64() > USER_START && stack.as_u64() <= USER_END,
+        "not a user address: entry {:p}, stack {:p}",
+        entry,
+        stack
+    );
+    assert_ne!(
+        thread::current_id(),
+        thread::BOOT_THREAD_ID,
+        "the boot thread has no kernel stack for interrupts in user mode"
+    );
+    let selectors = gdt::selectors();
+    unsafe {
+        asm!(
+            "push {ss}",
+            "push {rsp}",
+            "push {rflags}",
+            "push {cs}",
+            "push {rip}",
+            // nothing from the kernel is left in the registers
+            "xor eax, eax",
+            "xor ebx, ebx",
+            "xor ecx, ecx",
+            "xor edx, edx",
+            "xor esi, esi",
+            "xor edi, edi",
+            "xor ebp, ebp",
+            "xor r8d, r8d",
+            "xor r9d, r9d",
+            "xor r10d, r10d",
+            "xor r11d, r11d",
+            "xor r12d, r12d",
+            "xor r13d, r13d",
+            "xor r14d, r14d",
+            "xor r15d, r15d",
+            // GS is the user's there, see `percpu`
+            "cli",
+            "swapgs",
+            "iretq",
+            ss = in(reg) selectors.user_data.0 as u64,
+            rsp = in(reg) stack.as_u64(),
+            rflags = in(reg) USER_RFLAGS,
+            cs = in(reg) selectors.user_code.0 as u64,
+            rip = in(reg) entry.as_u64(),
+            options(noreturn)
+        )
+    }
+}
+
+/// Called for every trap: if it's an exception raised by user code, report it and raise the
+/// matching signal, taken before returning to user mode. Returns whether it was one, the
+/// kernel's handler of the exception mustn't run then.
+pub(crate) fn check_fault(frame: &TrapFrame) -> bool {
+    let vector = frame.vector as u8;
+    // NMIs and machine checks aren't caused by the code that was running
+    let is_fault = vector < 32
+        && !matches!(
+            vector,
+            NMI_VECTOR | DOUBLE_FAULT_VECTOR | MACHINE_CHECK_VECTOR
+        );
+    if !is_fault || !is_from_user(frame) {
+        return false;
+    }
+    let signal = match vector {
+        DIVIDE_ERROR_VECTOR | X87_FLOATING_POINT_VECTOR | SIMD_FLOATING_POINT_VECTOR => {
+            Signal::SIGFPE
+        }
+        INVALID_OPCODE_VECTOR => Signal::SIGILL,
+        DEBUG_VECTOR | BREAKPOINT_VECTOR => Signal::SIGTRAP,
+        ALIGNMENT_CHECK_VECTOR => Signal::SIGBUS,
+        _ => Signal::SIGSEGV,
+    };
+    let pid = process::current_pid();
+    if vector == PAGE_FAULT_VECTOR {
+        warn!(
+            "user {} @ {:p} in thread {} (pid {:?}), {:?}\n{:#?}",
+            stats::vector_name(vector),
+            Cr2::read(),
+            thread::current_id(),
+            pid,
+            signal,
+            frame
+        );
+    } else {
+        warn!(
+            "user {} in thread {} (pid {:?}), {:?}\n{:#?}",
+            stats::vector_name(vector),
+            thread::current_id(),
+            pid,
+            signal,
+            frame
+        );
+    }
+    signal::force(signal);
+    true
+}
+
+/// Run `code` in user mode, in a new process, returns its exit status.
+#[cfg(test)]
+pub(crate) fn run_user(code: &'static [u8]) -> i32 {
+    use crate::vmem::paging::PAGE_SIZE;
+    use x86_64::structures::paging::PageTableFlags;
+    let pid = process::spawn("user", move || {
+        let entry = VirtAddr::new(USER_START);
+        let stack = VirtAddr::new(USER_START + 16 * PAGE_SIZE);
+        process::with_space(process::current_pid().unwrap(), |space| {
+            space.map(entry, PAGE_SIZE, PageTableFlags::WRITABLE)?;
+            space.map(stack - PAGE_SIZE, PAGE_SIZE, PageTableFlags::WRITABLE)
+        })
+        .unwrap()
+        .unwrap();
+        // the address space is active
+        unsafe {
+            core::ptr::copy_nonoverlapping(code.as_ptr(), entry.as_mut_ptr(), code.len());
+        }
+        enter_user(entry, stack)
+    })
+    .unwrap();
+    process::waitpid(pid).unwrap()
+}
+
+#[test_case]
+fn test_user_page_fault() {
+    // mov al, [0]
+    let status = run_user(&[0x8a, 0x04, 0x25, 0x00, 0x00, 0x00, 0x00]);
+    assert_eq!(status, signal::exit_status(Signal::SIGSEGV));
+}
+
+#[test_case]
+fn test_user_privileged_instruction() {
+    // push 1; pop rax; hlt: #GP in ring 3, after using the user stack
+    let status = run_user(&[0x6a, 0x01, 0x58, 0xf4]);
+    assert_eq!(status, signal::exit_status(Signal::SIGSEGV));
+}
+
+#[test_case]
+fn test_kernel_unreachable() {
+    // mov rax, [kernel]: the kernel's pages aren't user accessible
+    static CODE: [u8; 10] = {
+        let addr = crate::heap::KERNEL_HEAP_START.to_le_bytes();
+        [
+            0x48, 0xa1, addr[0], addr[1], addr[2], addr[3], addr[4], addr[5], addr[6], addr[7],
+        ]
+    };
+    assert_eq!(run_user(&CODE), signal::exit_status(Signal::SIGSEGV));
+}
+
+#[test_case]
+fn test_user_arithmetic_and_invalid_opcode() {
+    // xor ecx, ecx; div ecx
+    let status = run_user(&[0x31, 0xc9, 0xf7, 0xf1]);
+    assert_eq!(status, signal::exit_status(Signal::SIGFPE));
+    // ud2
+    let status = run_user(&[0x0f, 0x0b]);
+    assert_eq!(status, signal::exit_status(Signal::SIGILL));
+}
@@ -1,27 +1,131 @@
 pub mod paging;
 
+use alloc::vec::Vec;
 use core::ops::Range;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
-use x86_64::structures::paging::{FrameAllocator, OffsetPageTable, PhysFrame, Size4KiB};
+use x86_64::structures::paging::{
+    mapper::MapToError, FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame,
+    Size4KiB,
+};
 use x86_64::{PhysAddr, VirtAddr};
 
+use crate::heap::BootstrapFramesAlloc;
 use crate::info;
-use pache::MiB;
+use crate::locked::IrqLocked;
+use pache::{GiB, MiB};
 use paging::PAGE_SIZE;
 
+/// addresses starting with 0x69c are memory mapped devices
+pub const MMIO_START: u64 = 0x0069_c000_0000;
+pub const MMIO_SIZE: u64 = GiB / 4;
+pub const MMIO_END: u64 = MMIO_START + MMIO_SIZE;
+
+/// the kernel's page tables and the physical frames allocator, once the heap is set up
+pub struct KernelSpace {
+    pub mapper: OffsetPageTable<'static>,
+    pub frames: BootstrapFramesAlloc,
+    /// next free address in the MMIO region
+    mmio_next: u64,
+    /// frames given back with `free_frame`, the frames allocator can't take them back
+    free_frames: Vec<PhysFrame>,
+}
+
+pub static KERNEL_SPACE: IrqLocked<Option<KernelSpace>> = IrqLocked::new(None);
+
+/// hand over the mapper and frame allocator used to set up the heap.
+pub fn install(mapper: OffsetPageTable<'static>, frames: BootstrapFramesAlloc) {
+    *KERNEL_SPACE.lock() = Some(KernelSpace {
+        mapper,
+        frames,
+        mmio_next: MMIO_START,
+        free_frames: Vec::new(),
+    });
+}
+
+/// Allocate a physical frame, it isn't zeroed.
+pub fn alloc_frame() -> Option<PhysFrame> {
+    let mut guard = KERNEL_SPACE.lock();
+    let space = guard.as_mut().expect("vmem::install wasn't called");
+    space
+        .free_frames
+        .pop()
+        .or_else(|| FrameAllocator::<Size4KiB>::allocate_frame(&mut space.frames))
+}
+
+/// Frames `alloc_frame` can still give.
+pub fn available_frames() -> u64 {
+    let guard = KERNEL_SPACE.lock();
+    let space = guard.as_ref().expect("vmem::install wasn't called");
+    space.free_frames.len() as u64 + space.frames.small_frames_left()
+}
+
+/// Give back a frame from `alloc_frame`, once nothing maps it anymore.
+pub fn free_frame(frame: PhysFrame) {
+    let mut guard = KERNEL_SPACE.lock();
+    let space = guard.as_mut().expect("vmem::install wasn't called");
+    space.free_frames.push(frame);
+}
+
+/// Map `size` bytes of device memory at `phys` as uncacheable, returns the virtual address of `phys`.
+pub fn map_mmio(phys: PhysAddr, size: u64) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let mut guard = KERNEL_SPACE.lock();
+    let space = guard.as_mut().expect("vmem::install wasn't called");
+
+    let start = phys.align_down(PAGE_SIZE);
+    let len = (phys + size).align_up(PAGE_SIZE) - start;
+    if space.mmio_next + len > MMIO_END {
+        return Err(MapToError::FrameAllocationFailed);
+    }
+    let virt = VirtAddr::new(space.mmio_next);
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH;
+    for offset in (0..len).step_by(PAGE_SIZE as usize) {
+        let page = Page::<Size4KiB>::containing_address(virt + offset);
+        let frame = PhysFrame::containing_address(start + offset);
+        unsafe {
+            space
+                .mapper
+                .map_to(page, frame, flags, &mut space.frames)?
+                .flush()
+        };
+    }
+    space.mmio_next += len;
+    Ok(virt + (phys - start))
+}
+
+/// where the complete physical memory is mapped, set by `init`
+static PMEM_OFFSET: AtomicU64 = AtomicU64::new(0);
+/// physical address of the kernel's level 4 table, set by `init`
+static KERNEL_PAGE_TABLE: AtomicU64 = AtomicU64::new(0);
+
 /// init a new OffsetPageTable with the l4frame's physical addr and the offset.
 ///
 /// SAFETY: caller must guarantee complete pmem. is mapped to vmem. at the passed `pmem_offset`.
 /// Also, only call once because of `&mut` aliasing.
 pub unsafe fn init(pmem_offset: VirtAddr) -> OffsetPageTable<'static> {
     info!("identity mapping at offset {:p}", pmem_offset);
+    PMEM_OFFSET.store(pmem_offset.as_u64(), Ordering::Relaxed);
     let phys = pl4frame().start_address();
+    KERNEL_PAGE_TABLE.store(phys.as_u64(), Ordering::Relaxed);
     let virt: VirtAddr = pmem_offset + phys.as_u64();
     info!("mapping PL4: V{:p} -> P{:p}", virt, phys);
     OffsetPageTable::new(&mut *(virt.as_mut_ptr()), pmem_offset)
 }
 
+/// Returns where `addr` is mapped in the physical memory mapping.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PMEM_OFFSET.load(Ordering::Relaxed) + addr.as_u64())
+}
+
+/// the level 4 table the kernel booted with, which kernel threads run on
+pub fn kernel_page_table() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_PAGE_TABLE.load(Ordering::Relaxed)))
+}
+
 fn pl4frame() -> PhysFrame {
     use x86_64::registers::control::Cr3;
     Cr3::read().0
@@ -1,6 +1,7 @@
 #![cfg_attr(not(test), no_std)]
 pub mod ansi_term;
 pub mod mem;
+pub mod ring;
 
 pub mod units;
 pub use units::{GiB, KiB, MiB, TiB};
@@ -0,0 +1,205 @@
+//! bounded lock-free MPMC queue, see
+//! https://www.1024cores.net/home/lock-free-algorithms/queues/bounded-mpmc-queue
+//!
+//! Every slot carries a sequence number telling whether it's ready to be written or read
+//! for a given position, so producers and consumers only ever CAS the head or the tail.
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+struct Slot<T> {
+    seq: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+pub struct Ring<T, const N: usize> {
+    slots: [Slot<T>; N],
+    /// next position to pop
+    head: AtomicUsize,
+    /// next position to push
+    tail: AtomicUsize,
+}
+
+unsafe impl<T: Send, const N: usize> Sync for Ring<T, N> {}
+unsafe impl<T: Send, const N: usize> Send for Ring<T, N> {}
+
+impl<T, const N: usize> Ring<T, N> {
+    /// `N` must be a power of 2
+    pub fn new() -> Self {
+        assert!(N.is_power_of_two(), "Ring capacity must be a power of 2");
+        let mut slots = MaybeUninit::<[Slot<T>; N]>::uninit();
+        let first = slots.as_mut_ptr() as *mut Slot<T>;
+        for i in 0..N {
+            unsafe {
+                first.add(i).write(Slot {
+                    seq: AtomicUsize::new(i),
+                    value: UnsafeCell::new(MaybeUninit::uninit()),
+                });
+            }
+        }
+        Ring {
+            slots: unsafe { slots.assume_init() },
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Returns the value back if the queue is full.
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let mut pos = self.tail.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos & (N - 1)];
+            let seq = slot.seq.load(Ordering::Acquire);
+            let diff = seq as isize - pos as isize;
+            if diff == 0 {
+                match self.tail.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        unsafe { (*slot.value.get()).as_mut_ptr().write(value) };
+                        slot.seq.store(pos.wrapping_add(1), Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(current) => pos = current,
+                }
+            } else if diff < 0 {
+                // the slot still holds the value from the previous lap
+                return Err(value);
+            } else {
+                pos = self.tail.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        let mut pos = self.head.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos & (N - 1)];
+            let seq = slot.seq.load(Ordering::Acquire);
+            let diff = seq as isize - pos.wrapping_add(1) as isize;
+            if diff == 0 {
+                match self.head.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        let value = unsafe { (*slot.value.get()).as_ptr().read() };
+                        slot.seq.store(pos.wrapping_add(N), Ordering::Release);
+                        return Some(value);
+                    }
+                    Err(current) => pos = current,
+                }
+            } else if diff < 0 {
+                // nothing was pushed at this position yet
+                return None;
+            } else {
+                pos = self.head.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// only a hint when used concurrently
+    pub fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Relaxed) == self.tail.load(Ordering::Relaxed)
+    }
+}
+
+impl<T, const N: usize> Default for Ring<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Drop for Ring<T, N> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn fifo() {
+        let ring: Ring<u32, 4> = Ring::new();
+        assert_eq!(ring.pop(), None);
+        for i in 0..4 {
+            assert_eq!(ring.push(i), Ok(()));
+        }
+        assert_eq!(ring.push(4), Err(4));
+        for i in 0..4 {
+            assert_eq!(ring.pop(), Some(i));
+        }
+        assert_eq!(ring.pop(), None);
+        assert!(ring.is_empty());
+    }
+    #[test]
+    fn wrap_around() {
+        let ring: Ring<usize, 8> = Ring::new();
+        for i in 0..1000 {
+            assert_eq!(ring.push(i), Ok(()));
+            assert_eq!(ring.push(i + 1), Ok(()));
+            assert_eq!(ring.pop(), Some(i));
+            assert_eq!(ring.pop(), Some(i + 1));
+        }
+    }
+    #[test]
+    fn drops_remaining() {
+        let value = Arc::new(());
+        {
+            let ring: Ring<Arc<()>, 4> = Ring::new();
+            ring.push(value.clone()).unwrap();
+            ring.push(value.clone()).unwrap();
+            assert_eq!(Arc::strong_count(&value), 3);
+        }
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+    #[test]
+    fn concurrent_producers() {
+        const PRODUCERS: usize = 4;
+        const PER_PRODUCER: usize = 10_000;
+        let ring: Arc<Ring<usize, 64>> = Arc::new(Ring::new());
+        let producers: Vec<_> = (0..PRODUCERS)
+            .map(|p| {
+                let ring = ring.clone();
+                thread::spawn(move || {
+                    for i in 0..PER_PRODUCER {
+                        let mut v = p * PER_PRODUCER + i;
+                        while let Err(back) = ring.push(v) {
+                            v = back;
+                            thread::yield_now();
+                        }
+                    }
+                })
+            })
+            .collect();
+        let mut seen = vec![false; PRODUCERS * PER_PRODUCER];
+        let mut received = 0;
+        while received < seen.len() {
+            match ring.pop() {
+                Some(v) => {
+                    assert!(!seen[v]);
+                    seen[v] = true;
+                    received += 1;
+                }
+                None => thread::yield_now(),
+            }
+        }
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        assert!(ring.is_empty());
+    }
+}
@@ -0,0 +1,35 @@
+//! uses the heap, exits with 0 if it works
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+sakuya::entry!(main);
+
+fn main() -> i32 {
+    let boxed = Box::new(42u64);
+    let mut numbers: Vec<u64> = (0..10_000).collect();
+    numbers.retain(|n| n % 3 == 0);
+    let mut text = String::new();
+    for n in numbers.iter().take(4) {
+        write!(text, "{} ", n).unwrap();
+    }
+    // bigger than a page, and freed blocks reused
+    let mut map = BTreeMap::new();
+    for round in 0..4 {
+        let big = alloc::vec![round as u8; 3 * 4096];
+        map.insert(round, big.len());
+    }
+    sakuya::println!("{}{}", text, boxed);
+    let ok = *boxed == 42
+        && numbers.len() == 3334
+        && text == "0 3 6 9 "
+        && map.values().all(|&len| len == 3 * 4096);
+    !ok as i32
+}
@@ -0,0 +1,19 @@
+//! greets its arguments, exits with their number
+#![no_std]
+#![no_main]
+
+use sakuya::{env, println};
+
+sakuya::entry!(main);
+
+fn main() -> i32 {
+    let mut count = 0;
+    for arg in env::args().skip(1) {
+        println!("Hello, {}!", arg);
+        count += 1;
+    }
+    if let Some(name) = env::var("NAME") {
+        println!("Hello, {}!", name);
+    }
+    count
+}
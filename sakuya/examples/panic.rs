@@ -0,0 +1,10 @@
+//! panics, so exits with `PANIC_EXIT_STATUS`
+#![no_std]
+#![no_main]
+
+sakuya::entry!(main);
+
+fn main() -> i32 {
+    let empty: Option<i32> = None;
+    empty.expect("nothing there")
+}
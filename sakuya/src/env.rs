@@ -0,0 +1,73 @@
+//! the arguments and the environment of the program
+use core::ptr;
+use core::slice;
+use core::str;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+/// null terminated arrays of null terminated strings
+static ARGV: AtomicPtr<*const u8> = AtomicPtr::new(ptr::null_mut());
+static ENVP: AtomicPtr<*const u8> = AtomicPtr::new(ptr::null_mut());
+
+/// Find the arguments and the environment from the initial stack pointer.
+///
+/// # Safety
+/// `stack` must be the stack pointer the kernel started the program with.
+#[cfg(not(test))]
+pub(crate) unsafe fn init(stack: *const u64) {
+    let argc = *stack as usize;
+    let argv = stack.add(1) as *mut *const u8;
+    ARGV.store(argv, Ordering::Relaxed);
+    ENVP.store(argv.add(argc + 1), Ordering::Relaxed);
+}
+
+/// Iterator over a null terminated array of strings.
+pub struct Strings {
+    next: *const *const u8,
+}
+
+impl Iterator for Strings {
+    type Item = &'static str;
+
+    fn next(&mut self) -> Option<&'static str> {
+        if self.next.is_null() {
+            return None;
+        }
+        let string = unsafe { *self.next };
+        if string.is_null() {
+            return None;
+        }
+        self.next = unsafe { self.next.add(1) };
+        let bytes = unsafe {
+            let len = (0..).take_while(|&i| *string.add(i) != 0).count();
+            slice::from_raw_parts(string, len)
+        };
+        // the kernel only passes strings
+        Some(str::from_utf8(bytes).unwrap_or(""))
+    }
+}
+
+/// the arguments, the name of the program first
+pub fn args() -> Strings {
+    Strings {
+        next: ARGV.load(Ordering::Relaxed),
+    }
+}
+
+/// the environment, as `NAME=value` strings
+pub fn vars() -> Strings {
+    Strings {
+        next: ENVP.load(Ordering::Relaxed),
+    }
+}
+
+/// the value of the environment variable `name`
+pub fn var(name: &str) -> Option<&'static str> {
+    vars().find_map(|var| {
+        let (key, value) = var.split_at(var.find('=')?);
+        if key == name {
+            Some(&value[1..])
+        } else {
+            None
+        }
+    })
+}
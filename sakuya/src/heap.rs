@@ -0,0 +1,109 @@
+//! the heap, grown with `brk`
+//!
+//! Blocks have power of 2 sizes, from 16 bytes, and are aligned on their size up to a page.
+//! Freed blocks go to the free list of their size, the memory is never given back.
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+
+use spin::Mutex;
+
+use crate::syscall;
+
+const MIN_BLOCK: usize = 16;
+const PAGE_SIZE: usize = 4096;
+/// block sizes, from `MIN_BLOCK` to half the address space
+const CLASSES: usize = 43;
+
+struct Free {
+    next: *mut Free,
+}
+
+struct Heap {
+    /// freed blocks, by size class
+    free: [*mut Free; CLASSES],
+    /// the first byte never allocated, 0 before the first allocation
+    next: usize,
+    /// the program break
+    end: usize,
+}
+
+unsafe impl Send for Heap {}
+
+pub struct Allocator(Mutex<Heap>);
+
+#[cfg(not(test))]
+#[global_allocator]
+static ALLOCATOR: Allocator = Allocator::new();
+
+fn align_up(addr: usize, align: usize) -> Option<usize> {
+    Some(addr.checked_add(align - 1)? & !(align - 1))
+}
+
+/// the size class of the blocks for `layout`
+fn class(layout: Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align()).max(MIN_BLOCK);
+    let class = size.checked_next_power_of_two()?.trailing_zeros() - MIN_BLOCK.trailing_zeros();
+    Some(class as usize).filter(|&class| class < CLASSES)
+}
+
+impl Heap {
+    /// Allocate fresh memory, moving the break if needed.
+    fn grow(&mut self, size: usize, align: usize) -> Option<*mut u8> {
+        if self.end == 0 {
+            self.end = syscall::brk(0).ok()? as usize;
+            self.next = self.end;
+        }
+        let start = align_up(self.next, align)?;
+        let end = start.checked_add(size)?;
+        if end > self.end {
+            self.end = syscall::brk(end as u64).ok()? as usize;
+        }
+        self.next = end;
+        Some(start as *mut u8)
+    }
+}
+
+impl Allocator {
+    pub const fn new() -> Allocator {
+        Allocator(Mutex::new(Heap {
+            free: [ptr::null_mut(); CLASSES],
+            next: 0,
+            end: 0,
+        }))
+    }
+}
+
+impl Default for Allocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let class = match class(layout) {
+            Some(class) => class,
+            None => return ptr::null_mut(),
+        };
+        let size = MIN_BLOCK << class;
+        let mut heap = self.0.lock();
+        let free = heap.free[class];
+        // freed blocks are only known to be aligned on a page, at most
+        if !free.is_null() && layout.align() <= PAGE_SIZE {
+            heap.free[class] = (*free).next;
+            return free as *mut u8;
+        }
+        let align = size.min(PAGE_SIZE).max(layout.align());
+        heap.grow(size, align).unwrap_or(ptr::null_mut())
+    }
+
+    unsafe fn dealloc(&self, block: *mut u8, layout: Layout) {
+        let class = class(layout).expect("freeing a block which couldn't be allocated");
+        let mut heap = self.0.lock();
+        let block = block as *mut Free;
+        block.write(Free {
+            next: heap.free[class],
+        });
+        heap.free[class] = block;
+    }
+}
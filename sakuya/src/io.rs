@@ -0,0 +1,64 @@
+//! standard output and error
+use core::fmt::{self, Write};
+
+use crate::syscall::{self, STDERR, STDOUT};
+
+/// A file descriptor written with `fmt::Write`.
+pub struct Fd(pub u32);
+
+pub fn stdout() -> Fd {
+    Fd(STDOUT)
+}
+
+pub fn stderr() -> Fd {
+    Fd(STDERR)
+}
+
+impl Fd {
+    /// Write all of `bytes`, the kernel might take them in several calls.
+    pub fn write_all(&mut self, mut bytes: &[u8]) -> syscall::Result<()> {
+        while !bytes.is_empty() {
+            let written = syscall::write(self.0, bytes)?;
+            bytes = &bytes[written..];
+        }
+        Ok(())
+    }
+}
+
+impl Write for Fd {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_all(s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(fd: u32, args: fmt::Arguments) {
+    // nowhere left to report it
+    let _ = Fd(fd).write_fmt(args);
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::io::_print($crate::syscall::STDOUT, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($fmt:expr) => ($crate::print!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) =>
+        ($crate::print!(concat!($fmt, "\n"), $($arg)*));
+}
+
+#[macro_export]
+macro_rules! eprint {
+    ($($arg:tt)*) => ($crate::io::_print($crate::syscall::STDERR, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! eprintln {
+    () => ($crate::eprint!("\n"));
+    ($fmt:expr) => ($crate::eprint!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) =>
+        ($crate::eprint!(concat!($fmt, "\n"), $($arg)*));
+}
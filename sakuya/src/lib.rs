@@ -0,0 +1,58 @@
+//! runtime for remilia user programs
+//!
+//! A program is a `no_std`, `no_main` binary built for `x86_64-remilia-user.json`, which names
+//! its main function with `entry!`:
+//!
+//! ```ignore
+//! #![no_std]
+//! #![no_main]
+//!
+//! sakuya::entry!(main);
+//!
+//! fn main() -> i32 {
+//!     sakuya::println!("Hello, {}!", sakuya::env::args().nth(1).unwrap_or("World"));
+//!     0
+//! }
+//! ```
+//!
+//! It exits with the status `main` returns, or 101 if it panics.
+#![no_std]
+#![feature(asm, global_asm, alloc_error_handler)]
+
+extern crate alloc;
+
+pub mod env;
+pub mod heap;
+pub mod io;
+#[cfg(not(test))]
+mod start;
+pub mod syscall;
+
+/// exit status of a program which panicked
+pub const PANIC_EXIT_STATUS: i32 = 101;
+
+/// Declare the main function of the program, a `fn() -> i32` returning its exit status.
+#[macro_export]
+macro_rules! entry {
+    ($main:path) => {
+        #[export_name = "sakuya_main"]
+        pub fn __sakuya_main() -> i32 {
+            // check the signature
+            let main: fn() -> i32 = $main;
+            main()
+        }
+    };
+}
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    eprintln!("{}", info);
+    syscall::exit(PANIC_EXIT_STATUS)
+}
+
+#[cfg(not(test))]
+#[alloc_error_handler]
+fn alloc_error(layout: core::alloc::Layout) -> ! {
+    panic!("out of memory allocating {:?}", layout)
+}
@@ -0,0 +1,31 @@
+//! the entry point
+//!
+//! The kernel starts programs with `argc` at the top of the stack, followed by the `argv` and
+//! `envp` pointers, see `kernel/process/elf.rs`.
+use crate::env;
+use crate::syscall;
+
+global_asm!(
+    "
+.pushsection .text
+.global _start
+_start:
+    mov rdi, rsp
+    // already aligned by the kernel, but it costs nothing to be sure
+    and rsp, -16
+    call sakuya_start
+    ud2
+.popsection
+"
+);
+
+#[no_mangle]
+extern "C" fn sakuya_start(stack: *const u64) -> ! {
+    extern "Rust" {
+        /// defined by `entry!`
+        fn sakuya_main() -> i32;
+    }
+    unsafe { env::init(stack) };
+    let status = unsafe { sakuya_main() };
+    syscall::exit(status)
+}
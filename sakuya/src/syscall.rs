@@ -0,0 +1,173 @@
+//! system calls
+//!
+//! The `syscall*` functions are the raw calls, the others decode their result: a value, or a
+//! negated errno. The numbers and the ABI are the kernel's, see `kernel/syscall/mod.rs`.
+use core::fmt;
+
+pub mod nr {
+    pub const EXIT: u64 = 0;
+    pub const WRITE: u64 = 1;
+    pub const YIELD: u64 = 2;
+    pub const GETPID: u64 = 3;
+    pub const TIME: u64 = 4;
+    pub const KILL: u64 = 5;
+    pub const SIGACTION: u64 = 6;
+    pub const SIGPROCMASK: u64 = 7;
+    pub const SIGRETURN: u64 = 8;
+    pub const BRK: u64 = 9;
+}
+
+pub const STDOUT: u32 = 1;
+pub const STDERR: u32 = 2;
+
+/// clocks of `time`
+pub const CLOCK_MONOTONIC: u64 = 0;
+pub const CLOCK_REALTIME: u64 = 1;
+
+/// handlers of `sigaction`, besides functions
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+
+/// what `sigprocmask` does with the set
+pub const SIG_BLOCK: u64 = 0;
+pub const SIG_UNBLOCK: u64 = 1;
+pub const SIG_SETMASK: u64 = 2;
+
+/// An error number returned by the kernel.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Errno(pub i64);
+
+impl Errno {
+    pub const EPERM: Errno = Errno(1);
+    pub const ESRCH: Errno = Errno(3);
+    pub const EINTR: Errno = Errno(4);
+    pub const ENOEXEC: Errno = Errno(8);
+    pub const EBADF: Errno = Errno(9);
+    pub const ECHILD: Errno = Errno(10);
+    pub const EAGAIN: Errno = Errno(11);
+    pub const ENOMEM: Errno = Errno(12);
+    pub const EFAULT: Errno = Errno(14);
+    pub const EINVAL: Errno = Errno(22);
+    pub const ENOSYS: Errno = Errno(38);
+}
+
+impl fmt::Debug for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Errno({})", self.0)
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Errno>;
+
+/// the largest errno, results above `-MAX_ERRNO` are values
+const MAX_ERRNO: u64 = 4095;
+
+fn decode(result: u64) -> Result<u64> {
+    if result > MAX_ERRNO.wrapping_neg() {
+        Err(Errno(-(result as i64)))
+    } else {
+        Ok(result)
+    }
+}
+
+pub unsafe fn syscall0(nr: u64) -> u64 {
+    let result;
+    asm!("syscall", inlateout("rax") nr => result, out("rcx") _, out("r11") _, options(nostack));
+    result
+}
+
+pub unsafe fn syscall1(nr: u64, a: u64) -> u64 {
+    let result;
+    asm!(
+        "syscall",
+        inlateout("rax") nr => result,
+        in("rdi") a,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack)
+    );
+    result
+}
+
+pub unsafe fn syscall2(nr: u64, a: u64, b: u64) -> u64 {
+    let result;
+    asm!(
+        "syscall",
+        inlateout("rax") nr => result,
+        in("rdi") a,
+        in("rsi") b,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack)
+    );
+    result
+}
+
+pub unsafe fn syscall3(nr: u64, a: u64, b: u64, c: u64) -> u64 {
+    let result;
+    asm!(
+        "syscall",
+        inlateout("rax") nr => result,
+        in("rdi") a,
+        in("rsi") b,
+        in("rdx") c,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack)
+    );
+    result
+}
+
+pub fn exit(status: i32) -> ! {
+    unsafe { syscall1(nr::EXIT, status as u64) };
+    unreachable!("exit returned")
+}
+
+/// Returns how many bytes were written, maybe less than `bytes`.
+pub fn write(fd: u32, bytes: &[u8]) -> Result<usize> {
+    let result = unsafe {
+        syscall3(
+            nr::WRITE,
+            fd as u64,
+            bytes.as_ptr() as u64,
+            bytes.len() as u64,
+        )
+    };
+    decode(result).map(|len| len as usize)
+}
+
+pub fn yield_now() {
+    unsafe { syscall0(nr::YIELD) };
+}
+
+pub fn getpid() -> u64 {
+    unsafe { syscall0(nr::GETPID) }
+}
+
+/// in nanoseconds
+pub fn time(clock: u64) -> Result<u64> {
+    decode(unsafe { syscall1(nr::TIME, clock) })
+}
+
+pub fn kill(pid: u64, signal: u32) -> Result<()> {
+    decode(unsafe { syscall2(nr::KILL, pid, signal as u64) }).map(|_| ())
+}
+
+/// Set the handler of `signal`: `SIG_DFL`, `SIG_IGN` or a function, called with `mask`
+/// blocked. Returns the previous handler.
+///
+/// # Safety
+/// A function must be `extern "C" fn(u32)`, and never unwind.
+pub unsafe fn sigaction(signal: u32, handler: u64, mask: u64) -> Result<u64> {
+    decode(syscall3(nr::SIGACTION, signal as u64, handler, mask))
+}
+
+/// Returns the previous mask.
+pub fn sigprocmask(how: u64, set: u64) -> Result<u64> {
+    decode(unsafe { syscall2(nr::SIGPROCMASK, how, set) })
+}
+
+/// Move the program break to `end`, or only return it if 0. Returns the new break.
+pub fn brk(end: u64) -> Result<u64> {
+    decode(unsafe { syscall1(nr::BRK, end) })
+}